@@ -0,0 +1,294 @@
+//!
+//! acceptor.rs
+//! Implementation of a Paxos Commit acceptor
+//!
+extern crate ipc_channel;
+extern crate log;
+extern crate stderrlog;
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use acceptor::ipc_channel::ipc::IpcReceiver as Receiver;
+use transport::Sender;
+
+use message::CommitProtocol;
+use message::MessageType;
+use message::Presumption;
+use message::ProtocolMessage;
+use oplog;
+use stats::RunStats;
+
+///
+/// Instance
+/// An acceptor's state of the consensus instance that decides one
+/// participant's vote on one txid
+///
+#[derive(Clone, Copy, Debug, Default)]
+struct Instance {
+    promised: u32,                          // highest ballot promised
+    accepted: Option<(u32, MessageType)>,   // ballot and vote accepted last
+}
+
+///
+/// is_vote()
+/// True for the message types that carry a vote in Paxos Commit
+///
+pub fn is_vote(mtype: MessageType) -> bool {
+    matches!(mtype, MessageType::ParticipantVoteCommit | MessageType::ParticipantVoteAbort | MessageType::ParticipantVoteReadOnly)
+}
+
+///
+/// Acceptor
+/// Structure for maintaining per-acceptor state and the channels to the
+/// leader (the coordinator). Participants send their votes on clones of the
+/// leader's sender to the acceptor.
+///
+#[derive(Debug)]
+pub struct Acceptor {
+    id_str: String,
+    log: oplog::OpLog,
+    running: Arc<AtomicBool>,
+	tx : Sender,
+	rx : Receiver<ProtocolMessage>,
+	instances: HashMap<(String, String), Instance>,
+	stats: RunStats,
+	num_promised: u64,
+	num_accepted: u64,
+}
+
+///
+/// Acceptor
+/// Implementation of an acceptor for Paxos Commit
+/// Required:
+/// 1. new -- Constructor
+/// 2. pub fn report_status -- Reports the promises and votes it accepted
+/// 3. pub fn protocol() -- Implements the acceptor side of Paxos Commit
+///
+impl Acceptor {
+
+    ///
+    /// new()
+    ///
+    /// Return a new acceptor. With recover set, the existing log is replayed
+    /// and the acceptor keeps every promise and vote it made before: Paxos
+    /// is only safe if acceptors do not forget. Acceptor logs are never
+    /// checkpointed, so they are never truncated either.
+    ///
+    pub fn new(
+        id_str: String,
+        log_path: String,
+        recover: bool,
+        log_config: oplog::LogConfig,
+        r: Arc<AtomicBool>,
+		sender: Sender,
+		recvr: Receiver<ProtocolMessage>
+		) -> Acceptor {
+
+        let log = if recover { oplog::OpLog::reopen(log_path, log_config) } else { oplog::OpLog::new(log_path, log_config) };
+        let mut instances: HashMap<(String, String), Instance> = HashMap::new();
+        for txid in log.txids() {
+            for record in log.records_for_txid(&txid) {
+                let instance = instances.entry((txid.clone(), record.msg.senderid.clone())).or_default();
+                instance.promised = instance.promised.max(record.msg.opid);
+                if is_vote(record.msg.mtype) {
+                    instance.accepted = Some((record.msg.opid, record.msg.mtype));
+                }
+            }
+        }
+        if recover {
+            info!("acceptor_{}::Recovered {} instances", id_str, instances.len());
+        }
+
+        Acceptor {
+            id_str,
+            log,
+            running: r,
+			tx: sender,
+			rx: recvr,
+			instances,
+			stats: RunStats::new(CommitProtocol::PaxosCommit, Presumption::Nothing),
+			num_promised: 0,
+			num_accepted: 0,
+        }
+    }
+
+    ///
+    /// reply()
+    /// Send a phase 1b or 2b message to the leader. A crashed leader does not
+    /// hear it; the next one asks again.
+    ///
+    fn reply(&mut self, pm: ProtocolMessage) {
+		self.stats.messages += 1;
+		if self.tx.send(pm).is_err() {
+			debug!("acceptor_{}::Leader is gone", self.id_str.clone());
+		}
+    }
+
+    ///
+    /// prepare()
+    /// Phase 1: promise a ballot higher than any promised before for the
+    /// instance, and tell the leader what was accepted in it so far. The
+    /// promise is forced to the log before the reply.
+    ///
+    fn prepare(&mut self, request: ProtocolMessage) {
+		let instance = self.instances.entry((request.txid.clone(), request.senderid.clone())).or_default();
+		if request.opid <= instance.promised {
+			debug!("acceptor_{}::Ignoring ballot {} for {}/{}, promised {}", self.id_str.clone(), request.opid, request.txid, request.senderid, instance.promised);
+			return;
+		}
+		instance.promised = request.opid;
+		let accepted = instance.accepted;
+		self.log.append( MessageType::PaxosPromise, request.txid.clone(), request.senderid.clone(), request.opid).unwrap();
+		self.num_promised += 1;
+		let mut promise = request;
+		promise.mtype = MessageType::PaxosPromise;
+		promise.accepted = accepted;
+		self.reply(promise);
+    }
+
+    ///
+    /// accept()
+    /// Phase 2: accept a vote unless a higher ballot was promised for its
+    /// instance. The vote is forced to the log, then passed on to the
+    /// leader, which counts it toward a majority.
+    ///
+    fn accept(&mut self, vote: ProtocolMessage) {
+		let first = !self.instances.keys().any(|key| key.0 == vote.txid);
+		let instance = self.instances.entry((vote.txid.clone(), vote.senderid.clone())).or_default();
+		if vote.opid < instance.promised {
+			debug!("acceptor_{}::Ignoring {:?} in ballot {} for {}/{}, promised {}", self.id_str.clone(), vote.mtype, vote.opid, vote.txid, vote.senderid, instance.promised);
+			return;
+		}
+		instance.promised = vote.opid;
+		instance.accepted = Some((vote.opid, vote.mtype));
+		if first {
+			self.stats.transactions += 1;
+		}
+		self.log.append( vote.mtype, vote.txid.clone(), vote.senderid.clone(), vote.opid).unwrap();
+		self.num_accepted += 1;
+		self.reply(vote);
+    }
+
+    ///
+    /// report_status()
+    /// Report the promises and votes this acceptor took on in this run. The
+    /// log writes and messages go to the stats file next to the log.
+    ///
+    pub fn report_status(&mut self) {
+        println!("{:16}:\tPromised: {:6}\tAccepted: {:6}\tInstances: {:6}", format!("acceptor_{}",self.id_str.clone()), self.num_promised, self.num_accepted, self.instances.len());
+
+        let (forced_writes, lazy_writes) = self.log.writes();
+        self.stats.forced_writes = forced_writes;
+        self.stats.lazy_writes = lazy_writes;
+        self.stats.write(&RunStats::path_for(self.log.path()));
+    }
+
+    ///
+    /// protocol()
+    /// Implements the acceptor side of Paxos Commit: votes come in from
+    /// participants (ballot 0) and from the leader (higher ballots), prepares
+    /// from the leader. The acceptor ends when the leader says so, or when
+    /// nobody is left to send it anything.
+    ///
+    pub fn protocol(&mut self) {
+        trace!("acceptor_{}::Beginning protocol", self.id_str.clone());
+		while let Ok(pm) = self.rx.recv() {
+			match pm.mtype {
+				MessageType::CoordinatorExit => {
+					if pm.txid == "done" {
+						trace!("acceptor_{}::Exiting", self.id_str.clone());
+						break;
+					}
+				},
+				MessageType::PaxosPrepare => self.prepare(pm),
+				mtype if is_vote(mtype) => self.accept(pm),
+				_ => warn!("acceptor_{}::Unexpected message {:?}", self.id_str.clone(), pm.mtype),
+			}
+			if !self.running.load(Ordering::SeqCst) {
+				trace!("acceptor_{}::Interrupted", self.id_str.clone());
+			}
+		}
+        self.report_status();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    use acceptor::ipc_channel::ipc;
+    use oplog::DurabilityPolicy;
+    use oplog::LogConfig;
+    use oplog::LogFormat;
+    use transport::Latency;
+    use transport::LinkFaults;
+    use transport::Transport;
+
+    use message::MessageType::ParticipantVoteAbort as VoteAbort;
+    use message::MessageType::ParticipantVoteCommit as VoteCommit;
+    use message::MessageType::PaxosPrepare as Prepare;
+    use message::MessageType::PaxosPromise as Promise;
+
+    // A directory of its own for every test, tests run in parallel
+    fn temp_dir(name: &str) -> String {
+        let dir = env::temp_dir().join(format!("acceptor_test_{}_{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.to_string_lossy().into_owned()
+    }
+
+    // Acceptor 0, and the end of its link to the leader
+    fn acceptor(dir: &str, recover: bool) -> (Acceptor, Receiver<ProtocolMessage>) {
+        let log_config = LogConfig { durability: DurabilityPolicy::Flush, format: LogFormat::Json, segment_size: 0, truncate: false };
+        let faults = LinkFaults { latency: Latency::None, links: Vec::new(), reorder: 0, duplicate: 0.0, partitions: Vec::new() };
+        let transport = Transport::new("acceptor_0".to_string(), dir, recover, 1.0, faults, 0);
+        let (tx, leader_rx) = ipc::channel().unwrap();
+        let (_, rx) = ipc::channel().unwrap();
+        let acceptor = Acceptor::new("0".to_string(), format!("{}//acceptor_0.log", dir), recover, log_config,
+                                     Arc::new(AtomicBool::new(true)), Transport::sender(&transport, tx, "coordinator".to_string()), rx);
+        (acceptor, leader_rx)
+    }
+
+    // A message of participant 0's instance of tx_1 in the given ballot
+    fn message(mtype: MessageType, ballot: u32) -> ProtocolMessage {
+        ProtocolMessage::generate(mtype, "tx_1".to_string(), "0".to_string(), ballot, 0)
+    }
+
+    // A reply to the leader, as (type, ballot, accepted)
+    type Reply = (MessageType, u32, Option<(u32, MessageType)>);
+
+    // Everything sent to the leader so far
+    fn replies(rx: &Receiver<ProtocolMessage>) -> Vec<Reply> {
+        let mut replies = Vec::new();
+        while let Ok(pm) = rx.try_recv() {
+            replies.push((pm.mtype, pm.opid, pm.accepted));
+        }
+        replies
+    }
+
+    #[test]
+    fn promises_carry_the_accepted_vote_across_recovery() {
+        let dir = temp_dir("promises");
+        {
+            let (mut acceptor, leader) = acceptor(&dir, false);
+            acceptor.accept(message(VoteCommit, 0));
+            acceptor.prepare(message(Prepare, 2));
+            // ballots at or below the one promised are ignored
+            acceptor.prepare(message(Prepare, 1));
+            acceptor.accept(message(VoteAbort, 1));
+            assert_eq!(replies(&leader), vec![(VoteCommit, 0, None), (Promise, 2, Some((0, VoteCommit)))]);
+        }
+
+        let (mut acceptor, leader) = acceptor(&dir, true);
+        acceptor.prepare(message(Prepare, 2));
+        acceptor.prepare(message(Prepare, 3));
+        acceptor.accept(message(VoteAbort, 3));
+        assert_eq!(replies(&leader), vec![(Promise, 3, Some((0, VoteCommit))), (VoteAbort, 3, None)]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -2,162 +2,1322 @@
 //! checker.rs
 //! Tools for checking output logs produced by the _T_wo _P_hase _C_ommit
 //! project in run mode. Exports a single public function called check_last_run
-//! that accepts a directory where client, participant, and coordinator log
-//! files are found, and the number of clients, participants. Loads and analyses
-//! log files to check a handful of correctness invariants.
+//! that accepts a CheckConfig: the directory where client, participant,
+//! coordinator and acceptor log files are found, the number of clients,
+//! participants and acceptors, and the protocol of the run. Streams and
+//! analyses log files to check a handful of correctness invariants.
 //!
 extern crate log;
 extern crate stderrlog;
 extern crate clap;
 extern crate ctrlc;
 
+use std::cmp;
 use std::collections::HashMap;
+use std::collections::HashSet;
+use std::time::Duration;
 
-use message;
+use acceptor;
+use crash;
+use message::CommitProtocol;
 use message::MessageType;
+use message::Presumption;
 use message::ProtocolMessage;
+use oplog::Corruption;
+use oplog::LogError;
+use oplog::LogIter;
 use oplog::OpLog;
+use seed;
+use stats::RunStats;
+use transport;
+use transport::DroppedMessage;
+
+// How far the logs are read past a transaction at least before it is taken
+// to be unsettled, however often the coordinator checkpoints: a decision
+// may take a vote timeout, or a restart, to reach a participant
+const MIN_UNSETTLED_AGE: Duration = Duration::from_secs(60);
+
+///
+/// LogStream
+/// The records of one log, streamed in file order, minus checkpoints: their
+/// copies of in-flight records would otherwise be counted twice. A copy is
+/// kept only if its txid was not seen before, i.e. truncation deleted the
+/// original. A txid in flight at a checkpoint was either copied by the
+/// checkpoint before or seen since, so only the txids of the last
+/// checkpoint (`checkpoint`) and of the records after it (`window`) are
+/// kept, and memory does not grow with the log. A torn tail is reported
+/// and ends the stream; corruption in the middle also sets `corrupt`.
+/// `records` counts the valid records read, `timestamp` is the time of the
+/// last one and `checkpoints` the times of the last two checkpoints, the
+/// one before the last first.
+///
+struct LogStream {
+    iter: LogIter,
+    in_checkpoint: bool,
+    checkpoint: HashSet<String>,
+    window: HashSet<String>,
+    first_lsn: Option<u64>,
+    records: usize,
+    timestamp: u64,
+    checkpoints: [u64; 2],
+    corrupt: bool,
+}
+
+impl LogStream {
+
+    ///
+    /// truncated()
+    /// True if the log lost its oldest records to truncation, so transactions
+    /// missing from it may have been there once
+    ///
+    fn truncated(&self) -> bool {
+        self.first_lsn.is_some_and(|lsn| lsn > 1)
+    }
+}
+
+impl Iterator for LogStream {
+    type Item = ProtocolMessage;
+
+    fn next(&mut self) -> Option<ProtocolMessage> {
+        loop {
+            match self.iter.next() {
+                Some(Ok(record)) => {
+                    self.records += 1;
+                    self.first_lsn.get_or_insert(record.lsn);
+                    self.timestamp = record.timestamp;
+                    match record.msg.mtype {
+                        MessageType::CheckpointBegin => {
+                            // what the copies of this checkpoint are checked against
+                            let copied: Vec<String> = self.checkpoint.drain().collect();
+                            self.window.extend(copied);
+                            self.in_checkpoint = true;
+                        },
+                        MessageType::CheckpointEnd => {
+                            self.checkpoints = [self.checkpoints[1], record.timestamp];
+                            self.window.clear();
+                            self.in_checkpoint = false;
+                        },
+                        _ if self.in_checkpoint => {
+                            let copy = !self.window.contains(&record.msg.txid);
+                            self.checkpoint.insert(record.msg.txid.clone());
+                            if copy {
+                                return Some(record.msg);
+                            }
+                        },
+                        _ => {
+                            self.window.insert(record.msg.txid.clone());
+                            return Some(record.msg);
+                        },
+                    }
+                },
+                Some(Err(LogError::TornTail(scan))) => {
+                    println!("{}", scan);
+                    return None;
+                },
+                Some(Err(LogError::Io(e))) => {
+                    error!("can not read log {}", e);
+                    self.corrupt = true;
+                    return None;
+                },
+                Some(Err(e)) => {
+                    error!("corrupt log {}", e);
+                    self.corrupt = true;
+                    return None;
+                },
+                None => return None,
+            }
+        }
+    }
+}
 
 ///
-/// check_participant()
+/// open_log()
 ///
-/// Given a participant name and HashMaps that represents the log files for the
-/// participant and coordinator (already filtered for commit records), check
-/// that the committed and aborted transactions are agreed upon by the two.
+/// Opens a log for streaming. With repair set, a torn tail is truncated off
+/// the file first.
 ///
-/// <params>
-///     participant: name of participant (label)
-///     ncommit: number of committed transactions from coordinator
-///     nabort: number of aborted transactions from coordinator
-///     ccommitted: map of committed transactions from coordinator
-///     plog: map of participant operations
-///
-fn check_participant(
-    participant: &String,
+fn open_log(path: &str, repair: bool) -> Option<LogStream> {
+    if repair {
+        match OpLog::repair(path.to_string()) {
+            Ok(ref scan) if scan.corruption == Corruption::TornTail => println!("repaired {}", scan),
+            Ok(_) => {},
+            Err(e) => {
+                error!("can not repair {}", e);
+                return None;
+            },
+        }
+    }
+    match OpLog::iter_file(path.to_string()) {
+        Ok(iter) => Some(LogStream { iter, in_checkpoint: false, checkpoint: HashSet::new(), window: HashSet::new(), first_lsn: None, records: 0, timestamp: 0, checkpoints: [0, 0], corrupt: false }),
+        Err(e) => {
+            error!("can not open log {}", e);
+            None
+        },
+    }
+}
+
+///
+/// ParticipantTxn
+/// What a participant's log says about one transaction
+///
+#[derive(Clone, Copy, Debug, Default)]
+struct ParticipantTxn {
+    commit_votes: usize,
+    abort_votes: usize,
+    read_only: bool,
+    decision: Option<MessageType>,
+}
+
+impl ParticipantTxn {
+
+    ///
+    /// settled()
+    /// True once the participant is done with the transaction: it has the
+    /// decision, or it only read and took no part in phase 2
+    ///
+    fn settled(&self) -> bool {
+        self.decision.is_some() || self.read_only
+    }
+
+    ///
+    /// voted()
+    /// False if the log has the decision only: its vote was truncated away
+    ///
+    fn voted(&self) -> bool {
+        self.commit_votes + self.abort_votes > 0 || self.read_only
+    }
+}
+
+///
+/// check_outcome()
+///
+/// Checks one transaction of a participant against the coordinator's
+/// decision. A participant may not have heard the decision (yet). A
+/// read-only participant goes along with either decision.
+///
+fn check_outcome(participant: &str, txid: &str, outcome: MessageType, txn: &ParticipantTxn) -> bool {
+    let mut result = true;
+    if outcome == MessageType::CoordinatorCommit && !(txn.commit_votes == 1 || txn.read_only) {
+        // Exactly one commit of txid per participant. If the coordinator
+        // committed the transaction, the participant has to have voted in
+        // favor, even if it never got the global commit message.
+        error!("{}: {} committed by the coordinator with {} votes to commit", participant, txid, txn.commit_votes);
+        result = false;
+    }
+    let opposite = match outcome {
+        MessageType::CoordinatorCommit => MessageType::CoordinatorAbort,
+        _ => MessageType::CoordinatorCommit,
+    };
+    if txn.decision == Some(opposite) {
+        error!("{}: {} decided {:?}, the coordinator {:?}", participant, txid, opposite, outcome);
+        result = false;
+    }
+    result
+}
+
+///
+/// relation()
+/// How a participant total compares to the coordinator's, for the report
+///
+fn relation(participant: usize, coordinator: usize) -> &'static str {
+    if participant < coordinator {
+        "<"
+    } else if participant > coordinator {
+        ">"
+    } else {
+        "=="
+    }
+}
+
+///
+/// presumed()
+/// The note on the presumed decisions in a coordinator total, for the report
+///
+fn presumed(num_presumed: usize) -> String {
+    if num_presumed > 0 {
+        format!(", {} presumed", num_presumed)
+    } else {
+        String::new()
+    }
+}
+
+///
+/// CoordinatorView
+/// What the coordinator log says so far: the protocol of the run and its
+/// presumption, its decision counts, the transactions it has on record but not decided
+/// (yet) with the time of that record, whether truncation lost its oldest
+/// records, the time of the last record read, the start of the window and,
+/// for the acceptors to be checked against, its decisions
+///
+struct CoordinatorView {
+    protocol: CommitProtocol,
+    presumption: Presumption,
+    num_commit: usize,
+    num_abort: usize,
+    undecided: HashMap<String, u64>,
+    truncated: bool,
+    timestamp: u64,
+    window: u64,
+    decisions: HashMap<String, MessageType>,    // Paxos Commit only
+}
+
+impl CoordinatorView {
+
+    ///
+    /// presumed()
+    /// True if `decision` of txid needs no coordinator record: it is what
+    /// the presumption of a 2PC run answers for, and in presumed commit the
+    /// coordinator has no Collecting record of txid, which would make it
+    /// presumed aborted
+    ///
+    fn presumed(&self, txid: &str, decision: MessageType) -> bool {
+        self.protocol == CommitProtocol::TwoPhase
+            && self.presumption != Presumption::Nothing
+            && decision == self.presumption.presumed()
+            && !self.undecided.contains_key(txid)
+    }
+
+    ///
+    /// horizon()
+    /// Entries stamped before this time are older than the window: the
+    /// checkpoint window of the coordinator log, but at least
+    /// MIN_UNSETTLED_AGE before what it and the participant log at
+    /// `timestamp` are read up to. Zero, so nothing is older, until the
+    /// coordinator log has two checkpoints, and in logs with no timestamps.
+    ///
+    fn horizon(&self, timestamp: u64) -> u64 {
+        let read = cmp::min(self.timestamp, timestamp);
+        cmp::min(self.window, read.saturating_sub(MIN_UNSETTLED_AGE.as_micros() as u64))
+    }
+}
+
+///
+/// ParticipantCheck
+/// The check of one participant log against the coordinator's decisions.
+/// The coordinator log is streamed once for all participants: each of its
+/// decisions is handed to every check, which then reads its own log until
+/// it is no longer behind, by count and by time. `outcomes` holds the
+/// coordinator decisions not matched yet, `txns` the participant
+/// transactions not matched yet, each with the time it was first seen; a
+/// participant transaction is matched once its own decision is in. Entries
+/// older than the window are checked as at the end of the logs and counted
+/// as unsettled, so memory is bounded by the window, not the logs.
+///
+struct ParticipantCheck {
+    name: String,
+    log: LogStream,
+    crashed: Option<String>,
+    outcomes: HashMap<String, (MessageType, u64)>,
+    txns: HashMap<String, (ParticipantTxn, u64)>,
+    result: bool,
     num_commit: usize,
+    num_local_commit: usize,
     num_abort: usize,
-    coord_committed: &HashMap<u32, ProtocolMessage>,
-    participant_log: &HashMap<u32, ProtocolMessage>
-    ) -> bool {
+    num_read_only: usize,
+    num_unchecked: usize,               // transactions a truncated log kept only part of
+    num_unsettled: usize,               // transactions not settled in both logs within the window
+    num_unvoted: usize,                 // coordinator commits of transactions it has no record of
+    num_blocked: usize,
+    num_blocked_lossy: usize,
+    num_terminated_commit: usize,
+    num_terminated_abort: usize,
+    num_presumed_commit: usize,         // decisions the coordinator logged no record of
+    num_presumed_abort: usize,
+}
+
+impl ParticipantCheck {
+
+    ///
+    /// open()
+    /// Opens the log of participant `pid` in `log_path` for checking, with
+    /// the crash spec it crashed at, if it did
+    ///
+    fn open(pid: u32, log_path: &String, repair: bool) -> Option<ParticipantCheck> {
+        let name = format!("participant_{}", pid);
+        let path = format!("{}//{}.log", log_path, name);
+        let log = open_log(&path, repair)?;
+        Some(ParticipantCheck {
+            name,
+            log,
+            crashed: crash::read(&path),
+            outcomes: HashMap::new(),
+            txns: HashMap::new(),
+            result: true,
+            num_commit: 0,
+            num_local_commit: 0,
+            num_abort: 0,
+            num_read_only: 0,
+            num_unchecked: 0,
+            num_unsettled: 0,
+            num_unvoted: 0,
+            num_blocked: 0,
+            num_blocked_lossy: 0,
+            num_terminated_commit: 0,
+            num_terminated_abort: 0,
+            num_presumed_commit: 0,
+            num_presumed_abort: 0,
+        })
+    }
+
+    ///
+    /// matched()
+    /// Checks a transaction both logs are done with. A truncated participant
+    /// log may have lost the vote of it; such a transaction is only counted.
+    ///
+    fn matched(&mut self, txid: &str, outcome: MessageType, txn: &ParticipantTxn) {
+        if self.log.truncated() && !txn.voted() {
+            self.num_unchecked += 1;
+        } else {
+            self.result &= check_outcome(&self.name, txid, outcome, txn);
+        }
+    }
+
+    ///
+    /// decision()
+    /// Hands the check a coordinator decision, logged at `timestamp`
+    ///
+    fn decision(&mut self, txid: &String, outcome: MessageType, timestamp: u64) {
+        match self.txns.get(txid).cloned() {
+            Some((txn, _)) if txn.settled() => {
+                self.matched(txid, outcome, &txn);
+                self.txns.remove(txid);
+            },
+            _ => { self.outcomes.insert(txid.clone(), (outcome, timestamp)); },
+        }
+    }
+
+    ///
+    /// behind()
+    /// True if the coordinator log, read up to `timestamp`, is ahead of the
+    /// participant log
+    ///
+    fn behind(&self, timestamp: u64) -> bool {
+        self.outcomes.len() > self.txns.len() || self.log.timestamp < timestamp
+    }
+
+    ///
+    /// step()
+    /// Reads the next record of the participant log. False at its end.
+    ///
+    fn step(&mut self) -> bool {
+        let pm = match self.log.next() {
+            Some(pm) => pm,
+            None => return false,
+        };
+        match pm.mtype {
+            MessageType::CoordinatorCommit => self.num_commit += 1,
+            MessageType::CoordinatorAbort => self.num_abort += 1,
+            MessageType::ParticipantVoteCommit => self.num_local_commit += 1,
+            MessageType::ParticipantVoteAbort => {},
+            MessageType::ParticipantVoteReadOnly => self.num_read_only += 1,
+            _ => return true,
+        }
+        let timestamp = self.log.timestamp;
+        let txn = {
+            let entry = self.txns.entry(pm.txid.clone()).or_insert((ParticipantTxn::default(), timestamp));
+            let txn = &mut entry.0;
+            match pm.mtype {
+                MessageType::ParticipantVoteCommit => txn.commit_votes += 1,
+                MessageType::ParticipantVoteAbort => txn.abort_votes += 1,
+                MessageType::ParticipantVoteReadOnly => txn.read_only = true,
+                _ => txn.decision = Some(pm.mtype),
+            }
+            *txn
+        };
+        if txn.settled() {
+            if let Some((outcome, _)) = self.outcomes.remove(&pm.txid) {
+                self.matched(&pm.txid, outcome, &txn);
+                self.txns.remove(&pm.txid);
+            }
+        }
+        true
+    }
+
+    ///
+    /// unmatched()
+    ///
+    /// Checks a participant transaction the coordinator log has no decision
+    /// for, or the decision of which the participant never heard. A presumed
+    /// decision has no record: the presumption answers for it. The others
+    /// are aborted or blocked in 2PC (a crashed coordinator). In 3PC the participants terminated them; they are added
+    /// to `undecided` for a check across participants. A truncated
+    /// coordinator log may have lost the decision; such a transaction is only
+    /// counted. `lossy` are the txids the transport dropped a message of.
+    ///
+    fn unmatched(&mut self, txid: String, txn: ParticipantTxn, coord: &CoordinatorView, undecided: &mut HashMap<String, Vec<ParticipantTxn>>, lossy: &HashSet<String>) {
+        if !txn.settled() && txn.abort_votes == 0 {
+            self.num_blocked += 1;
+            if lossy.contains(&txid) {
+                self.num_blocked_lossy += 1;
+            }
+        }
+        match (self.outcomes.remove(&txid), txn.decision) {
+            (Some((outcome, _)), _) => self.matched(&txid, outcome, &txn),
+            (None, Some(decision)) if coord.presumed(&txid, decision) => {
+                if decision == MessageType::CoordinatorCommit {
+                    self.num_presumed_commit += 1;
+                } else {
+                    self.num_presumed_abort += 1;
+                }
+                self.matched(&txid, decision, &txn);
+            },
+            (None, _) if coord.truncated && !coord.undecided.contains_key(&txid) => self.num_unchecked += 1,
+            (None, _) => {
+                match txn.decision {
+                    Some(MessageType::CoordinatorCommit) => self.num_terminated_commit += 1,
+                    Some(_) => self.num_terminated_abort += 1,
+                    None => {},
+                }
+                // only 3PC participants decide without the coordinator's word
+                if coord.protocol == CommitProtocol::ThreePhase {
+                    undecided.entry(txid).or_default().push(txn);
+                } else if txn.decision == Some(MessageType::CoordinatorCommit) {
+                    error!("{}: {} committed without a coordinator decision", self.name, txid);
+                    self.result = false;
+                }
+            },
+        }
+    }
+
+    ///
+    /// unheard()
+    /// Counts a coordinator decision of a transaction missing from the
+    /// participant log altogether; a truncated one may have lost it. Every
+    /// coordinator commit needs a local commit.
+    ///
+    fn unheard(&mut self, outcome: MessageType) {
+        if self.log.truncated() {
+            self.num_unchecked += 1;
+        } else if outcome == MessageType::CoordinatorCommit {
+            self.num_unvoted += 1;
+        }
+    }
+
+    ///
+    /// evict()
+    /// Checks the entries older than the window as if both logs ended, and
+    /// forgets them. What one of the logs had not settled by then is counted
+    /// as unsettled.
+    ///
+    fn evict(&mut self, coord: &CoordinatorView, undecided: &mut HashMap<String, Vec<ParticipantTxn>>, lossy: &HashSet<String>) {
+        let horizon = coord.horizon(self.log.timestamp);
+        let old: Vec<String> = self.txns.iter().filter(|(_, entry)| entry.1 < horizon).map(|(txid, _)| txid.clone()).collect();
+        for txid in old {
+            let (txn, _) = self.txns.remove(&txid).unwrap();
+            if !txn.settled() {
+                self.num_unsettled += 1;
+            }
+            self.unmatched(txid, txn, coord, undecided, lossy);
+        }
+        let old: Vec<String> = self.outcomes.iter().filter(|(txid, entry)| entry.1 < horizon && !self.txns.contains_key(*txid)).map(|(txid, _)| txid.clone()).collect();
+        for txid in old {
+            let (outcome, _) = self.outcomes.remove(&txid).unwrap();
+            self.num_unsettled += 1;
+            self.unheard(outcome);
+        }
+    }
+
+    ///
+    /// finish()
+    ///
+    /// Checks what is left once both logs are read to the end, and prints
+    /// the report of the participant. 3PC does not block, unless the
+    /// transport dropped messages of the transaction on purpose, or the
+    /// participant crashed at a crash point and was not restarted.
+    ///
+    fn finish(&mut self, coord: &CoordinatorView, undecided: &mut HashMap<String, Vec<ParticipantTxn>>, lossy: &HashSet<String>) -> bool {
+        if self.log.corrupt {
+            return false;
+        }
+        // every participant logs its votes; a log with none has nothing to check
+        if self.log.records == 0 {
+            error!("{}: no valid records in its log", self.name);
+            return false;
+        }
+
+        let txns: Vec<(String, (ParticipantTxn, u64))> = self.txns.drain().collect();
+        for (txid, (txn, _)) in txns {
+            self.unmatched(txid, txn, coord, undecided, lossy);
+        }
+        let outcomes: Vec<(MessageType, u64)> = self.outcomes.drain().map(|(_, entry)| entry).collect();
+        for (outcome, _) in outcomes {
+            self.unheard(outcome);
+        }
+
+        let participant = self.name.clone();
+        let participant_truncated = self.log.truncated();
+
+        // The totals only compare when both logs are complete. The
+        // coordinator totals include the presumed decisions this participant
+        // heard of, which the coordinator logged no record of.
+        let num_commit = coord.num_commit + self.num_presumed_commit;
+        let num_abort = coord.num_abort + self.num_presumed_abort;
+        let totals_compared = !coord.truncated && !participant_truncated;
+        if totals_compared {
+            if self.num_commit - self.num_terminated_commit > num_commit {
+                error!("{}: {} commits without a coordinator commit", participant, self.num_commit - self.num_terminated_commit - num_commit);
+                self.result = false;
+            }
+            if self.num_local_commit + self.num_read_only < coord.num_commit {
+                error!("{}: {} coordinator commits without a vote to commit", participant, coord.num_commit - self.num_local_commit - self.num_read_only);
+                self.result = false;
+            }
+            if self.num_abort - self.num_terminated_abort > num_abort {
+                error!("{}: {} aborts without a coordinator abort", participant, self.num_abort - self.num_terminated_abort - num_abort);
+                self.result = false;
+            }
+        }
+
+        if self.num_unvoted > 0 {
+            error!("{}: {} coordinator commits of transactions it never voted on", participant, self.num_unvoted);
+            self.result = false;
+        }
+
+        // 3PC does not block: every vote to commit ends in a decision, unless
+        // the messages to reach it were dropped, or the participant crashed
+        if coord.protocol == CommitProtocol::ThreePhase && self.crashed.is_none() && self.num_blocked != self.num_blocked_lossy {
+            error!("{}: {} transactions blocked in 3PC", participant, self.num_blocked - self.num_blocked_lossy);
+            self.result = false;
+        }
+
+        let status = if self.result { "OK" } else { "FAIL" };
+        if totals_compared {
+            println!("{} {}: Committed: {} {} {} (Committed-global{}), Aborted: {} {} {} (Aborted-global{}), Read-only: {}",
+                     participant,
+                     status,
+                     self.num_commit,
+                     relation(self.num_commit, num_commit),
+                     num_commit,
+                     presumed(self.num_presumed_commit),
+                     self.num_abort,
+                     relation(self.num_abort, num_abort),
+                     num_abort,
+                     presumed(self.num_presumed_abort),
+                     self.num_read_only);
+        } else {
+            println!("{} {}: Committed: {}, Aborted: {}, Read-only: {} (truncated logs: not compared to the {} committed and {} aborted of the coordinator)",
+                     participant,
+                     status,
+                     self.num_commit,
+                     self.num_abort,
+                     self.num_read_only,
+                     coord.num_commit,
+                     coord.num_abort);
+        }
+        if self.num_blocked > 0 || self.num_terminated_commit + self.num_terminated_abort > 0 {
+            println!("{} without a coordinator decision: Blocked: {} ({} lost messages), Committed: {}, Aborted: {}",
+                     participant,
+                     self.num_blocked,
+                     self.num_blocked_lossy,
+                     self.num_terminated_commit,
+                     self.num_terminated_abort);
+        }
+        if self.num_unsettled > 0 {
+            println!("{} unsettled: {} transactions not settled in both logs within the checkpoint window",
+                     participant,
+                     self.num_unsettled);
+        }
+        if let Some(ref spec) = self.crashed {
+            println!("{} crashed at {}", participant, spec);
+        }
+        if self.num_unchecked > 0 {
+            println!("{} truncated logs: {} transactions left in only one log not checked",
+                     participant,
+                     self.num_unchecked);
+        }
+        self.result
+    }
+}
+
+///
+/// stream_coordinator()
+///
+/// Streams the coordinator log once, handing every decision to the check of
+/// each participant, which catches up on its own log. Counts coordinator
+/// Commits and Aborts; Paxos Commit checks its decisions against the
+/// acceptors. Each coordinator checkpoint moves the window on, and the
+/// checks forget what is older. None if the coordinator log is corrupt.
+///
+fn stream_coordinator(
+    coord_log: &mut LogStream,
+    config: &CheckConfig,
+    checks: &mut [ParticipantCheck],
+    undecided: &mut HashMap<String, Vec<ParticipantTxn>>,
+    lossy: &HashSet<String>) -> Option<CoordinatorView> {
+
+    let protocol = config.protocol;
+    let mut coord = CoordinatorView {
+        protocol,
+        presumption: config.presumption,
+        num_commit: 0,
+        num_abort: 0,
+        undecided: HashMap::new(),
+        truncated: false,
+        timestamp: 0,
+        window: 0,
+        decisions: HashMap::new(),
+    };
+    while let Some(pm) = coord_log.next() {
+        coord.timestamp = coord_log.timestamp;
+        coord.truncated = coord_log.truncated();
+        if coord_log.checkpoints[0] != coord.window {
+            coord.window = coord_log.checkpoints[0];
+            for check in checks.iter_mut() {
+                check.evict(&coord, undecided, lossy);
+            }
+            let read = checks.iter().map(|check| check.log.timestamp).min().unwrap_or(coord.timestamp);
+            let horizon = coord.horizon(read);
+            coord.undecided.retain(|_, timestamp| *timestamp >= horizon);
+        }
+        match pm.mtype {
+            MessageType::CoordinatorCommit => coord.num_commit += 1,
+            MessageType::CoordinatorAbort => coord.num_abort += 1,
+            // a 3PC PreCommit is not a decision
+            MessageType::CoordinatorCompleted => continue,
+            _ => {
+                coord.undecided.insert(pm.txid, coord.timestamp);
+                continue;
+            },
+        }
+        coord.undecided.remove(&pm.txid);
+        for check in checks.iter_mut() {
+            check.decision(&pm.txid, pm.mtype, coord.timestamp);
+            while check.behind(coord.timestamp) && check.step() {}
+        }
+        if protocol == CommitProtocol::PaxosCommit {
+            coord.decisions.insert(pm.txid, pm.mtype);
+        }
+    }
+    if coord_log.corrupt {
+        return None;
+    }
+    coord.truncated = coord_log.truncated();
+    Some(coord)
+}
+
+///
+/// check_undecided()
+///
+/// Checks the 3PC transactions the coordinator never decided across all
+/// participants: they all reached the same decision, and a commit needs a
+/// vote to commit (or a read-only vote) from every participant. Transactions
+/// every participant only read had nothing to decide and are skipped. With
+/// truncated participant logs, a commit needs the votes that are left.
+///
+fn check_undecided(
+    num_participants: u32,
+    truncated: bool,
+    undecided: &HashMap<String, Vec<ParticipantTxn>>) -> bool {
+
+    let mut result = true;
+    let mut num_txns = 0;
+    let mut num_commit = 0;
+    let mut num_abort = 0;
+    for (txid, txns) in undecided {
+        if txns.len() == num_participants as usize && txns.iter().all(|t| t.read_only) {
+            continue;
+        }
+        num_txns += 1;
+        let commit = txns.iter().any(|t| t.decision == Some(MessageType::CoordinatorCommit));
+        let abort = txns.iter().any(|t| t.decision == Some(MessageType::CoordinatorAbort));
+        if commit && abort {
+            error!("{}: committed by some participants, aborted by others", txid);
+        }
+        result &= !(commit && abort);
+        if commit {
+            let all_voted = (truncated || txns.len() == num_participants as usize) && txns.iter().all(|t| t.commit_votes == 1 || t.read_only || (truncated && !t.voted()));
+            if !all_voted {
+                error!("{}: committed without a vote to commit from every participant", txid);
+            }
+            result &= all_voted;
+            num_commit += 1;
+        } else if abort {
+            num_abort += 1;
+        }
+    }
+    if num_txns > 0 {
+        println!("Without the coordinator {}: {} transactions, {} committed, {} aborted, {} undecided",
+                 if result { "OK" } else { "FAIL" },
+                 num_txns,
+                 num_commit,
+                 num_abort,
+                 num_txns - num_commit - num_abort);
+    }
+    result
+}
+
+///
+/// Accepted
+/// Every (ballot, vote) each acceptor accepted for one Paxos instance, in
+/// log order
+///
+type Accepted = Vec<Vec<(u32, MessageType)>>;
+
+///
+/// check_acceptors()
+///
+/// Paxos Commit: checks that the acceptors agree on the votes that decided
+/// every transaction. A vote is chosen once a majority of acceptors accepted
+/// it in the same ballot; after that, an acceptor may only accept the same
+/// vote again. A transaction commits if every participant's vote is chosen
+/// and none is an abort, and aborts once an abort is chosen. That outcome
+/// has to match the coordinator's decision, where there is one.
+///
+/// <params>
+///     num_participants: number of participants (instances per transaction)
+///     num_acceptors: number of acceptors
+///     log_path: directory of the acceptor and coordinator logs
+///     repair: truncate torn tails off the acceptor logs before checking them
+///     decisions: txid -> decision in the coordinator log
+///
+fn check_acceptors(
+    num_participants: u32,
+    num_acceptors: u32,
+    log_path: &String,
+    repair: bool,
+    decisions: &HashMap<String, MessageType>) -> bool {
 
     let mut result = true;
+    let quorum = num_acceptors as usize / 2 + 1;
+
+    // (txid, participant) -> what each acceptor accepted for it
+    let mut instances: HashMap<(String, String), Accepted> = HashMap::new();
+    for aid in 0..num_acceptors {
+        let acceptor_log_path = format!("{}//acceptor_{}.log", log_path, aid);
+        let mut acceptor_log = match open_log(&acceptor_log_path, repair) {
+            Some(log) => log,
+            None => return false,
+        };
+        for pm in &mut acceptor_log {
+            if !acceptor::is_vote(pm.mtype) {
+                continue;
+            }
+            let accepted = instances.entry((pm.txid.clone(), pm.senderid.clone()))
+                .or_insert_with(|| vec![Vec::new(); num_acceptors as usize]);
+            accepted[aid as usize].push((pm.opid, pm.mtype));
+        }
+        if acceptor_log.corrupt {
+            return false;
+        }
+    }
+
+    // Choose, and check that each acceptor's last vote goes along
+    let mut chosen: HashMap<String, Vec<MessageType>> = HashMap::new();
+    let mut num_agree = vec![0; num_acceptors as usize];
+    let mut num_missed = vec![0; num_acceptors as usize];
+    let mut num_disagree = vec![0; num_acceptors as usize];
+    for ((txid, participant), accepted) in &instances {
+        let mut counts: HashMap<(u32, MessageType), usize> = HashMap::new();
+        for votes in accepted {
+            let distinct: HashSet<&(u32, MessageType)> = votes.iter().collect();
+            for vote in distinct {
+                *counts.entry(*vote).or_insert(0) += 1;
+            }
+        }
+        let values: HashSet<MessageType> = counts.iter().filter(|(_, n)| **n >= quorum).map(|(vote, _)| vote.1).collect();
+        if values.len() > 1 {
+            error!("{}/{}: acceptors chose {:?}", txid, participant, values);
+        }
+        result &= values.len() <= 1;
+        let value = match values.into_iter().next() {
+            Some(value) => value,
+            None => continue,
+        };
+        let ballot = counts.iter().filter(|(vote, n)| **n >= quorum && vote.1 == value).map(|(vote, _)| vote.0).min().unwrap();
+        for (aid, votes) in accepted.iter().enumerate() {
+            match votes.last() {
+                Some(last) if last.0 >= ballot && last.1 != value => {
+                    error!("{}/{}: acceptor_{} accepted {:?} at ballot {}, {:?} was chosen", txid, participant, aid, last.1, last.0, value);
+                    num_disagree[aid] += 1;
+                    result = false;
+                },
+                Some(_) => num_agree[aid] += 1,
+                None => num_missed[aid] += 1,
+            }
+        }
+        chosen.entry(txid.clone()).or_default().push(value);
+    }
 
-    // Filter the participant log for Global Commits, Local Commits, and Aborted
-    let participant_commit_map: HashMap<u32, message::ProtocolMessage> =
-        participant_log.iter()
-        .filter(|e| (*e.1).mtype == MessageType::CoordinatorCommit)
-        .map(|(k,v)| (k.clone(), v.clone()))
-        .collect();
-    let participant_local_commit_map: HashMap<u32, message::ProtocolMessage> =
-        participant_log.iter()
-        .filter(|e| (*e.1).mtype == MessageType::ParticipantVoteCommit)
-        .map(|(k,v)| (k.clone(), v.clone()))
-        .collect();
-    let participant_abort_map: HashMap<u32, message::ProtocolMessage> =
-        participant_log.iter()
-        .filter(|e| (*e.1).mtype == MessageType::CoordinatorAbort)
-        .map(|(k,v)| (k.clone(), v.clone()))
-        .collect();
-
-    let num_participant_commit = participant_commit_map.len();
-    let num_participant_local_commit = participant_local_commit_map.len();
-    let num_participant_abort = participant_abort_map.len();
-
-    result &= num_participant_commit <= num_commit;
-    result &= num_participant_local_commit >= num_commit;
-    result &= num_participant_abort <= num_abort;
-
-    assert!(num_participant_commit <= num_commit);
-    assert!(num_commit <= num_participant_local_commit);
-    assert!(num_abort >= num_participant_abort);
-
-    for (_, coord_msg) in coord_committed.iter() {
-        let txid = coord_msg.txid.clone();
-        let mut _found_txid = 0;
-        let mut found_local_txid = 0;
-        for (_, participant_msg) in participant_commit_map.iter() {
-            if participant_msg.txid == txid {
-                _found_txid += 1;
-            }
-        }
-
-        for (_, participant_msg) in participant_local_commit_map.iter() {
-            // Handle the case where the participant simply doesn't get the
-            // global commit message from the coordinator. If the coordinator
-            // committed the transaction, the participant has to have voted in
-            // favor. Namely, when _found_txid != found_local_txid.
-            if participant_msg.txid == txid {
-                found_local_txid += 1;
-            }
-        }
-
-        // Exactly one commit of txid per participant
-        result &= found_local_txid == 1;
-        assert!(found_local_txid == 1);
-    }
-    println!("{} OK: Committed: {} == {} (Committed-global), Aborted: {} <= {} (Aborted-global)",
-             participant.clone(),
-             num_participant_commit,
+    // Outcomes against the coordinator's decisions
+    let mut num_commit = 0;
+    let mut num_abort = 0;
+    let mut num_undecided = 0;
+    for (txid, votes) in &chosen {
+        let outcome = if votes.contains(&MessageType::ParticipantVoteAbort) {
+            num_abort += 1;
+            MessageType::CoordinatorAbort
+        } else if votes.len() == num_participants as usize {
+            num_commit += 1;
+            MessageType::CoordinatorCommit
+        } else {
+            num_undecided += 1;
+            continue;
+        };
+        if let Some(decision) = decisions.get(txid) {
+            if *decision != outcome {
+                error!("{}: coordinator decided {:?}, acceptors chose {:?}", txid, decision, votes);
+            }
+            result &= *decision == outcome;
+        }
+    }
+    for txid in decisions.keys() {
+        if !chosen.contains_key(txid) {
+            error!("{}: coordinator decided without the acceptors", txid);
+            result = false;
+        }
+    }
+    for aid in 0..num_acceptors as usize {
+        println!("acceptor_{} {}: agrees on {} chosen votes, disagrees on {}, missed {}",
+                 aid,
+                 if num_disagree[aid] == 0 { "OK" } else { "FAIL" },
+                 num_agree[aid],
+                 num_disagree[aid],
+                 num_missed[aid]);
+    }
+    println!("Acceptors {}: {} transactions, {} committed, {} aborted, {} undecided",
+             if result { "OK" } else { "FAIL" },
+             chosen.len(),
              num_commit,
-             num_participant_abort,
-             num_abort);
+             num_abort,
+             num_undecided);
     result
 }
 
 ///
-/// check_last_run()
+/// check_partitions()
 ///
-/// Accepts a directory where client, participant, and coordinator log files are
-/// found, and the number of clients, participants. Loads and analyses log files
-/// to check a handful of correctness invariants.
+/// Checks atomicity across the partitions of the last run: every
+/// transaction a partition cut a message of is decided the same way by the
+/// coordinator and every participant that decided it, whichever side of
+/// the partition they were on.
 ///
 /// <params>
-///     num_clients: Number of clients
-///     num_requests: Number of requests per client
-///     num_participants: Number of participants
-///     log_path: Directory for client, participant, and coordinator logs
-///
-pub fn check_last_run(
-    num_clients: u32,
-    num_requests: u32,
-    num_participants: u32,
-    log_path: &String) {
+///     num_participants: number of participants
+///     log_path: directory of the logs
+///     cut: txids a partition cut a message of
+///
+fn check_partitions(num_participants: u32, log_path: &String, cut: &HashSet<String>) -> bool {
+    if cut.is_empty() {
+        return true;
+    }
+    let mut names = vec!["coordinator".to_string()];
+    names.extend((0..num_participants).map(|pid| format!("participant_{}", pid)));
+    let mut decisions: HashMap<String, Vec<(String, MessageType)>> = HashMap::new();
+    for name in names {
+        let log = match open_log(&format!("{}//{}.log", log_path, name), false) {
+            Some(log) => log,
+            None => continue,
+        };
+        for pm in log {
+            match pm.mtype {
+                MessageType::CoordinatorCommit | MessageType::CoordinatorAbort if cut.contains(&pm.txid) => {
+                    decisions.entry(pm.txid).or_default().push((name.clone(), pm.mtype));
+                },
+                _ => {},
+            }
+        }
+    }
+
+    let mut result = true;
+    let mut num_commit = 0;
+    let mut num_abort = 0;
+    for (txid, decided) in &decisions {
+        let commit = decided.iter().any(|d| d.1 == MessageType::CoordinatorCommit);
+        let abort = decided.iter().any(|d| d.1 == MessageType::CoordinatorAbort);
+        if commit && abort {
+            error!("{}: split by a partition: {:?}", txid, decided);
+        }
+        result &= !(commit && abort);
+        if commit {
+            num_commit += 1;
+        } else {
+            num_abort += 1;
+        }
+    }
+    println!("Across partitions {}: {} transactions cut, {} committed, {} aborted, {} undecided",
+             if result { "OK" } else { "FAIL" },
+             cut.len(),
+             num_commit,
+             num_abort,
+             cut.len() - decisions.len());
+    result
+}
 
-        info!("Checking 2PC run:  {} requests * {} clients, {} participants",
-              num_requests,
-              num_clients,
+///
+/// report_drops()
+///
+/// Prints the messages the transport dropped on purpose in the last run, by
+/// sender and by type, so they can be told from messages a crash lost. The
+/// ones a partition cut are counted apart as well.
+///
+fn report_drops(drops: &Vec<DroppedMessage>) {
+    if drops.is_empty() {
+        return;
+    }
+    let mut by_sender: HashMap<&String, usize> = HashMap::new();
+    let mut by_type: HashMap<MessageType, usize> = HashMap::new();
+    for dropped in drops {
+        *by_sender.entry(&dropped.from).or_insert(0) += 1;
+        *by_type.entry(dropped.mtype).or_insert(0) += 1;
+    }
+    let mut senders: Vec<_> = by_sender.into_iter().collect();
+    senders.sort();
+    let mut types: Vec<_> = by_type.into_iter().map(|(mtype, n)| (format!("{:?}", mtype), n)).collect();
+    types.sort();
+    println!("Dropped on purpose: {} messages, {} transactions", drops.len(), drops.iter().map(|d| &d.txid).collect::<HashSet<_>>().len());
+    let cut: Vec<&DroppedMessage> = drops.iter().filter(|d| d.partitioned).collect();
+    if !cut.is_empty() {
+        println!("Cut by partitions: {} messages, {} transactions", cut.len(), cut.iter().map(|d| &d.txid).collect::<HashSet<_>>().len());
+    }
+    for (from, n) in senders {
+        println!("{:16}:\tdropped {}", from, n);
+    }
+    for (mtype, n) in types {
+        println!("{:16}:\t{}", mtype, n);
+    }
+}
+
+///
+/// report_costs()
+///
+/// Prints the log writes and messages per transaction that the coordinator,
+/// the participants and any acceptors recorded in their stats files, and
+/// their sum over the coordinator's transactions, so protocol variants (and
+/// flat and tree 2PC) can be compared. The coordinator also has the time it
+/// took to decide.
+///
+fn report_costs(num_participants: u32, num_acceptors: u32, log_path: &String) {
+    let mut names = vec!["coordinator".to_string()];
+    names.extend((0..num_participants).map(|pid| format!("participant_{}", pid)));
+    names.extend((0..num_acceptors).map(|aid| format!("acceptor_{}", aid)));
+
+    let mut total = RunStats::default();
+    for name in names {
+        let stats = match RunStats::read(&format!("{}//{}.stats", log_path, name)) {
+            Some(stats) => stats,
+            None => {
+                println!("{}: no stats", name);
+                continue;
+            },
+        };
+        println!("{:16}:\t{}: {} transactions, per transaction: {:.2} forced writes, {:.2} lazy writes, {:.2} messages",
+                 name,
+                 stats.variant,
+                 stats.transactions,
+                 stats.per_txn(stats.forced_writes),
+                 stats.per_txn(stats.lazy_writes),
+                 stats.per_txn(stats.messages));
+        if total.variant.is_empty() {
+            total.variant = stats.variant.clone();
+            total.transactions = stats.transactions;
+        }
+        total.forced_writes += stats.forced_writes;
+        total.lazy_writes += stats.lazy_writes;
+        total.messages += stats.messages;
+        total.decide_micros += stats.decide_micros;
+    }
+    if !total.variant.is_empty() {
+        println!("{:16}:\t{}: {} transactions, per transaction: {:.2} forced writes, {:.2} lazy writes, {:.2} messages",
+                 "total",
+                 total.variant,
+                 total.transactions,
+                 total.per_txn(total.forced_writes),
+                 total.per_txn(total.lazy_writes),
+                 total.per_txn(total.messages));
+        println!("{:16}:\t{:.3} ms from proposal to decision per transaction",
+                 "latency",
+                 total.per_txn(total.decide_micros) / 1000.0);
+    }
+}
+
+///
+/// CheckConfig
+/// What check mode needs to know of the run it checks
+///
+#[derive(Clone, Debug)]
+pub struct CheckConfig {
+    pub num_clients: u32,               // Number of clients
+    pub num_requests: u32,              // Number of requests per client
+    pub num_participants: u32,          // Number of participants
+    pub num_acceptors: u32,             // Number of acceptors (Paxos Commit only)
+    pub log_path: String,               // Directory for client, participant, and coordinator logs
+    pub repair: bool,                   // Truncate torn tails off the logs before checking them
+    pub protocol: CommitProtocol,       // Commit protocol of the run
+    pub presumption: Presumption,       // 2PC variant of the run: which decisions have no record
+}
+
+///
+/// check_last_run()
+///
+/// Accepts a directory where client, participant, coordinator and acceptor
+/// log files are found, and the number of clients, participants and
+/// acceptors. Streams and analyses log files to check a handful of
+/// correctness invariants.
+///
+/// Returns false if a check failed or a log could not be read.
+///
+pub fn check_last_run(config: &CheckConfig) -> bool {
+
+        let num_participants = config.num_participants;
+        let log_path = &config.log_path;
+        let repair = config.repair;
+        let protocol = config.protocol;
+        info!("Checking {} run:  {} requests * {} clients, {} participants",
+              protocol.name(),
+              config.num_requests,
+              config.num_clients,
               num_participants);
+        let seeds = seed::read(log_path);
+        if !seeds.is_empty() {
+            println!("Seeds: {}", seeds.iter().map(|seed| seed.to_string()).collect::<Vec<String>>().join(", "));
+        }
 
         let coord_log_path = format!("{}//{}", log_path, "coordinator.log");
-        let coord_log = OpLog::from_file(coord_log_path);
-
-        let lock = coord_log.arc();
-        let coord_map = lock.lock().unwrap();
-
-        // Filter coordinator logs for Commit and Abort
-        let committed: HashMap<u32, message::ProtocolMessage> =
-            coord_map.iter()
-            .filter(|e| (*e.1).mtype == MessageType::CoordinatorCommit)
-            .map(|(k,v)| (k.clone(), v.clone()))
-            .collect();
-        let aborted: HashMap<u32, message::ProtocolMessage> =
-            coord_map.iter()
-            .filter(|e| (*e.1).mtype == MessageType::CoordinatorAbort)
-            .map(|(k,v)| (k.clone(), v.clone()))
-            .collect();
-
-        let num_commit = committed.len();
-        let num_abort = aborted.len();
-
-        // Iterate and check each participant
+        let mut coord_log = match open_log(&coord_log_path, repair) {
+            Some(log) => log,
+            None => return false,
+        };
+
+        let mut result = true;
+        let mut checks = Vec::new();
         for pid in 0..num_participants {
-            let participant_id_str = format!("participant_{}", pid);
-            let participant_log_path = format!("{}//{}.log", log_path, participant_id_str);
-            let participant_oplog = OpLog::from_file(participant_log_path);
-            let participant_lock = participant_oplog.arc();
-            let participant_log = participant_lock.lock().unwrap();
-            check_participant(&participant_id_str, num_commit, num_abort, &committed, &participant_log);
+            match ParticipantCheck::open(pid, log_path, repair) {
+                Some(check) => checks.push(check),
+                None => result = false,
+            }
+        }
+
+        // Transactions that lost messages on purpose
+        let drops = transport::read_drops(log_path);
+        let lossy: HashSet<String> = drops.iter().map(|dropped| dropped.txid.clone()).collect();
+
+        let mut undecided = HashMap::new();
+        let coord = match stream_coordinator(&mut coord_log, config, &mut checks, &mut undecided, &lossy) {
+            Some(coord) => coord,
+            None => return false,
+        };
+
+        // Finish and report the check of each participant
+        let mut truncated = false;
+        for mut check in checks {
+            while check.step() {}
+            truncated |= check.log.truncated();
+            result &= check.finish(&coord, &mut undecided, &lossy);
+        }
+        result &= check_undecided(num_participants, truncated, &undecided);
+        let cut: HashSet<String> = drops.iter().filter(|dropped| dropped.partitioned).map(|dropped| dropped.txid.clone()).collect();
+        result &= check_partitions(num_participants, log_path, &cut);
+        let num_acceptors = match protocol {
+            CommitProtocol::PaxosCommit => {
+                result &= check_acceptors(num_participants, config.num_acceptors, log_path, repair, &coord.decisions);
+                config.num_acceptors
+            },
+            _ => 0,
+        };
+        report_drops(&drops);
+        report_costs(num_participants, num_acceptors, log_path);
+        result
+    }
+
+#[cfg(test)]
+mod tests {
+    extern crate serde_json;
+
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    use oplog::DurabilityPolicy;
+    use oplog::LogConfig;
+    use oplog::LogFormat;
+    use oplog::LogRecord;
+
+    use message::MessageType::CheckpointBegin;
+    use message::MessageType::CheckpointEnd;
+    use message::MessageType::CoordinatorAbort as Abort;
+    use message::MessageType::CoordinatorCollecting;
+    use message::MessageType::CoordinatorCommit as Commit;
+    use message::MessageType::ParticipantVoteAbort as VoteAbort;
+    use message::MessageType::ParticipantVoteCommit as VoteCommit;
+
+    // A directory of its own for every test, tests run in parallel
+    fn temp_dir(name: &str) -> String {
+        let dir = env::temp_dir().join(format!("checker_test_{}_{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.to_string_lossy().into_owned()
+    }
+
+    fn write_log(dir: &str, name: &str, records: &[(MessageType, &str)]) {
+        let config = LogConfig {
+            durability: DurabilityPolicy::Flush,
+            format: LogFormat::Json,
+            segment_size: 0,
+            truncate: false,
+        };
+        let mut log = OpLog::new(format!("{}//{}.log", dir, name), config);
+        for (mtype, txid) in records {
+            log.append(*mtype, txid.to_string(), name.to_string(), 0).unwrap();
         }
     }
 
+    // A JSON log with records appended at the given second, so a test can
+    // span minutes
+    fn write_timed_log(dir: &str, name: &str, records: &[(MessageType, &str, u64)]) {
+        let mut text = String::new();
+        for (n, (mtype, txid, secs)) in records.iter().enumerate() {
+            let record = LogRecord {
+                lsn: n as u64 + 1,
+                timestamp: secs * 1_000_000,
+                msg: ProtocolMessage::generate(*mtype, txid.to_string(), name.to_string(), 0, 0),
+            };
+            text.push_str(&serde_json::to_string(&record).unwrap());
+            text.push('\n');
+        }
+        fs::write(format!("{}//{}.log", dir, name), text).unwrap();
+    }
 
+    fn config(dir: &str, num_participants: u32, protocol: CommitProtocol, presumption: Presumption) -> CheckConfig {
+        CheckConfig {
+            num_clients: 1,
+            num_requests: 4,
+            num_participants,
+            num_acceptors: 0,
+            log_path: dir.to_string(),
+            repair: false,
+            protocol,
+            presumption,
+        }
+    }
+
+    fn check(dir: &str, num_participants: u32, protocol: CommitProtocol, presumption: Presumption) -> bool {
+        check_last_run(&config(dir, num_participants, protocol, presumption))
+    }
+
+    #[test]
+    fn participants_checked_in_one_pass() {
+        let dir = temp_dir("one_pass");
+        write_log(&dir, "coordinator", &[(Commit, "tx_0"), (Abort, "tx_1"), (Commit, "tx_2")]);
+        // in step with the coordinator
+        write_log(&dir, "participant_0", &[
+            (VoteCommit, "tx_0"), (Commit, "tx_0"),
+            (VoteAbort, "tx_1"), (Abort, "tx_1"),
+            (VoteCommit, "tx_2"), (Commit, "tx_2")]);
+        // every vote before the first decision
+        write_log(&dir, "participant_1", &[
+            (VoteCommit, "tx_0"), (VoteCommit, "tx_1"), (VoteCommit, "tx_2"),
+            (Commit, "tx_0"), (Abort, "tx_1"), (Commit, "tx_2")]);
+        assert!(check(&dir, 2, CommitProtocol::TwoPhase, Presumption::Nothing));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn participant_against_the_coordinator() {
+        let dir = temp_dir("against");
+        write_log(&dir, "coordinator", &[(Commit, "tx_0"), (Abort, "tx_1"), (Commit, "tx_2")]);
+        write_log(&dir, "participant_0", &[
+            (VoteCommit, "tx_0"), (Commit, "tx_0"),
+            (VoteCommit, "tx_1"), (Abort, "tx_1"),
+            (VoteCommit, "tx_2"), (Commit, "tx_2")]);
+        // commits what the coordinator aborted
+        write_log(&dir, "participant_1", &[
+            (VoteCommit, "tx_0"), (Commit, "tx_0"),
+            (VoteCommit, "tx_1"), (Commit, "tx_1"),
+            (VoteCommit, "tx_2"), (Commit, "tx_2")]);
+        assert!(!check(&dir, 2, CommitProtocol::TwoPhase, Presumption::Nothing));
+
+        // a coordinator commit without a vote to commit
+        write_log(&dir, "participant_1", &[
+            (VoteCommit, "tx_0"), (Commit, "tx_0"),
+            (VoteAbort, "tx_1"), (Abort, "tx_1"),
+            (VoteAbort, "tx_2")]);
+        assert!(!check(&dir, 2, CommitProtocol::TwoPhase, Presumption::Nothing));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn unsettled_evicted_past_the_window() {
+        let dir = temp_dir("window");
+        // the participant never hears the decision of tx_0
+        write_timed_log(&dir, "participant_0", &[
+            (VoteCommit, "tx_0", 5),
+            (VoteCommit, "tx_1", 290), (Commit, "tx_1", 310),
+            (VoteCommit, "tx_2", 490), (Commit, "tx_2", 510),
+            (VoteCommit, "tx_3", 690), (Commit, "tx_3", 710)]);
+        let decisions = [(Commit, "tx_0", 10), (Commit, "tx_1", 300), (Commit, "tx_2", 500), (Commit, "tx_3", 700)];
+        let checkpoints = [(CheckpointBegin, "checkpoint", 100), (CheckpointEnd, "checkpoint", 100),
+                           (CheckpointBegin, "checkpoint", 400), (CheckpointEnd, "checkpoint", 400),
+                           (CheckpointBegin, "checkpoint", 600), (CheckpointEnd, "checkpoint", 600)];
+        let mut records: Vec<(MessageType, &str, u64)> = decisions.iter().chain(checkpoints.iter()).cloned().collect();
+        records.sort_by_key(|record| record.2);
+
+        let stream = |records: &[(MessageType, &str, u64)]| {
+            write_timed_log(&dir, "coordinator", records);
+            let mut coord_log = open_log(&format!("{}//coordinator.log", dir), false).unwrap();
+            let mut checks = vec![ParticipantCheck::open(0, &dir, false).unwrap()];
+            let mut undecided = HashMap::new();
+            let config = config(&dir, 1, CommitProtocol::TwoPhase, Presumption::Nothing);
+            let coord = stream_coordinator(&mut coord_log, &config, &mut checks, &mut undecided, &HashSet::new()).unwrap();
+            let mut check = checks.pop().unwrap();
+            let kept = check.txns.len() + check.outcomes.len();
+            let unsettled = check.num_unsettled;
+            while check.step() {}
+            (kept, unsettled, check.finish(&coord, &mut undecided, &HashSet::new()))
+        };
+        // the second checkpoint moves the window past tx_0
+        assert_eq!(stream(&records), (0, 1, true));
+        // without checkpoints the window is the whole log
+        assert_eq!(stream(&decisions), (2, 0, true));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn presumed_decisions_have_no_record() {
+        let dir = temp_dir("presumed");
+        let finish = |presumption: Presumption| {
+            let mut checks = vec![ParticipantCheck::open(0, &dir, false).unwrap()];
+            let mut coord_log = open_log(&format!("{}//coordinator.log", dir), false).unwrap();
+            let mut undecided = HashMap::new();
+            let config = config(&dir, 1, CommitProtocol::TwoPhase, presumption);
+            let coord = stream_coordinator(&mut coord_log, &config, &mut checks, &mut undecided, &HashSet::new()).unwrap();
+            let mut check = checks.pop().unwrap();
+            while check.step() {}
+            let result = check.finish(&coord, &mut undecided, &HashSet::new());
+            (result, check.num_presumed_commit, check.num_presumed_abort, check.num_terminated_abort)
+        };
+
+        // presumed abort: the coordinator logs its commits only
+        write_log(&dir, "coordinator", &[(Commit, "tx_0"), (Commit, "tx_2")]);
+        write_log(&dir, "participant_0", &[
+            (VoteCommit, "tx_0"), (Commit, "tx_0"),
+            (VoteAbort, "tx_1"), (Abort, "tx_1"),
+            (VoteCommit, "tx_2"), (Commit, "tx_2"),
+            (VoteCommit, "tx_3"), (Abort, "tx_3")]);
+        assert_eq!(finish(Presumption::Abort), (true, 0, 2, 0));
+        // presumed nothing: the aborts were never decided by the coordinator
+        assert_eq!(finish(Presumption::Nothing), (true, 0, 0, 2));
+        // a commit is never presumed in presumed abort
+        write_log(&dir, "coordinator", &[(Commit, "tx_0")]);
+        assert!(!finish(Presumption::Abort).0);
+
+        // presumed commit: a Collecting record before the votes
+        write_log(&dir, "coordinator", &[
+            (CoordinatorCollecting, "tx_0"), (Commit, "tx_0"), (CoordinatorCollecting, "tx_1"), (Abort, "tx_1"),
+            (CoordinatorCollecting, "tx_2"), (Commit, "tx_2"), (CoordinatorCollecting, "tx_3"), (Abort, "tx_3")]);
+        assert_eq!(finish(Presumption::Commit), (true, 0, 0, 0));
+        // without a decision, a Collecting record means abort
+        write_log(&dir, "coordinator", &[
+            (CoordinatorCollecting, "tx_0"), (CoordinatorCollecting, "tx_1"), (Abort, "tx_1"),
+            (CoordinatorCollecting, "tx_2"), (Commit, "tx_2"), (CoordinatorCollecting, "tx_3"), (Abort, "tx_3")]);
+        assert!(!finish(Presumption::Commit).0);
+        // no record at all is a commit
+        write_log(&dir, "coordinator", &[(Abort, "tx_1"), (Abort, "tx_3")]);
+        assert_eq!(finish(Presumption::Commit), (true, 2, 0, 0));
+        // a presumed commit needs a vote to commit all the same
+        write_log(&dir, "participant_0", &[(VoteAbort, "tx_0"), (Commit, "tx_0")]);
+        write_log(&dir, "coordinator", &[]);
+        assert!(!finish(Presumption::Commit).0);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -4,28 +4,45 @@
 //!
 extern crate ipc_channel;
 extern crate log;
+extern crate rand;
 extern crate stderrlog;
 
-use std::thread;
-use std::time::Duration;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::collections::HashMap;
 
+use client::rand::prelude::*;
 use client::ipc_channel::ipc::IpcReceiver as Receiver;
-use client::ipc_channel::ipc::TryRecvError;
-use client::ipc_channel::ipc::IpcSender as Sender;
+use transport::Sender;
 
 use message;
 use message::MessageType;
-use message::RequestStatus;
+use message::OpKind;
+use message::Operation;
+/// keys each participant owns
+const KEYS_PER_PARTICIPANT: u32 = 16;
+
+///
+/// Workload
+/// What the requests of a client touch: one key of each of the
+/// num_participants participants, written with probability write_prob. The
+/// keys and writes are drawn from rng.
+///
+#[derive(Debug)]
+pub struct Workload {
+    pub num_participants: u32,
+    pub write_prob: f64,
+    pub rng: StdRng,
+}
+
 // Client state and primitives for communicating with the coordinator
 #[derive(Debug)]
 pub struct Client {
     pub id_str: String,
     pub running: Arc<AtomicBool>,
     pub num_requests: u32,
-	tx : Sender<message::ProtocolMessage>,
+    pub epoch: u32,
+    workload: Workload,
+	tx : Sender,
 	rx : Receiver<message::ProtocolMessage>,
 	successful_ops: u64,
     failed_ops: u64,
@@ -53,15 +70,21 @@ impl Client {
     /// HINT: You may want to pass some global flags that indicate whether
     ///       the protocol is still running to this constructor
     ///
+    /// Every request touches one key of each participant, as workload says.
+    ///
     pub fn new(id_str: String,
                running: Arc<AtomicBool>,
-			   sender: Sender<message::ProtocolMessage>,
+               epoch: u32,
+               workload: Workload,
+			   sender: Sender,
 				recvr: Receiver<message::ProtocolMessage>
 			   ) -> Client {
         Client {
-            id_str: id_str,
-            running: running,
+            id_str,
+            running,
             num_requests: 0,
+            epoch,
+            workload,
 			tx: sender,
 			rx: recvr,
 			successful_ops: 0,
@@ -92,21 +115,44 @@ impl Client {
   
     }
 
+    ///
+    /// next_ops(&mut self)
+    /// Operations of the next request: one per participant, on a random key
+    /// the participant owns, a write with probability write_prob
+    ///
+    fn next_ops(&mut self) -> Vec<Operation> {
+        let write_prob = self.workload.write_prob;
+        let num_participants = self.workload.num_participants;
+        let rng = &mut self.workload.rng;
+        (0..num_participants).map(|p| {
+            let x: f64 = rng.gen();
+            Operation {
+                kind: if x < write_prob { OpKind::Write } else { OpKind::Read },
+                key: p + num_participants * rng.gen_range(0, KEYS_PER_PARTICIPANT),
+            }
+        }).collect()
+    }
+
     ///
     /// send_next_operation(&mut self)
     /// Send the next operation to the coordinator
     ///
     pub fn send_next_operation(&mut self) {
 
-        // Create a new request with a unique TXID.
-        self.num_requests = self.num_requests + 1;
-        let txid = format!("client{}_op_{}", self.id_str.clone(), self.num_requests);
+        // Create a new request with a unique TXID. A recovered coordinator
+        // hands out a new epoch so txids of earlier runs are not reused.
+        self.num_requests += 1;
+        let txid = match self.epoch {
+            0 => format!("client{}_op_{}", self.id_str.clone(), self.num_requests),
+            e => format!("client{}_e{}_op_{}", self.id_str.clone(), e, self.num_requests),
+        };
 		let cl_id: u32 = self.id_str.parse().unwrap();
-        let pm = message::ProtocolMessage::generate(message::MessageType::ClientRequest,
+        let mut pm = message::ProtocolMessage::generate(message::MessageType::ClientRequest,
                                                     txid.clone(),
                                                     self.id_str.clone(),
                                                     self.num_requests,
-													cl_id.clone());
+													cl_id);
+        pm.ops = self.next_ops();
         info!("{}::Sending operation #{}", self.id_str.clone(), self.num_requests);
 
         // TODO
@@ -115,6 +161,21 @@ impl Client {
         trace!("{}::Sent operation #{}", self.id_str.clone(), self.num_requests);
     }
 
+    ///
+    /// send_done(&mut self)
+    /// Tell the coordinator this client sends no more requests
+    ///
+    pub fn send_done(&mut self) {
+		let cl_id: u32 = self.id_str.parse().unwrap();
+        let pm = message::ProtocolMessage::generate(message::MessageType::ClientRequest,
+                                                    "done".to_string(),
+                                                    self.id_str.clone(),
+                                                    self.num_requests,
+													cl_id);
+		self.tx.send(pm).unwrap();
+        trace!("{}::Done after {} operations", self.id_str.clone(), self.num_requests);
+    }
+
     ///
     /// recv_result()
     /// Wait for the coordinator to respond with the result for the
@@ -125,21 +186,16 @@ impl Client {
 
         info!("{}::Receiving Coordinator Result", self.id_str.clone());
 
-		match self.rx.recv() {
-			Ok(res) => { // Do something interesting wth your result
-				if res.mtype==MessageType::CoordinatorExit &&  res.txid == "done" {
-					return false	
-				}
-				match res.mtype{
-					MessageType::ClientResultCommit => self.successful_ops+=1,
-					MessageType::ClientResultAbort => self.failed_ops+=1,   
-					//CoordinatorExit => self.running =false,
-					_ => println!("not supposed to be here")
-				}
-            },
-            Err(_) => {
-
-            }
+		if let Ok(res) = self.rx.recv() { // Do something interesting wth your result
+			if res.mtype==MessageType::CoordinatorExit &&  res.txid == "done" {
+				return false	
+			}
+			match res.mtype{
+				MessageType::ClientResultCommit => self.successful_ops+=1,
+				MessageType::ClientResultAbort => self.failed_ops+=1,   
+				//CoordinatorExit => self.running =false,
+				_ => println!("not supposed to be here")
+			}
         }
 		true
     }
@@ -153,7 +209,7 @@ impl Client {
         // TODO: Collect actual stats
         let successful_ops: u64 = self.successful_ops;
         let failed_ops: u64 = self.failed_ops;
-        let unknown_ops: u64 = 0;
+        let unknown_ops: u64 = self.unknown_ops;
 
         println!("{:16}:\tCommitted: {:6}\tAborted: {:6}\tUnknown: {:6}", format!("client_{}",self.id_str.clone()), successful_ops, failed_ops, unknown_ops);
     }
@@ -169,24 +225,25 @@ impl Client {
 
        // println!("client protocol{}",self.id_str);
 		let mut counter =0;
-		loop{
+		while counter < n_requests && self.running.load(Ordering::SeqCst) {
 			self.send_next_operation();
 			counter+= 1;
-			if counter == n_requests{
-				break;
-			}
 		}
+		self.send_done();
+		let sent = counter;
 		counter =0;
 		
-		loop{
+		while counter < sent {
 			let success = self.recv_result();
 			
 			counter+= 1;
-			if counter == n_requests || !success{
+			if !success{
 				break;
 			}
 		}
 		//println!("received results {}",self.id_str);
+		// results the transport dropped, or that the run ended before
+		self.unknown_ops = sent as u64 - self.successful_ops - self.failed_ops;
         self.wait_for_exit_signal();
         self.report_status();
     }
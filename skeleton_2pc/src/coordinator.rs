@@ -8,26 +8,43 @@ extern crate rand;
 extern crate ipc_channel;
 
 use std::collections::HashMap;
+use std::collections::VecDeque;
 use std::sync::Arc;
-use std::sync::Mutex;
+use std::sync::mpsc;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
 use std::time::Duration;
 use std::time::Instant;
 
-use coordinator::ipc_channel::ipc::IpcSender as Sender;
+use transport::Sender;
 use coordinator::ipc_channel::ipc::IpcReceiver as Receiver;
+use coordinator::ipc_channel::ipc::IpcReceiverSet;
+use coordinator::ipc_channel::ipc::IpcSelectionResult;
 use coordinator::ipc_channel::ipc::TryRecvError;
-use coordinator::ipc_channel::ipc::channel;
 
-use message;
+use acceptor;
+use crash::CrashPoint;
+use crash::CrashSpec;
+use message::CommitProtocol;
 use message::MessageType;
+use message::Presumption;
 use message::ProtocolMessage;
 use message::RequestStatus;
 use oplog;
+use stats::RunStats;
+
+/// rounds of re-sending unacked decisions at the end of a run
+const FINAL_ACK_ROUNDS: u32 = 3;
+/// how long phase 1 waits for votes; missing votes are taken as aborts
+const VOTE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Restart
+/// A participant run mode restarted after a crash: its name, and the
+/// coordinator's channels to and from the new process
+pub type Restart = (String, Sender, Receiver<ProtocolMessage>);
 
 /// CoordinatorState
-/// States for 2PC state machine
+/// States for 2PC / 3PC state machine of one transaction
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum CoordinatorState {
     Quiescent,
@@ -35,19 +52,100 @@ pub enum CoordinatorState {
     ProposalSent,
     ReceivedVotesAbort,
     ReceivedVotesCommit,
+    PreCommitSent,
     SentGlobalDecision
 }
 
+/// Instance
+/// Paxos Commit: the coordinator's view of the consensus instance that
+/// chooses one participant's vote on a txid
+#[derive(Debug, Default)]
+struct Instance {
+    ballot: u32,                                // ballot of the coordinator's phase 1, 0 before
+    accepted: HashMap<(u32, MessageType), Vec<String>>, // acceptors that accepted a vote in a ballot
+    promises: Vec<(String, Option<(u32, MessageType)>)>, // acceptors that promised the ballot, what they had accepted
+}
+
+/// Txn
+/// State machine of one in-flight transaction. It lives from the proposal
+/// until the decision is sent; acks of the decision are tracked in unacked.
+#[derive(Debug)]
+struct Txn {
+    seq: u32,                                   // number of the transaction in this run, 0 if recovered from the acceptors
+    state: CoordinatorState,
+    request: ProtocolMessage,                   // the client's request
+    votes: HashMap<String, RequestStatus>,      // participant -> vote, read-only counts as commit
+    read_only: Vec<String>,                     // participants that voted read-only
+    pending: Vec<String>,                       // participants yet to ack the PreCommit
+    record: Option<ProtocolMessage>,            // latest record logged for the txid, if any
+    decision_requests: Vec<(String, ProtocolMessage)>, // answered once decided
+    instances: HashMap<String, Instance>,       // Paxos Commit: participant -> its instance
+    started: Instant,                           // proposal sent
+    timer: Instant,                             // start of the current phase
+}
+
+impl Txn {
+
+    ///
+    /// new()
+    /// A transaction that is about to propose `request`
+    ///
+    fn new(seq: u32, request: ProtocolMessage) -> Txn {
+        Txn {
+            seq,
+            state: CoordinatorState::ReceivedRequest,
+            request,
+            votes: HashMap::new(),
+            read_only: Vec::new(),
+            pending: Vec::new(),
+            record: None,
+            decision_requests: Vec::new(),
+            instances: HashMap::new(),
+            started: Instant::now(),
+            timer: Instant::now(),
+        }
+    }
+}
+
+///
+/// CoordinatorConfig
+/// How the coordinator runs
+///
+#[derive(Clone, Debug)]
+pub struct CoordinatorConfig {
+    pub log_config: oplog::LogConfig,       // durability and format of the log
+    pub protocol: CommitProtocol,           // 2PC, 3PC or Paxos Commit
+    pub presumption: Presumption,           // 2PC variant, what a txid with no record is taken to be
+    pub ack_timeout: Duration,              // how long to wait for acks before re-sending a decision
+    pub max_in_flight: usize,               // most transactions in phase 1 or PreCommit at once
+    pub crash: Option<CrashSpec>,           // where to crash, in which transaction, None for never
+}
+
 /// Coordinator
 /// Struct maintaining state for coordinator
 #[derive(Debug)]
 pub struct Coordinator {
-    state: CoordinatorState,
     running: Arc<AtomicBool>,
     log: oplog::OpLog,
-	client_map: HashMap<String,(Sender<ProtocolMessage>, Receiver<ProtocolMessage>)>,
-	part_map: HashMap<String,(Sender<ProtocolMessage>, Receiver<ProtocolMessage>)>,
-	num_requests: u32,
+	client_map: HashMap<String,Sender>,
+	client_rx: Vec<(String, Receiver<ProtocolMessage>)>,
+	part_map: HashMap<String,(Sender, Receiver<ProtocolMessage>)>,
+	children: Vec<String>,
+	acceptor_map: HashMap<String,(Sender, Receiver<ProtocolMessage>)>,
+	decisions: HashMap<String, ProtocolMessage>,
+	epoch: u32,
+	recovering: bool,
+	protocol: CommitProtocol,
+	presumption: Presumption,
+	ack_timeout: Duration,
+	unacked: HashMap<String, (ProtocolMessage, Vec<String>, Instant)>,
+	txns: HashMap<String, Txn>,
+	max_in_flight: usize,
+	crash: Option<CrashSpec>,
+	crashed: bool,
+	restarts: Option<mpsc::Receiver<Restart>>,
+	num_txns: u32,
+	stats: RunStats,
 	successful_ops: u64,
     failed_ops: u64,
     unknown_ops: u64,
@@ -62,6 +160,7 @@ pub struct Coordinator {
 /// 3. report_status -- Report of aggregate commit/abort/unknown stats on exit.
 /// 4. participant_join -- What to do when a participant joins
 /// 5. client_join -- What to do when a client joins
+/// 6. acceptor_join -- What to do when a Paxos Commit acceptor joins
 ///
 impl Coordinator {
 
@@ -72,35 +171,146 @@ impl Coordinator {
     /// <params>
     ///     log_path: directory for log files --> create a new log there.
     ///     r: atomic bool --> still running?
+    ///     config: log settings, protocol, timeouts and crash point
     ///
-    pub fn new(
-        log_path: String,
-        r: &Arc<AtomicBool>, nr: u32) -> Coordinator {
+    pub fn new(log_path: String, r: &Arc<AtomicBool>, config: CoordinatorConfig) -> Coordinator {
+
+        Coordinator::new_with_log(oplog::OpLog::new(log_path, config.log_config), r, config)
+    }
 
+    ///
+    /// recover()
+    /// Initialize a coordinator from the log of a previous run
+    ///
+    /// <params>
+    ///     log_path: path of the existing log --> replayed, then appended to.
+    ///     r: atomic bool --> still running?
+    ///     config: log settings, protocol, timeouts and crash point
+    ///
+    /// Every CoordinatorCommit / CoordinatorAbort found in the log that is
+    /// not Completed yet is kept so that protocol() can re-send it and answer
+    /// in-doubt participants before taking new work, unless it is the
+    /// presumed outcome, which the presumption answers for. The epoch is bumped past the log's sequence number,
+    /// which only grows, so new client txids do not collide with old ones.
+    /// A 3PC txid that only got as far as PreCommit is not a decision: its
+    /// participants settled it among themselves with the termination protocol.
+    /// Presumed commit: a txid still collecting votes must not be presumed
+    /// committed, so its abort is forced to the log first. In Paxos Commit
+    /// a txid with no decision in the log is decided by the acceptors.
+    ///
+    pub fn recover(log_path: String, r: &Arc<AtomicBool>, config: CoordinatorConfig) -> Coordinator {
+
+        let mut log = oplog::OpLog::reopen(log_path, config.log_config);
+        let mut decisions = HashMap::new();
+        for txid in log.txids() {
+            let record = log.last_record_for_txid(&txid).unwrap();
+            match record.msg.mtype {
+                MessageType::CoordinatorCommit | MessageType::CoordinatorAbort if config.presumption.explicit(record.msg.mtype) => {
+                    decisions.insert(txid, record.msg);
+                },
+                MessageType::CoordinatorCollecting => {
+                    let mut pm = record.msg;
+                    pm.mtype = MessageType::CoordinatorAbort;
+                    log.append(pm.mtype, pm.txid.clone(), pm.senderid.clone(), pm.opid).unwrap();
+                    decisions.insert(txid, pm);
+                },
+                _ => {},
+            }
+        }
+        let num_records = log.arc().lock().unwrap().len();
+        info!("coordinator::Recovered {} decisions from {} log records", decisions.len(), num_records);
+
+        let epoch = log.lsn() as u32 + 1;
+        let mut coordinator = Coordinator::new_with_log(log, r, config);
+        coordinator.decisions = decisions;
+        coordinator.epoch = epoch;
+        coordinator.recovering = true;
+        coordinator
+    }
+
+    fn new_with_log(log: oplog::OpLog, r: &Arc<AtomicBool>, config: CoordinatorConfig) -> Coordinator {
         Coordinator {
-            state: CoordinatorState::Quiescent,
-            log: oplog::OpLog::new(log_path),
+            log,
             running: r.clone(),
 			client_map: HashMap::new(),
+			client_rx: Vec::new(),
 			part_map: HashMap::new(),
-            num_requests: nr,
+			children: Vec::new(),
+			acceptor_map: HashMap::new(),
+			decisions: HashMap::new(),
+			epoch: 0,
+			recovering: false,
+			protocol: config.protocol,
+			presumption: config.presumption,
+			ack_timeout: config.ack_timeout,
+			unacked: HashMap::new(),
+			txns: HashMap::new(),
+			max_in_flight: config.max_in_flight,
+			crash: config.crash,
+			crashed: false,
+			restarts: None,
+			num_txns: 0,
+			stats: RunStats::new(config.protocol, config.presumption),
 			successful_ops: 0,
 			failed_ops: 0,
 			unknown_ops: 0,
         }
     }
 
+    ///
+    /// epoch()
+    /// Epoch that clients must prefix to their txids (0 for a fresh log)
+    ///
+    pub fn epoch(&self) -> u32 {
+        self.epoch
+    }
+
+    ///
+    /// barrier()
+    /// Barrier of the log, for the transport to hold the coordinator's
+    /// messages for: records are appended without waiting, and the
+    /// messages that depend on them wait instead
+    ///
+    pub fn barrier(&self) -> Option<oplog::Barrier> {
+        self.log.barrier()
+    }
+
+    ///
+    /// flush()
+    /// Send the messages the transport held back for the log, once the
+    /// records they wait for are durable, after waiting for that if wait is
+    /// set. Before waiting for an answer, it has to be waited for. A log
+    /// that can not be written is fatal.
+    ///
+    fn flush(&self, wait: bool) {
+        for (tx, _) in self.part_map.values() {
+            tx.flush(wait).unwrap();
+        }
+        for tx in self.client_map.values() {
+            tx.flush(wait).unwrap();
+        }
+        for (tx, _) in self.acceptor_map.values() {
+            tx.flush(wait).unwrap();
+        }
+    }
+
     ///
     /// participant_join()
-    /// Adds a new participant for the coordinator to keep track of
+    /// Adds a new participant for the coordinator to keep track of. The
+    /// protocol runs with the children; in tree 2PC the others are reached
+    /// through them, and directly only to recover.
     ///
     /// HINT: Keep track of any channels involved!
     /// HINT: You may need to change the signature of this function
     ///
-    pub fn participant_join(&mut self, name: &String, tx: Sender<ProtocolMessage>,rx: Receiver<ProtocolMessage>) {
-        assert!(self.state == CoordinatorState::Quiescent);
+    pub fn participant_join(&mut self, name: &str, tx: Sender,rx: Receiver<ProtocolMessage>, child: bool) {
+        assert!(self.txns.is_empty());
        // TODO
-	   	self.part_map.insert( name.clone(),(tx,rx));
+	   	self.part_map.insert( name.to_string(),(tx,rx));
+		if child {
+			self.children.push(name.to_string());
+			self.children.sort();
+		}
     }
 
     ///
@@ -110,17 +320,58 @@ impl Coordinator {
     /// HINT: Keep track of any channels involved!
     /// HINT: You may need to change the signature of this function
     ///
-    pub fn client_join(&mut self, name: &String, tx: Sender<ProtocolMessage>,rx: Receiver<ProtocolMessage>) {
-        assert!(self.state == CoordinatorState::Quiescent);
+    pub fn client_join(&mut self, name: &str, tx: Sender,rx: Receiver<ProtocolMessage>) {
+        assert!(self.txns.is_empty());
 
         // TODO
-		self.client_map.insert( name.clone(),(tx,rx));
+		self.client_map.insert( name.to_string(),tx);
+		self.client_rx.push((name.to_string(), rx));
+    }
+
+    ///
+    /// acceptor_join()
+    /// Paxos Commit: adds an acceptor for the coordinator to run the
+    /// consensus instances of the votes with
+    ///
+    pub fn acceptor_join(&mut self, name: &str, tx: Sender,rx: Receiver<ProtocolMessage>) {
+        assert!(self.txns.is_empty());
+		self.acceptor_map.insert( name.to_string(),(tx,rx));
+    }
+
+    ///
+    /// restarts_from()
+    /// Run mode restarts participants that crashed; their new channels come
+    /// in on rx
+    ///
+    pub fn restarts_from(&mut self, rx: mpsc::Receiver<Restart>) {
+		self.restarts = Some(rx);
+    }
+
+    ///
+    /// rejoin()
+    /// Swap in the channels of participants that were restarted, and return
+    /// their names. A restarted participant asks about its in-doubt txids
+    /// like one that recovers, and gets the decisions re-sent to it.
+    ///
+    fn rejoin(&mut self) -> Vec<String> {
+		let mut rejoined = Vec::new();
+		let restarts = match self.restarts {
+			Some(ref rx) => rx.try_iter().collect::<Vec<Restart>>(),
+			None => return rejoined,
+		};
+		for (name, tx, rx) in restarts {
+			info!("coordinator::Participant {} rejoins", name);
+			self.part_map.insert(name.clone(), (tx, rx));
+			rejoined.push(name);
+		}
+		rejoined
     }
 
     ///
     /// report_status()
     /// Report the abort/commit/unknown status (aggregate) of all transaction
-    /// requests made by this coordinator before exiting.
+    /// requests made by this coordinator before exiting. The log writes and
+    /// messages of the run go to the stats file next to the log.
     ///
     pub fn report_status(&mut self) {
         // TODO: Collect actual stats
@@ -129,9 +380,15 @@ impl Coordinator {
         let unknown_ops: u64 = self.unknown_ops;
 
         println!("coordinator     :\tCommitted: {:6}\tAborted: {:6}\tUnknown: {:6}", successful_ops, failed_ops, unknown_ops);
+
+        let (forced_writes, lazy_writes) = self.log.writes();
+        self.stats.transactions = self.num_txns as u64;
+        self.stats.forced_writes = forced_writes;
+        self.stats.lazy_writes = lazy_writes;
+        self.stats.write(&RunStats::path_for(self.log.path()));
     }
-	pub fn send_result(&mut self, mut pm:  ProtocolMessage, tx: &Sender<ProtocolMessage>){
-		
+	pub fn send_result(&mut self, mut pm:  ProtocolMessage, tx: &Sender){
+		let cl_id = pm.cl_id;
 		match pm.mtype{
 			MessageType::ParticipantVoteCommit => pm.mtype = MessageType::ClientResultCommit,  
 			MessageType::ParticipantVoteAbort => pm.mtype = MessageType::ClientResultAbort, 
@@ -139,174 +396,1167 @@ impl Coordinator {
 				//nothing
 			}								
 		}
-		tx.send(pm).unwrap();
+		if tx.send(pm).is_err() {
+			debug!("coordinator::Client {} is gone", cl_id);
+		}
 	}
+    ///
+    /// resend_decisions()
+    /// Re-send the recovered decisions that are not yet acknowledged to all
+    /// participants. Those are the explicit ones: a decision every
+    /// participant acknowledged has a Completed record and is not recovered,
+    /// and a presumed one needs no acks, as the presumption answers for it.
+    /// Participants that are not waiting on a txid ignore its decision, but
+    /// acknowledge it; until all of them have, it is unacked.
+    ///
+    pub fn resend_decisions(&mut self) {
+        let mut names: Vec<String> = self.part_map.keys().cloned().collect();
+        names.sort();
+        for (txid, pm) in &self.decisions {
+            if !self.presumption.explicit(pm.mtype) {
+                continue;
+            }
+            for (id, val) in &self.part_map {
+                let (tx, _) = val;
+                if tx.send(pm.clone()).is_err() {
+                    debug!("coordinator::Participant {} is gone", id);
+                }
+                self.stats.messages += 1;
+            }
+            self.unacked.insert(txid.clone(), (pm.clone(), names.clone(), Instant::now()));
+        }
+        if !self.unacked.is_empty() {
+            info!("coordinator::Re-sent {} recovered decisions", self.unacked.len());
+        }
+    }
+
+    ///
+    /// ack_received()
+    /// Note that participant `part` acknowledged the decision of txid. Once
+    /// the last one has, the txid is completed.
+    ///
+    fn ack_received(&mut self, part: &String, txid: &String) {
+        let done = match self.unacked.get_mut(txid) {
+            Some(entry) => {
+                entry.1.retain(|id| id != part);
+                entry.1.is_empty()
+            },
+            None => return,
+        };
+        if done {
+            self.unacked.remove(txid);
+            self.complete(txid);
+        }
+    }
+
+    ///
+    /// complete()
+    /// Every participant has the decision of txid on record, so nobody will
+    /// ask about it again: log that it is completed and forget it. The
+    /// Completed record is not forced; losing it only means re-sending the
+    /// decision after a crash.
+    ///
+    fn complete(&mut self, txid: &String) {
+        if let Some(pm) = self.decisions.remove(txid) {
+            self.log.append_lazy(MessageType::CoordinatorCompleted, pm.txid.clone(), pm.senderid.clone(), pm.opid);
+        }
+    }
+
+    ///
+    /// resend_unacked()
+    /// Re-send decisions to the participants that have not acknowledged
+    /// them within the ack timeout
+    ///
+    fn resend_unacked(&mut self) {
+        let now = Instant::now();
+        for (_, entry) in self.unacked.iter_mut() {
+            let (ref pm, ref missing, ref mut sent) = *entry;
+            if now.duration_since(*sent) < self.ack_timeout {
+                continue;
+            }
+            debug!("coordinator::Re-sending {:?} for {} to {} participants", pm.mtype, pm.txid, missing.len());
+            for id in missing {
+                let (tx, _) = self.part_map.get(id).unwrap();
+                if tx.send(pm.clone()).is_err() {
+                    debug!("coordinator::Participant {} is gone", id);
+                }
+                self.stats.messages += 1;
+            }
+            *sent = now;
+        }
+    }
+
+    ///
+    /// poll_acks()
+    /// Wait up to `wait` for the acks of unacked decisions, for instance at
+    /// the end of a run. Decision requests are answered on the spot.
+    ///
+    fn poll_acks(&mut self, wait: Duration) {
+        let names: Vec<String> = self.part_map.keys().cloned().collect();
+        let timer = Instant::now();
+        while !self.unacked.is_empty() && timer.elapsed() < wait {
+            self.flush(true);
+            for id in &names {
+                let res = {
+                    let (_, rx) = self.part_map.get(id).unwrap();
+                    rx.try_recv()
+                };
+                match res {
+                    Ok(ref pm) if pm.mtype == MessageType::ParticipantAck => self.ack_received(id, &pm.txid),
+                    Ok(ref pm) if pm.mtype == MessageType::ParticipantDecisionRequest => self.answer_decision_request(id, pm),
+                    Ok(pm) => warn!("coordinator::Unexpected {:?} for {} from participant {} while waiting for acks", pm.mtype, pm.txid, id),
+                    Err(_) => {},
+                }
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    ///
+    /// answer_decision_request()
+    /// Tell a participant the outcome of a txid it is in doubt about. A txid
+    /// with no decision on record was never decided. With presumed nothing
+    /// it is aborted, and the abort is logged so the answer can not change
+    /// later; otherwise the presumed outcome is the answer, and it is stable
+    /// without a record. In Paxos Commit nothing is presumed: the acceptors
+    /// know the outcome.
+    ///
+    fn answer_decision_request(&mut self, part: &String, request: &ProtocolMessage) {
+        if self.protocol == CommitProtocol::PaxosCommit && !self.decisions.contains_key(&request.txid) {
+            self.learn_outcome(part, request);
+            return;
+        }
+        let answer = match self.decisions.get(&request.txid) {
+            Some(pm) => pm.clone(),
+            None => {
+                let pm = ProtocolMessage::generate(self.presumption.presumed(), request.txid.clone(), request.senderid.clone(), request.opid, 0);
+                if self.presumption == Presumption::Nothing {
+                    info!("coordinator::No decision for {}, aborting it", request.txid);
+                    self.log.append_async(pm.mtype, pm.txid.clone(), pm.senderid.clone(), pm.opid);
+                    self.decisions.insert(pm.txid.clone(), pm.clone());
+                } else {
+                    info!("coordinator::No decision for {}, presumed {:?}", request.txid, pm.mtype);
+                }
+                pm
+            },
+        };
+        let (tx, _) = self.part_map.get(part).unwrap();
+        if tx.send(answer).is_err() {
+            debug!("coordinator::Participant {} is gone", part);
+        }
+        self.stats.messages += 1;
+    }
+
+    ///
+    /// learn_outcome()
+    /// Paxos Commit: the outcome of a txid with no decision on record is in
+    /// the votes the acceptors chose. The request waits in a transaction of
+    /// its own, with no client (seq 0), while phase 1 asks the acceptors
+    /// for every participant's vote.
+    ///
+    fn learn_outcome(&mut self, part: &str, request: &ProtocolMessage) {
+        if let Some(txn) = self.txns.get_mut(&request.txid) {
+            txn.decision_requests.push((part.to_string(), request.clone()));
+            return;
+        }
+        info!("coordinator::No decision for {}, asking the acceptors", request.txid);
+        let pm = ProtocolMessage::generate(MessageType::CoordinatorPropose, request.txid.clone(), request.senderid.clone(), request.opid, 0);
+        let mut txn = Txn::new(0, pm);
+        txn.state = CoordinatorState::ProposalSent;
+        txn.decision_requests.push((part.to_string(), request.clone()));
+        self.txns.insert(request.txid.clone(), txn);
+        self.start_phase1(&request.txid);
+    }
+
+    ///
+    /// resolve_in_doubt()
+    /// Answer the decision requests of recovering participants. Each one
+    /// sends a request per in-doubt txid followed by a "done" request. In
+    /// Paxos Commit, the ones with no decision on record are answered once
+    /// the acceptors have told their votes.
+    ///
+    pub fn resolve_in_doubt(&mut self) {
+        let names: Vec<String> = self.part_map.keys().cloned().collect();
+        for name in names {
+            loop {
+                let res = {
+                    let (_, rx) = self.part_map.get(&name).unwrap();
+                    rx.recv()
+                };
+                match res {
+                    Ok(request) => {
+                        if request.mtype == MessageType::ParticipantAck {
+                            // of a re-sent decision
+                            self.ack_received(&name, &request.txid);
+                            continue;
+                        }
+                        if request.mtype != MessageType::ParticipantDecisionRequest {
+                            warn!("coordinator::Unexpected {:?} from participant {} while recovering", request.mtype, name);
+                            continue;
+                        }
+                        if request.txid == "done" {
+                            break;
+                        }
+                        self.answer_decision_request(&name, &request);
+                    },
+                    Err(_) => break,
+                }
+            }
+        }
+        self.flush(true);
+        while !self.txns.is_empty() {
+            self.flush(true);
+            if !self.poll_acceptors() {
+                thread::sleep(Duration::from_millis(1));
+            }
+            self.check_timeouts();
+        }
+    }
+    ///
+    /// send_phase2()
+    /// Send a phase 2 message (PreCommit, or the decision) to the
+    /// participants in `to`, the ones that did not vote read-only. In the
+    /// transaction set to crash during_broadcast (seq is the transaction's
+    /// number) the coordinator crashes after the first participant: it
+    /// returns true, and the caller has to stop without a word to anyone.
+    ///
+    fn send_phase2(&mut self, pm: &ProtocolMessage, to: &Vec<String>, seq: u32) -> bool {
+        for id in to {
+            let (tx, _) = self.part_map.get(id).unwrap();
+            if tx.send(pm.clone()).is_err() {
+                debug!("coordinator::Participant {} is gone", id);
+            }
+            self.stats.messages += 1;
+            if self.crash_here(CrashPoint::DuringBroadcast, seq, pm) {
+                return true;
+            }
+        }
+        false
+    }
+
+    ///
+    /// crash_here()
+    /// True if the coordinator crashes at point of the transaction numbered
+    /// seq: it sets `crashed`, and the caller has to stop without a word to
+    /// anyone. Transactions recovered from the acceptors (seq 0) do not crash.
+    ///
+    fn crash_here(&mut self, point: CrashPoint, seq: u32, pm: &ProtocolMessage) -> bool {
+        match self.crash {
+            Some(ref crash) if seq > 0 && crash.fires(point, seq) => {},
+            _ => return false,
+        }
+        warn!("coordinator::Crashing {} of {} ({:?})", point.name(), pm.txid, pm.mtype);
+        // what was logged is durable, and what was sent went out
+        match point {
+            CrashPoint::DuringBroadcast | CrashPoint::AfterBroadcast => self.flush(true),
+            _ => self.log.sync().unwrap(),
+        }
+        self.crashed = true;
+        true
+    }
+
+    ///
+    /// start_txn()
+    /// Phase 1 of a client request: propose it to every participant. With
+    /// presumed commit a Collecting record is forced first: without it a
+    /// crash would leave the txid presumed committed. In Paxos Commit the
+    /// participants send their votes to the acceptors. In tree 2PC only the
+    /// children get the proposal and pass it down.
+    ///
+    fn start_txn(&mut self, request: ProtocolMessage) {
+        self.num_txns += 1;
+        let mut txn = Txn::new(self.num_txns, request.clone());
+        if self.presumption == Presumption::Commit {
+            let mut collecting = request.clone();
+            collecting.mtype = MessageType::CoordinatorCollecting;
+            self.log.append_async( collecting.mtype, collecting.txid.clone(), collecting.senderid.clone(), collecting.opid);
+            txn.record = Some(collecting);
+        }
+        let mut msg = request;
+        msg.mtype = MessageType::CoordinatorPropose;
+        for id in &self.children {
+            let (tx, _) = self.part_map.get(id).unwrap();
+            if tx.send(msg.clone()).is_err() {
+                debug!("coordinator::Participant {} is gone", id);
+            }
+            self.stats.messages += 1;
+        }
+        txn.state = CoordinatorState::ProposalSent;
+        self.txns.insert(msg.txid.clone(), txn);
+    }
+
+    ///
+    /// route()
+    /// Hand a message from participant `part` to the state machine of the
+    /// txid it is about. The first vote to abort ends phase 1 right away:
+    /// the txid is aborted whatever the others vote. Votes and PreCommit
+    /// acks for a txid that has moved on (they came after the timeout or
+    /// after an abort) are dropped, as are votes delivered twice.
+    ///
+    fn route(&mut self, part: &String, pm: ProtocolMessage) {
+        match pm.mtype {
+            MessageType::ParticipantVoteCommit | MessageType::ParticipantVoteAbort | MessageType::ParticipantVoteReadOnly => {
+                let all_in = match self.txns.get_mut(&pm.txid) {
+                    Some(ref mut txn) if txn.state == CoordinatorState::ProposalSent && !txn.votes.contains_key(part) => {
+                        let vote = match pm.mtype {
+                            MessageType::ParticipantVoteCommit => RequestStatus::Committed,
+                            MessageType::ParticipantVoteReadOnly => {
+                                txn.read_only.push(part.clone());
+                                RequestStatus::Committed
+                            },
+                            _ => RequestStatus::Aborted,
+                        };
+                        txn.votes.insert(part.clone(), vote);
+                        vote == RequestStatus::Aborted || txn.votes.len() == self.children.len()
+                    },
+                    _ => {
+                        debug!("coordinator::Late {:?} for {} from participant {}", pm.mtype, pm.txid, part);
+                        false
+                    },
+                };
+                if all_in {
+                    self.votes_in(&pm.txid);
+                }
+            },
+            MessageType::ParticipantAckPreCommit => {
+                let all_in = match self.txns.get_mut(&pm.txid) {
+                    Some(ref mut txn) if txn.state == CoordinatorState::PreCommitSent => {
+                        txn.pending.retain(|id| id != part);
+                        txn.pending.is_empty()
+                    },
+                    _ => false,
+                };
+                if all_in {
+                    self.decide(&pm.txid, true);
+                }
+            },
+            MessageType::ParticipantAck => self.ack_received(part, &pm.txid),
+            // the end of a restarted participant's requests
+            MessageType::ParticipantDecisionRequest if pm.txid == "done" => {},
+            MessageType::ParticipantDecisionRequest => {
+                match self.txns.get_mut(&pm.txid) {
+                    Some(txn) => txn.decision_requests.push((part.clone(), pm)),
+                    None => self.answer_decision_request(part, &pm),
+                }
+            },
+            _ => warn!("coordinator::Unexpected {:?} for {} from participant {}", pm.mtype, pm.txid, part),
+        }
+    }
+
+    ///
+    /// send_acceptors()
+    /// Paxos Commit: send a phase 1a or 2a message to every acceptor
+    ///
+    fn send_acceptors(&mut self, pm: &ProtocolMessage) {
+        for (id, val) in &self.acceptor_map {
+            let (tx, _) = val;
+            if tx.send(pm.clone()).is_err() {
+                debug!("coordinator::Acceptor {} is gone", id);
+            }
+            self.stats.messages += 1;
+        }
+    }
+
+    ///
+    /// start_phase1()
+    /// Paxos Commit: ask the acceptors to promise a new ballot for every
+    /// instance of txid that has no vote chosen yet. The ballot is above
+    /// the coordinator's epoch, so a recovered coordinator outbids the one
+    /// before it.
+    ///
+    fn start_phase1(&mut self, txid: &String) {
+        let mut prepares = Vec::new();
+        match self.txns.get_mut(txid) {
+            Some(txn) => {
+                for id in self.part_map.keys() {
+                    if txn.votes.contains_key(id) {
+                        continue;
+                    }
+                    let instance = txn.instances.entry(id.clone()).or_insert(Instance::default());
+                    instance.ballot = instance.ballot.max(self.epoch) + 1;
+                    instance.promises.clear();
+                    let mut prepare = txn.request.clone();
+                    prepare.mtype = MessageType::PaxosPrepare;
+                    prepare.senderid = id.clone();
+                    prepare.opid = instance.ballot;
+                    prepares.push(prepare);
+                }
+                txn.timer = Instant::now();
+            },
+            None => return,
+        }
+        for prepare in prepares {
+            self.send_acceptors(&prepare);
+        }
+    }
+
+    ///
+    /// route_acceptor()
+    /// Paxos Commit: hand a message from acceptor `acc` to the state machine
+    /// of the txid it is about. A vote (phase 2b) is chosen once a majority
+    /// of acceptors accepted it in the same ballot, and counts as the vote
+    /// of its participant; an abort chosen decides the txid right away.
+    /// Once a majority promised a ballot (phase 1b), the
+    /// vote accepted in the highest ballot is proposed in it, or an abort if
+    /// none was accepted.
+    ///
+    fn route_acceptor(&mut self, acc: &String, pm: ProtocolMessage) {
+        let quorum = self.acceptor_map.len() / 2 + 1;
+        let part = pm.senderid.clone();
+        let mut proposal: Option<ProtocolMessage> = None;
+        let all_in = match self.txns.get_mut(&pm.txid) {
+            Some(ref mut txn) if txn.state == CoordinatorState::ProposalSent && !txn.votes.contains_key(&part) => {
+                let instance = txn.instances.entry(part.clone()).or_insert(Instance::default());
+                match pm.mtype {
+                    MessageType::PaxosPromise => {
+                        if pm.opid == instance.ballot && !instance.promises.iter().any(|promise| promise.0 == *acc) {
+                            instance.promises.push((acc.clone(), pm.accepted));
+                            if instance.promises.len() == quorum {
+                                let mut msg = pm.clone();
+                                msg.mtype = instance.promises.iter()
+                                    .filter_map(|promise| promise.1)
+                                    .max_by_key(|accepted| accepted.0)
+                                    .map_or(MessageType::ParticipantVoteAbort, |accepted| accepted.1);
+                                msg.accepted = None;
+                                proposal = Some(msg);
+                            }
+                        }
+                        false
+                    },
+                    mtype if acceptor::is_vote(mtype) => {
+                        let acceptors = instance.accepted.entry((pm.opid, mtype)).or_insert(Vec::new());
+                        if !acceptors.contains(acc) {
+                            acceptors.push(acc.clone());
+                        }
+                        if acceptors.len() < quorum {
+                            false
+                        } else {
+                            let vote = match mtype {
+                                MessageType::ParticipantVoteCommit => RequestStatus::Committed,
+                                MessageType::ParticipantVoteReadOnly => {
+                                    txn.read_only.push(part.clone());
+                                    RequestStatus::Committed
+                                },
+                                _ => RequestStatus::Aborted,
+                            };
+                            txn.votes.insert(part.clone(), vote);
+                            vote == RequestStatus::Aborted || txn.votes.len() == self.part_map.len()
+                        }
+                    },
+                    _ => {
+                        warn!("coordinator::Unexpected {:?} for {} from acceptor {}", pm.mtype, pm.txid, acc);
+                        false
+                    },
+                }
+            },
+            _ => {
+                debug!("coordinator::Late {:?} for {} from acceptor {}", pm.mtype, pm.txid, acc);
+                false
+            },
+        };
+        if let Some(msg) = proposal {
+            self.send_acceptors(&msg);
+        }
+        if all_in {
+            self.votes_in(&pm.txid);
+        }
+    }
+
+    ///
+    /// poll_acceptors()
+    /// Paxos Commit: route everything the acceptors sent so far. Returns
+    /// true if there was anything.
+    ///
+    fn poll_acceptors(&mut self) -> bool {
+        let names: Vec<String> = self.acceptor_map.keys().cloned().collect();
+        let mut heard = false;
+        for id in names {
+            loop {
+                let res = {
+                    let (_, rx) = self.acceptor_map.get(&id).unwrap();
+                    rx.try_recv()
+                };
+                match res {
+                    Ok(pm) => {
+                        heard = true;
+                        self.route_acceptor(&id, pm);
+                        if self.crashed {
+                            return heard;
+                        }
+                    },
+                    Err(_) => break,
+                }
+            }
+        }
+        heard
+    }
+
+    ///
+    /// phase2()
+    /// Participants a txn's phase 2 goes to: all children but the
+    /// read-only ones
+    ///
+    fn phase2(&self, txn: &Txn) -> Vec<String> {
+        self.children.iter().filter(|id| !txn.read_only.contains(id)).cloned().collect()
+    }
+
+    ///
+    /// votes_in()
+    /// Phase 1 of txid is over: every participant voted, one voted abort, or
+    /// the missing votes timed out. In 3PC a commit goes through PreCommit
+    /// first, unless every participant only read.
+    ///
+    fn votes_in(&mut self, txid: &String) {
+        let (commit, phase2, mut pre, seq) = {
+            let txn = &self.txns[txid];
+            let commit = txn.votes.values().all(|v| *v == RequestStatus::Committed);
+            (commit, self.phase2(txn), txn.request.clone(), txn.seq)
+        };
+        if !commit || self.protocol != CommitProtocol::ThreePhase || phase2.is_empty() {
+            self.decide(txid, commit);
+            return;
+        }
+        //3PC: nobody commits before every participant knows that all voted commit
+        pre.mtype = MessageType::CoordinatorPreCommit;
+        self.log.append_async( pre.mtype, pre.txid.clone(), pre.senderid.clone(), pre.opid);
+        {
+            let txn = self.txns.get_mut(txid).unwrap();
+            txn.state = CoordinatorState::ReceivedVotesCommit;
+            txn.record = Some(pre.clone());
+        }
+        if self.send_phase2(&pre, &phase2, seq) {
+            return;
+        }
+        let txn = self.txns.get_mut(txid).unwrap();
+        txn.state = CoordinatorState::PreCommitSent;
+        txn.pending = phase2;
+        txn.timer = Instant::now();
+    }
+
+    ///
+    /// decide()
+    /// Phase 2 of txid: log the decision, tell the client and the
+    /// participants that did not vote read-only, and answer the decision
+    /// requests that waited for it. An explicit decision is unacked until
+    /// all of them have acknowledged it; a presumed one is forgotten. A
+    /// transaction recovered from the acceptors has no client to tell.
+    /// The coordinator may crash before the decision is logged, after it is
+    /// logged, or after it went to every participant.
+    ///
+    fn decide(&mut self, txid: &String, commit: bool) {
+        let (seq, request) = {
+            let txn = &self.txns[txid];
+            (txn.seq, txn.request.clone())
+        };
+        if self.crash_here(CrashPoint::BeforeDecisionLog, seq, &request) {
+            return;
+        }
+        let mut txn = self.txns.remove(txid).unwrap();
+        let phase2 = self.phase2(&txn);
+        let mut pm = txn.request.clone();
+        let client_mtype: MessageType;
+        if commit {
+            txn.state = CoordinatorState::ReceivedVotesCommit;
+            pm.mtype = MessageType::CoordinatorCommit;
+            client_mtype = MessageType::ClientResultCommit;
+        } else {
+            txn.state = CoordinatorState::ReceivedVotesAbort;
+            pm.mtype = MessageType::CoordinatorAbort;
+            client_mtype = MessageType::ClientResultAbort;
+        }
+        // write-ahead: the decision is durable before anyone hears of it,
+        // except a presumed abort, which needs no record at all, and the
+        // commit of a transaction that only read, which nobody waits on.
+        // In Paxos Commit the acceptors hold the votes it follows from, so
+        // the record need not be forced.
+        if !phase2.is_empty() && (commit || self.presumption != Presumption::Abort) {
+            if self.protocol == CommitProtocol::PaxosCommit {
+                self.log.append_lazy( pm.mtype, pm.txid.clone(), pm.senderid.clone(), pm.opid);
+            } else {
+                self.log.append_async( pm.mtype, pm.txid.clone(), pm.senderid.clone(), pm.opid);
+            }
+        }
+        self.decisions.insert(pm.txid.clone(), pm.clone());
+        if self.crash_here(CrashPoint::AfterDecisionLog, txn.seq, &pm) {
+            return;
+        }
+        if txn.seq > 0 {
+            self.stats.decide_micros += txn.started.elapsed().as_micros() as u64;
+            if commit {
+                self.successful_ops += 1;
+            } else {
+                self.failed_ops += 1;
+            }
+            let mut result = pm.clone();
+            result.mtype = client_mtype;
+            let gone = match self.client_map.get(&txn.request.cl_id.to_string()) {
+                Some(coor_cl_tx) => coor_cl_tx.send(result).is_err(),
+                None => true,
+            };
+            if gone {
+                debug!("coordinator::Client {} is gone", txn.request.cl_id);
+            }
+        }
+        //Decision to participants
+        if self.send_phase2(&pm, &phase2, txn.seq) || self.crash_here(CrashPoint::AfterBroadcast, txn.seq, &pm) {
+            return;
+        }
+        txn.state = CoordinatorState::SentGlobalDecision;
+        for (part, request) in txn.decision_requests {
+            self.answer_decision_request(&part, &request);
+        }
+        // a presumed decision needs no acks: the presumption answers for it
+        if self.presumption.explicit(pm.mtype) && !phase2.is_empty() {
+            self.unacked.insert(pm.txid.clone(), (pm, phase2, Instant::now()));
+        } else {
+            self.decisions.remove(&pm.txid);
+        }
+    }
+
+    ///
+    /// check_timeouts()
+    /// Phase 1 of a txid ends after VOTE_TIMEOUT, the missing votes taken
+    /// as aborts. A PreCommit still missing acks after the ack timeout is
+    /// committed all the same: the participants that did not ack failed.
+    /// In Paxos Commit the acceptors are asked for the missing votes
+    /// instead, again every VOTE_TIMEOUT; an instance with no vote accepted
+    /// gets an abort chosen.
+    ///
+    fn check_timeouts(&mut self) {
+        let mut expired: Vec<(u32, String, CoordinatorState)> = Vec::new();
+        let mut phase1: Vec<String> = Vec::new();
+        for (txid, txn) in self.txns.iter_mut() {
+            match txn.state {
+                CoordinatorState::ProposalSent if txn.timer.elapsed() >= VOTE_TIMEOUT && self.protocol == CommitProtocol::PaxosCommit => {
+                    phase1.push(txid.clone());
+                },
+                CoordinatorState::ProposalSent if txn.timer.elapsed() >= VOTE_TIMEOUT => {
+                    for id in &self.children {
+                        if !txn.votes.contains_key(id) {
+                            txn.votes.insert(id.clone(), RequestStatus::Aborted);
+                        }
+                    }
+                    expired.push((txn.seq, txid.clone(), txn.state));
+                },
+                CoordinatorState::PreCommitSent if txn.timer.elapsed() >= self.ack_timeout => {
+                    info!("coordinator::{} participants did not ack the PreCommit of {}", txn.pending.len(), txid);
+                    expired.push((txn.seq, txid.clone(), txn.state));
+                },
+                _ => {},
+            }
+        }
+        for txid in phase1 {
+            debug!("coordinator::Votes on {} missing after {:?}, asking the acceptors", txid, VOTE_TIMEOUT);
+            self.start_phase1(&txid);
+        }
+        expired.sort_by_key(|entry| entry.0);
+        for (_, txid, state) in expired {
+            match state {
+                CoordinatorState::ProposalSent => self.votes_in(&txid),
+                _ => self.decide(&txid, true),
+            }
+            if self.crashed {
+                return;
+            }
+        }
+    }
+
+    ///
+    /// finish_acks()
+    /// At the end of a run, give the unacked decisions a few more ack
+    /// timeouts, re-sending them in between, also to participants restarted
+    /// in the meantime. Whatever is still unacked is re-sent by the next
+    /// recovery.
+    ///
+    fn finish_acks(&mut self) {
+        for _ in 0..FINAL_ACK_ROUNDS {
+            if self.unacked.is_empty() {
+                return;
+            }
+            self.rejoin();
+            self.poll_acks(self.ack_timeout);
+            self.resend_unacked();
+        }
+        if !self.unacked.is_empty() {
+            info!("coordinator::{} decisions not acknowledged by every participant", self.unacked.len());
+        }
+    }
+
+    ///
+    /// spawn_intake()
+    /// Moves the client receivers into an IpcReceiverSet served by a thread
+    /// of its own, which forwards every request as soon as it arrives. A
+    /// client is done once it sends a "done" request, hangs up or sends a
+    /// request that can not be decoded; the returned channel disconnects
+    /// when all clients are done or the set can not be selected on.
+    ///
+    fn spawn_intake(&mut self) -> mpsc::Receiver<ProtocolMessage> {
+        let mut set = IpcReceiverSet::new().unwrap();
+        let mut clients = HashMap::new();
+        for (name, rx) in self.client_rx.drain(..) {
+            let id = set.add(rx).unwrap();
+            clients.insert(id, name);
+        }
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            while !clients.is_empty() {
+                let events = match set.select() {
+                    Ok(events) => events,
+                    Err(e) => {
+                        error!("coordinator::client intake failed: {:?}", e);
+                        return;
+                    },
+                };
+                for event in events {
+                    match event {
+                        IpcSelectionResult::MessageReceived(id, msg) => {
+                            let request: ProtocolMessage = match msg.to() {
+                                Ok(request) => request,
+                                Err(e) => {
+                                    // nothing more is taken from a client that sends garbage
+                                    if let Some(name) = clients.remove(&id) {
+                                        error!("coordinator::bad request from client {}: {:?}", name, e);
+                                    }
+                                    continue;
+                                },
+                            };
+                            if request.txid == "done" {
+                                if let Some(name) = clients.remove(&id) {
+                                    trace!("coordinator::client {} is done", name);
+                                }
+                            } else if tx.send(request).is_err() {
+                                return;
+                            }
+                        },
+                        IpcSelectionResult::ChannelClosed(id) => {
+                            clients.remove(&id);
+                        },
+                    }
+                }
+            }
+        });
+        rx
+    }
+
     ///
     /// protocol()
-    /// Implements the coordinator side of the 2PC protocol
+    /// Implements the coordinator side of the 2PC / 3PC / Paxos Commit protocol
     /// HINT: If the simulation ends early, don't keep handling requests!
     /// HINT: Wait for some kind of exit signal before returning from the protocol!
     ///
     pub fn protocol(&mut self) {
 
-        // TODO
-		//receive request from client
-		let mut request: ProtocolMessage;
-		let mut pm_queue: Vec<ProtocolMessage> = Vec::new();
-		let mut counter =0;
-		loop{
-			for (id, val) in &self.client_map {
-					let (_, rx)= val;
-					match rx.recv() {
-					Ok(res) => { 
-						//println!("requests{}", res.clone().txid);
-							request = res.clone();
-							pm_queue.push(request);				
+		// finish what a previous run decided before taking new work
+		self.resend_decisions();
+		if self.recovering {
+			self.resolve_in_doubt();
+		}
+
+		//requests come in from the intake thread as clients send them
+		let intake = self.spawn_intake();
+		let mut intake_open = true;
+		let mut pm_queue: VecDeque<ProtocolMessage> = VecDeque::new();
+		//run up to max_in_flight transactions at once; each one is a state
+		//machine driven by the votes and acks routed to it by txid
+		let mut names: Vec<String> = self.part_map.keys().cloned().collect();
+		names.sort();
+		let mut gone: Vec<String> = Vec::new();
+		loop {
+			let mut idle = true;
+			for name in self.rejoin() {
+				gone.retain(|id| *id != name);
+			}
+			while intake_open {
+				match intake.try_recv() {
+					Ok(request) => {
+						idle = false;
+						pm_queue.push_back(request);
 					},
-					Err(_) => {
-						//wait
-					}
+					Err(mpsc::TryRecvError::Empty) => break,
+					Err(mpsc::TryRecvError::Disconnected) => intake_open = false,
 				}
 			}
-			counter+=1;
-			if counter==self.num_requests {
-				break;
+			while self.txns.len() < self.max_in_flight && !pm_queue.is_empty() {
+				let request = pm_queue.pop_front().unwrap();
+				self.start_txn(request);
 			}
-		}
-		self.state = CoordinatorState::ReceivedRequest;
-		//send request to participants
-
-		while pm_queue.len() !=0{
-			self.state = CoordinatorState::ReceivedRequest;
-			let mut commit: bool = true;
-			let mut msg = pm_queue[0].clone();	
-			msg.mtype = MessageType::CoordinatorPropose;
-			//send request to participant
-			for (id, val) in &self.part_map{							
-				let (tx,_)= val;
-				tx.send(msg.clone()).unwrap();
+			// every client is done and everything it asked for is decided
+			if self.txns.is_empty() && pm_queue.is_empty() && !intake_open {
+				break;
 			}
-			self.state = CoordinatorState::ProposalSent;
-			
-			//recieve messages votes
-			let mut votingblock: HashMap<u32, RequestStatus> = HashMap::new();
-			let timer = Instant::now();
-			loop{				
-				for (id, val) in &self.part_map{
-					let part_id: u32 = id.parse().unwrap();				
-					if votingblock.contains_key(&part_id) == true{
-						continue;
-					}
-					let (_, rx) = val;
-					match rx.try_recv() {
-						Ok(res) => {
-							let mtype :MessageType =res.mtype;
-							match (mtype) {
-								(MessageType::ParticipantVoteCommit) => votingblock.insert(part_id,RequestStatus::Committed),
-								(MessageType::ParticipantVoteAbort) => votingblock.insert(part_id,RequestStatus::Aborted),
-								_ => votingblock.insert(part_id,RequestStatus::Aborted),
-							};
-						},
-						Err(TryRecvError::Empty) =>{
-							let elapsed_time = timer.elapsed();
-								if elapsed_time >=  Duration::from_secs(1){
-									break;
-								}
-							//no op;
-						},
-						Err(_) =>{// an error occured
-							votingblock.insert(part_id,RequestStatus::Aborted);
-						},
-					};	
+			for id in &names {
+				if gone.contains(id) {
+					continue;
 				}
-							
-				//timeOut
-				let elapsed_time = timer.elapsed();
-				if elapsed_time >=  Duration::from_secs(1){
-					for (id, val) in &self.part_map{
-						let part_id: u32 = id.parse().unwrap();
-						if votingblock.contains_key(&part_id) == false{
-							votingblock.insert(part_id,RequestStatus::Aborted);
-						}				
-					}
-					break;
+				let res = {
+					let (_, rx) = self.part_map.get(id).unwrap();
+					rx.try_recv()
+				};
+				match res {
+					Ok(pm) => {
+						idle = false;
+						self.route(id, pm);
+					},
+					Err(TryRecvError::Empty) => {},
+					Err(_) => {
+						// its votes and acks time out
+						gone.push(id.clone());
+					},
 				}
-				if votingblock.len() == self.part_map.len(){
-					break;
-				} 
-			}
-		
-			//voting alalysis
-			for (id,val) in votingblock{
-				if val != RequestStatus::Committed {
-					commit = false;
-					break;
+				if self.crashed {
+					return;
 				}
 			}
-			//decision
-			let id = pm_queue[0].cl_id.to_string();
-			let mut pm =pm_queue[0].clone();
-			let mut coor_mtype :  MessageType;
-			//message to clients
-			if commit{
-				self.state = CoordinatorState::ReceivedVotesCommit;
-				coor_mtype= MessageType::CoordinatorCommit;
-				pm.mtype = MessageType::ClientResultCommit; 
-				self.successful_ops+=1;
-			}
-			else{
-				self.state = CoordinatorState::ReceivedVotesAbort;
-				coor_mtype= MessageType::CoordinatorAbort;
-				pm.mtype = MessageType::ClientResultAbort; 
-				self.failed_ops+=1;
+			if self.poll_acceptors() {
+				idle = false;
 			}
-			let (coor_cl_tx, _) = self.client_map.get(&id).unwrap();
-			coor_cl_tx.send(pm.clone()).unwrap();
-			pm.mtype = coor_mtype;
-			self.log.append( pm.mtype.clone(), pm.txid.clone(), pm.senderid.clone(), pm.opid.clone());
-			//Decision to participants
-			for (id, val) in &self.part_map{						
-				let (tx,_)= val;
-				tx.send(pm.clone()).unwrap();
+			if self.crashed {
+				return;
 			}
-			self.state = CoordinatorState::SentGlobalDecision;
-			//request dequeued
-			pm_queue.remove(0);
-			//Gracefull exit
-			
-			if !self.running.load(Ordering::SeqCst){
-				//participants
-				for (id, val) in &self.part_map{						
-					let (tx,_)= val;
-					let pm = ProtocolMessage::generate( MessageType::CoordinatorExit,"done".to_string(),"done".to_string(),0,0);//t: MessageType, tid: String, sid: String, oid: u32,cid: u32
-					tx.send(pm.clone()).unwrap();
-				}
-				for (id, val) in &self.client_map{						
-					let (tx,_)= val;
-					let pm = ProtocolMessage::generate( MessageType::CoordinatorExit,"done".to_string(),"done".to_string(),0,0);//t: MessageType, tid: String, sid: String, oid: u32,cid: u32
-					tx.send(pm.clone()).unwrap();
-				}
-				self.report_status();
+			self.check_timeouts();
+			if self.crashed {
 				return;
 			}
-						
+			self.resend_unacked();
+			// the checkpoint keeps the records of what is in flight, and of every
+			// decision not acknowledged by all participants yet, from being
+			// truncated: without them a participant in doubt would be answered
+			// with the presumption after a crash
+			if self.log.checkpoint_due() {
+				let mut in_flight: Vec<ProtocolMessage> = self.decisions.values().cloned().collect();
+				in_flight.extend(self.txns.values().filter_map(|txn| txn.record.clone()));
+				self.log.checkpoint(&in_flight).unwrap();
+			}
+			//Gracefull exit: finish what is in flight, start nothing new
+			if !self.running.load(Ordering::SeqCst) {
+				pm_queue.clear();
+				intake_open = false;
+			}
+			// what depends on the records of a round goes out once they are
+			// durable; a busy round moves on without waiting for that
+			self.flush(idle);
+			if idle {
+				thread::sleep(Duration::from_millis(1));
+			}
 		}
 
-
+		self.finish_acks();
         self.report_status();
 		//exit now
 		//tell participants it time to shut down
-		println!("ending coor");
 		for (id, val) in &self.part_map{						
 				let (tx,_)= val;
 				let pm = ProtocolMessage::generate( MessageType::CoordinatorExit,"done".to_string(),"done".to_string(),0,0);//t: MessageType, tid: String, sid: String, oid: u32,cid: u32
-				tx.send(pm.clone()).unwrap();
+				if tx.send(pm.clone()).is_err() {
+					debug!("coordinator::Participant {} is gone", id);
+				}
 		}
-		for (id, val) in &self.client_map{						
+		for (id, val) in &self.acceptor_map{
 			let (tx,_)= val;
+			let pm = ProtocolMessage::generate( MessageType::CoordinatorExit,"done".to_string(),"done".to_string(),0,0);
+			if tx.send(pm.clone()).is_err() {
+				debug!("coordinator::Acceptor {} is gone", id);
+			}
+		}
+		for (id, tx) in &self.client_map{						
 			let pm = ProtocolMessage::generate( MessageType::CoordinatorExit,"done".to_string(),"done".to_string(),0,0);//t: MessageType, tid: String, sid: String, oid: u32,cid: u32
-			tx.send(pm.clone()).unwrap();
+			if tx.send(pm.clone()).is_err() {
+				debug!("coordinator::Client {} is gone", id);
+			}
 		}
+		self.flush(true);
 		
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    use coordinator::ipc_channel::ipc;
+    use coordinator::ipc_channel::ipc::IpcSender;
+    use oplog::DurabilityPolicy;
+    use oplog::LogConfig;
+    use oplog::LogFormat;
+    use transport::Latency;
+    use transport::LinkFaults;
+    use transport::Transport;
+
+    use message::MessageType::CoordinatorAbort as Abort;
+    use message::MessageType::CoordinatorCommit as Commit;
+
+    // A directory of its own for every test, tests run in parallel
+    fn temp_dir(name: &str) -> String {
+        let dir = env::temp_dir().join(format!("coordinator_test_{}_{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.to_string_lossy().into_owned()
+    }
+
+    fn config(protocol: CommitProtocol, presumption: Presumption) -> CoordinatorConfig {
+        CoordinatorConfig {
+            log_config: LogConfig {
+                durability: DurabilityPolicy::Flush,
+                format: LogFormat::Json,
+                segment_size: 0,
+                truncate: false,
+            },
+            protocol,
+            presumption,
+            ack_timeout: Duration::from_secs(60),
+            max_in_flight: 8,
+            crash: None,
+        }
+    }
+
+    // A participant as the coordinator sees it: what the coordinator sends
+    // it comes out of rx, and what it sends the coordinator goes into tx
+    struct Peer {
+        rx: Receiver<ProtocolMessage>,
+        tx: IpcSender<ProtocolMessage>,
+    }
+
+    impl Peer {
+        // Everything the coordinator sent so far, as (txid, type)
+        fn received(&self) -> Vec<(String, MessageType)> {
+            let mut received = Vec::new();
+            while let Ok(pm) = self.rx.try_recv() {
+                received.push((pm.txid, pm.mtype));
+            }
+            received.sort_by_key(|entry| format!("{:?}", entry));
+            received
+        }
+    }
+
+    // Joins participants "0".."n-1" as children, over links with no faults
+    fn join(coordinator: &mut Coordinator, dir: &str, n: u32) -> Vec<Peer> {
+        let faults = LinkFaults { latency: Latency::None, links: Vec::new(), reorder: 0, duplicate: 0.0, partitions: Vec::new() };
+        let transport = Transport::new("coordinator".to_string(), dir, false, 1.0, faults, 0);
+        (0..n).map(|num| {
+            let (coor_part_tx, coor_part_rx) = ipc::channel().unwrap();
+            let (part_coor_tx, part_coor_rx) = ipc::channel().unwrap();
+            let tx = Transport::sender(&transport, coor_part_tx, format!("participant_{}", num));
+            coordinator.participant_join(&num.to_string(), tx, part_coor_rx, true);
+            Peer { rx: coor_part_rx, tx: part_coor_tx }
+        }).collect()
+    }
+
+    fn message(mtype: MessageType, txid: &str) -> ProtocolMessage {
+        ProtocolMessage::generate(mtype, txid.to_string(), "0".to_string(), 1, 0)
+    }
+
+    #[test]
+    fn recovery_resends_unacked_decisions() {
+        let dir = temp_dir("recovery_resends");
+        let path = format!("{}//coordinator.log", dir);
+        let config = config(CommitProtocol::TwoPhase, Presumption::Nothing);
+        {
+            let mut log = oplog::OpLog::new(path.clone(), config.log_config);
+            for (mtype, txid) in &[(Commit, "tx_1"), (Abort, "tx_2"), (Commit, "tx_3"), (MessageType::CoordinatorCompleted, "tx_3")] {
+                log.append(*mtype, txid.to_string(), "0".to_string(), 1).unwrap();
+            }
+        }
+
+        let running = Arc::new(AtomicBool::new(true));
+        let mut coordinator = Coordinator::recover(path, &running, config);
+        assert!(coordinator.epoch() > 0);
+        let mut recovered: Vec<&String> = coordinator.decisions.keys().collect();
+        recovered.sort();
+        assert_eq!(recovered, vec!["tx_1", "tx_2"]);
+
+        let peers = join(&mut coordinator, &dir, 2);
+        coordinator.resend_decisions();
+        for peer in &peers {
+            assert_eq!(peer.received(), vec![("tx_1".to_string(), Commit), ("tx_2".to_string(), Abort)]);
+        }
+        // tx_1 is completed once both acked it
+        coordinator.route(&"0".to_string(), message(MessageType::ParticipantAck, "tx_1"));
+        assert!(coordinator.decisions.contains_key("tx_1"));
+        coordinator.route(&"1".to_string(), message(MessageType::ParticipantAck, "tx_1"));
+        assert!(!coordinator.decisions.contains_key("tx_1"));
+        assert_eq!(coordinator.log.last_state_for_txid("tx_1"), Some(MessageType::CoordinatorCompleted));
+        assert!(coordinator.unacked.contains_key("tx_2"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn recovery_aborts_what_it_never_decided() {
+        let dir = temp_dir("recovery_aborts");
+        let path = format!("{}//coordinator.log", dir);
+        let config = config(CommitProtocol::TwoPhase, Presumption::Nothing);
+        oplog::OpLog::new(path.clone(), config.log_config).append(Commit, "tx_1".to_string(), "0".to_string(), 1).unwrap();
+
+        let running = Arc::new(AtomicBool::new(true));
+        let mut coordinator = Coordinator::recover(path, &running, config);
+        let peers = join(&mut coordinator, &dir, 1);
+        peers[0].tx.send(message(MessageType::ParticipantDecisionRequest, "tx_1")).unwrap();
+        peers[0].tx.send(message(MessageType::ParticipantDecisionRequest, "tx_2")).unwrap();
+        peers[0].tx.send(message(MessageType::ParticipantDecisionRequest, "done")).unwrap();
+        coordinator.resolve_in_doubt();
+        assert_eq!(peers[0].received(), vec![("tx_1".to_string(), Commit), ("tx_2".to_string(), Abort)]);
+        // the abort is on record, so the answer can not change
+        assert_eq!(coordinator.log.last_state_for_txid("tx_2"), Some(Abort));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn presumed_decisions_are_not_acked() {
+        let dir = temp_dir("presumed");
+        let running = Arc::new(AtomicBool::new(true));
+
+        // presumed abort: an abort is neither logged nor waits for acks
+        let path = format!("{}//coordinator_pa.log", dir);
+        let mut coordinator = Coordinator::new(path, &running, config(CommitProtocol::TwoPhase, Presumption::Abort));
+        let peers = join(&mut coordinator, &dir, 2);
+        coordinator.start_txn(message(MessageType::ClientRequest, "tx_1"));
+        coordinator.route(&"0".to_string(), message(MessageType::ParticipantVoteAbort, "tx_1"));
+        assert_eq!(peers[1].received(), vec![("tx_1".to_string(), Abort), ("tx_1".to_string(), MessageType::CoordinatorPropose)]);
+        assert_eq!(coordinator.log.last_state_for_txid("tx_1"), None);
+        assert!(coordinator.unacked.is_empty());
+
+        // presumed commit: the txid is on record as collecting votes before
+        // it is proposed, and its commit waits for no acks
+        let path = format!("{}//coordinator_pc.log", dir);
+        let mut coordinator = Coordinator::new(path, &running, config(CommitProtocol::TwoPhase, Presumption::Commit));
+        let peers = join(&mut coordinator, &dir, 2);
+        coordinator.start_txn(message(MessageType::ClientRequest, "tx_1"));
+        assert_eq!(coordinator.log.last_state_for_txid("tx_1"), Some(MessageType::CoordinatorCollecting));
+        coordinator.route(&"0".to_string(), message(MessageType::ParticipantVoteCommit, "tx_1"));
+        coordinator.route(&"1".to_string(), message(MessageType::ParticipantVoteCommit, "tx_1"));
+        assert_eq!(peers[1].received(), vec![("tx_1".to_string(), Commit), ("tx_1".to_string(), MessageType::CoordinatorPropose)]);
+        assert_eq!(coordinator.log.last_state_for_txid("tx_1"), Some(Commit));
+        assert!(coordinator.unacked.is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn read_only_participants_skip_phase_two() {
+        let dir = temp_dir("read_only");
+        let running = Arc::new(AtomicBool::new(true));
+        let mut coordinator = Coordinator::new(format!("{}//coordinator.log", dir), &running, config(CommitProtocol::TwoPhase, Presumption::Nothing));
+        let peers = join(&mut coordinator, &dir, 2);
+        let propose = ("tx_1".to_string(), MessageType::CoordinatorPropose);
+
+        coordinator.start_txn(message(MessageType::ClientRequest, "tx_1"));
+        coordinator.route(&"0".to_string(), message(MessageType::ParticipantVoteReadOnly, "tx_1"));
+        coordinator.route(&"1".to_string(), message(MessageType::ParticipantVoteCommit, "tx_1"));
+        assert_eq!(peers[0].received(), vec![propose.clone()]);
+        assert_eq!(peers[1].received(), vec![("tx_1".to_string(), Commit), propose]);
+        assert_eq!(coordinator.unacked["tx_1"].1, vec!["1".to_string()]);
+
+        // a transaction that only read is over after one phase, off the record
+        coordinator.start_txn(message(MessageType::ClientRequest, "tx_2"));
+        coordinator.route(&"0".to_string(), message(MessageType::ParticipantVoteReadOnly, "tx_2"));
+        coordinator.route(&"1".to_string(), message(MessageType::ParticipantVoteReadOnly, "tx_2"));
+        for peer in &peers {
+            assert_eq!(peer.received(), vec![("tx_2".to_string(), MessageType::CoordinatorPropose)]);
+        }
+        assert_eq!(coordinator.log.last_state_for_txid("tx_2"), None);
+        assert!(!coordinator.unacked.contains_key("tx_2"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn decision_resent_until_every_participant_acked() {
+        let dir = temp_dir("resend");
+        let running = Arc::new(AtomicBool::new(true));
+        let mut config = config(CommitProtocol::TwoPhase, Presumption::Nothing);
+        config.ack_timeout = Duration::from_secs(0);
+        let mut coordinator = Coordinator::new(format!("{}//coordinator.log", dir), &running, config);
+        let peers = join(&mut coordinator, &dir, 3);
+        coordinator.start_txn(message(MessageType::ClientRequest, "tx_1"));
+        for part in &["0", "1", "2"] {
+            coordinator.route(&part.to_string(), message(MessageType::ParticipantVoteCommit, "tx_1"));
+        }
+        coordinator.route(&"0".to_string(), message(MessageType::ParticipantAck, "tx_1"));
+        for peer in &peers {
+            peer.received();
+        }
+
+        coordinator.resend_unacked();
+        assert!(peers[0].received().is_empty());
+        for peer in &peers[1..] {
+            assert_eq!(peer.received(), vec![("tx_1".to_string(), Commit)]);
+        }
+        coordinator.route(&"1".to_string(), message(MessageType::ParticipantAck, "tx_1"));
+        coordinator.route(&"2".to_string(), message(MessageType::ParticipantAck, "tx_1"));
+        assert!(coordinator.unacked.is_empty());
+        assert_eq!(coordinator.log.last_state_for_txid("tx_1"), Some(MessageType::CoordinatorCompleted));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn transactions_in_flight_decided_by_their_own_votes() {
+        let dir = temp_dir("in_flight");
+        let running = Arc::new(AtomicBool::new(true));
+        let mut coordinator = Coordinator::new(format!("{}//coordinator.log", dir), &running, config(CommitProtocol::TwoPhase, Presumption::Nothing));
+        let _peers = join(&mut coordinator, &dir, 2);
+        for txid in &["tx_1", "tx_2", "tx_3"] {
+            coordinator.start_txn(message(MessageType::ClientRequest, txid));
+        }
+        assert_eq!(coordinator.txns.len(), 3);
+
+        coordinator.route(&"1".to_string(), message(MessageType::ParticipantVoteCommit, "tx_3"));
+        coordinator.route(&"0".to_string(), message(MessageType::ParticipantVoteCommit, "tx_1"));
+        coordinator.route(&"1".to_string(), message(MessageType::ParticipantVoteAbort, "tx_2"));
+        assert_eq!(coordinator.log.last_state_for_txid("tx_2"), Some(Abort));
+        coordinator.route(&"0".to_string(), message(MessageType::ParticipantVoteCommit, "tx_3"));
+        assert_eq!(coordinator.log.last_state_for_txid("tx_3"), Some(Commit));
+        // tx_1 still waits for the vote of participant 1
+        assert_eq!(coordinator.txns.keys().collect::<Vec<_>>(), vec!["tx_1"]);
+        assert_eq!(coordinator.log.last_state_for_txid("tx_1"), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn intake_forwards_requests_until_every_client_is_done() {
+        let dir = temp_dir("intake");
+        let running = Arc::new(AtomicBool::new(true));
+        let mut coordinator = Coordinator::new(format!("{}//coordinator.log", dir), &running, config(CommitProtocol::TwoPhase, Presumption::Nothing));
+        let faults = LinkFaults { latency: Latency::None, links: Vec::new(), reorder: 0, duplicate: 0.0, partitions: Vec::new() };
+        let transport = Transport::new("coordinator".to_string(), &dir, false, 1.0, faults, 0);
+        let clients: Vec<IpcSender<ProtocolMessage>> = (0..2).map(|num| {
+            let (coor_cl_tx, _) = ipc::channel().unwrap();
+            let (cl_coor_tx, cl_coor_rx) = ipc::channel().unwrap();
+            coordinator.client_join(&num.to_string(), Transport::sender(&transport, coor_cl_tx, format!("client_{}", num)), cl_coor_rx);
+            cl_coor_tx
+        }).collect();
+        let intake = coordinator.spawn_intake();
+
+        for (num, client) in clients.iter().enumerate() {
+            for op in 1..4 {
+                client.send(message(MessageType::ClientRequest, &format!("client{}_op_{}", num, op))).unwrap();
+            }
+            client.send(message(MessageType::ClientRequest, "done")).unwrap();
+        }
+        let mut requests: Vec<String> = intake.iter().map(|request| request.txid).collect();
+        requests.sort();
+        assert_eq!(requests, vec!["client0_op_1", "client0_op_2", "client0_op_3", "client1_op_1", "client1_op_2", "client1_op_3"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn first_abort_vote_decides_and_late_votes_are_dropped() {
+        let dir = temp_dir("early_abort");
+        let running = Arc::new(AtomicBool::new(true));
+        let mut coordinator = Coordinator::new(format!("{}//coordinator.log", dir), &running, config(CommitProtocol::TwoPhase, Presumption::Nothing));
+        let peers = join(&mut coordinator, &dir, 3);
+        coordinator.start_txn(message(MessageType::ClientRequest, "tx_1"));
+        coordinator.route(&"0".to_string(), message(MessageType::ParticipantVoteCommit, "tx_1"));
+        coordinator.route(&"1".to_string(), message(MessageType::ParticipantVoteAbort, "tx_1"));
+        for peer in &peers {
+            assert_eq!(peer.received(), vec![("tx_1".to_string(), Abort), ("tx_1".to_string(), MessageType::CoordinatorPropose)]);
+        }
+        assert_eq!(coordinator.log.last_state_for_txid("tx_1"), Some(Abort));
+
+        // the vote participant 2 sends late counts for nothing
+        coordinator.start_txn(message(MessageType::ClientRequest, "tx_2"));
+        coordinator.route(&"2".to_string(), message(MessageType::ParticipantVoteCommit, "tx_1"));
+        assert!(coordinator.txns["tx_2"].votes.is_empty());
+        assert_eq!(coordinator.log.last_state_for_txid("tx_1"), Some(Abort));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,267 @@
+//!
+//! crash.rs
+//! Crash injection at named points of the protocols. A CrashSpec names the
+//! process, the point and the transaction, e.g.
+//! "participant_1:after_vote_log:tx=5" crashes participant_1 after it logged
+//! its vote on the fifth transaction it votes on, before sending the vote.
+//! A participant that crashes leaves a note next to its log, so the checker
+//! knows why it left transactions blocked.
+//!
+use std::fs;
+use std::process;
+
+/// Exit code of a participant that crashed at its crash point; run mode
+/// restarts a participant that exits with it if asked to
+pub const CRASH_EXIT_CODE: i32 = 17;
+
+///
+/// CrashPoint
+/// Where in a transaction a process crashes
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CrashPoint {
+    AfterVoteLog,       // participant: vote logged, not sent
+    AfterVoteSend,      // participant: vote sent
+    BeforeDecisionLog,  // coordinator: votes in, nothing logged
+    AfterDecisionLog,   // coordinator: decision logged, nobody told; participant: decision logged, not acked
+    DuringBroadcast,    // coordinator: phase 2 (PreCommit or decision) sent to one participant
+    AfterBroadcast,     // coordinator: decision sent to every participant, acks not waited for
+}
+
+impl CrashPoint {
+
+    ///
+    /// from_name(name)
+    /// Parse the name of a crash point
+    ///
+    pub fn from_name(name: &str) -> Option<CrashPoint> {
+        match name {
+            "after_vote_log" => Some(CrashPoint::AfterVoteLog),
+            "after_vote_send" => Some(CrashPoint::AfterVoteSend),
+            "before_decision_log" => Some(CrashPoint::BeforeDecisionLog),
+            "after_decision_log" => Some(CrashPoint::AfterDecisionLog),
+            "during_broadcast" => Some(CrashPoint::DuringBroadcast),
+            "after_broadcast" => Some(CrashPoint::AfterBroadcast),
+            _ => None,
+        }
+    }
+
+    ///
+    /// name()
+    /// The inverse of from_name()
+    ///
+    pub fn name(&self) -> &'static str {
+        match *self {
+            CrashPoint::AfterVoteLog => "after_vote_log",
+            CrashPoint::AfterVoteSend => "after_vote_send",
+            CrashPoint::BeforeDecisionLog => "before_decision_log",
+            CrashPoint::AfterDecisionLog => "after_decision_log",
+            CrashPoint::DuringBroadcast => "during_broadcast",
+            CrashPoint::AfterBroadcast => "after_broadcast",
+        }
+    }
+
+    ///
+    /// of_participant()
+    /// True for the points a participant can crash at; the others are the
+    /// coordinator's, except after_decision_log, which both have
+    ///
+    fn of_participant(&self) -> bool {
+        matches!(*self, CrashPoint::AfterVoteLog | CrashPoint::AfterVoteSend | CrashPoint::AfterDecisionLog)
+    }
+}
+
+///
+/// CrashSpec
+/// A process to crash, where, and in which of its transactions (counted
+/// from 1 in the order the coordinator starts them, or the participant
+/// votes on them)
+///
+#[derive(Clone, Debug, PartialEq)]
+pub struct CrashSpec {
+    pub process: String,
+    pub point: CrashPoint,
+    pub tx: u32,
+}
+
+impl CrashSpec {
+
+    ///
+    /// from_name(spec)
+    /// Parse "PROCESS:POINT:tx=N", where PROCESS is "coordinator" or
+    /// "participant_N". Returns None if the spec is bad, or the process
+    /// has no such point.
+    ///
+    pub fn from_name(spec: &str) -> Option<CrashSpec> {
+        let parts: Vec<&str> = spec.split(':').collect();
+        if parts.len() != 3 || !parts[2].starts_with("tx=") {
+            return None;
+        }
+        let point = CrashPoint::from_name(parts[1])?;
+        let tx = parts[2]["tx=".len()..].parse::<u32>().ok()?;
+        let fits = match parts[0] {
+            "coordinator" => !point.of_participant() || point == CrashPoint::AfterDecisionLog,
+            p if p.starts_with("participant_") => point.of_participant() && p["participant_".len()..].parse::<u32>().is_ok(),
+            _ => false,
+        };
+        if !fits || tx == 0 {
+            return None;
+        }
+        Some(CrashSpec {
+            process: parts[0].to_string(),
+            point,
+            tx,
+        })
+    }
+
+    ///
+    /// name()
+    /// The inverse of from_name()
+    ///
+    pub fn name(&self) -> String {
+        format!("{}:{}:tx={}", self.process, self.point.name(), self.tx)
+    }
+
+    ///
+    /// fires(point, tx)
+    /// True if the process crashes at point in its transaction tx
+    ///
+    pub fn fires(&self, point: CrashPoint, tx: u32) -> bool {
+        self.point == point && self.tx == tx
+    }
+}
+
+///
+/// path_for(log_path)
+/// Where the process with the log at log_path notes its crash
+///
+pub fn path_for(log_path: &str) -> String {
+    match log_path.ends_with(".log") {
+        true => format!("{}.crash", &log_path[..log_path.len() - 4]),
+        false => format!("{}.crash", log_path),
+    }
+}
+
+///
+/// crash(spec, txid, log_path)
+/// Kill this process on the spot, as a crash would: nothing is flushed or
+/// sent on its way out. The spec is noted next to the log at log_path.
+///
+pub fn crash(spec: &CrashSpec, txid: &str, log_path: &str) -> ! {
+    warn!("{}::Crashing {} of {}", spec.process, spec.point.name(), txid);
+    if let Err(e) = fs::write(path_for(log_path), spec.name()) {
+        error!("Failed to note crash \"{}\": {:?}", path_for(log_path), e);
+    }
+    process::exit(CRASH_EXIT_CODE);
+}
+
+///
+/// read(log_path)
+/// The spec the process with the log at log_path crashed at, None if it
+/// did not crash
+///
+pub fn read(log_path: &str) -> Option<String> {
+    fs::read_to_string(path_for(log_path)).ok()
+}
+
+///
+/// clear(log_path)
+/// Forget the crash of a previous run, on a fresh start
+///
+pub fn clear(log_path: &str) {
+    if fs::metadata(path_for(log_path)).is_ok() {
+        fs::remove_file(path_for(log_path)).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POINTS: [CrashPoint; 6] = [
+        CrashPoint::AfterVoteLog,
+        CrashPoint::AfterVoteSend,
+        CrashPoint::BeforeDecisionLog,
+        CrashPoint::AfterDecisionLog,
+        CrashPoint::DuringBroadcast,
+        CrashPoint::AfterBroadcast,
+    ];
+
+    #[test]
+    fn point_names() {
+        for point in POINTS.iter() {
+            assert_eq!(CrashPoint::from_name(point.name()), Some(*point));
+        }
+        assert_eq!(CrashPoint::from_name("after_vote"), None);
+        assert_eq!(CrashPoint::from_name(""), None);
+    }
+
+    #[test]
+    fn parse_spec() {
+        let spec = CrashSpec::from_name("participant_1:after_vote_log:tx=5").unwrap();
+        assert_eq!(spec.process, "participant_1");
+        assert_eq!(spec.point, CrashPoint::AfterVoteLog);
+        assert_eq!(spec.tx, 5);
+        assert!(spec.fires(CrashPoint::AfterVoteLog, 5));
+        assert!(!spec.fires(CrashPoint::AfterVoteLog, 4));
+        assert!(!spec.fires(CrashPoint::AfterVoteSend, 5));
+
+        let spec = CrashSpec::from_name("coordinator:during_broadcast:tx=12").unwrap();
+        assert_eq!(spec.process, "coordinator");
+        assert_eq!(spec.point, CrashPoint::DuringBroadcast);
+        assert_eq!(spec.tx, 12);
+    }
+
+    #[test]
+    fn spec_names_round_trip() {
+        for process in &["coordinator", "participant_0", "participant_12"] {
+            for point in POINTS.iter() {
+                let name = format!("{}:{}:tx=3", process, point.name());
+                if let Some(spec) = CrashSpec::from_name(&name) {
+                    assert_eq!(spec.name(), name);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn points_of_each_process() {
+        for point in POINTS.iter() {
+            let coordinator = CrashSpec::from_name(&format!("coordinator:{}:tx=1", point.name())).is_some();
+            let participant = CrashSpec::from_name(&format!("participant_0:{}:tx=1", point.name())).is_some();
+            match *point {
+                CrashPoint::AfterDecisionLog => assert!(coordinator && participant),
+                CrashPoint::AfterVoteLog | CrashPoint::AfterVoteSend => assert!(!coordinator && participant),
+                _ => assert!(coordinator && !participant),
+            }
+        }
+    }
+
+    #[test]
+    fn bad_specs() {
+        for spec in &[
+            "",
+            "participant_1",
+            "participant_1:after_vote_log",
+            "participant_1:after_vote_log:5",
+            "participant_1:after_vote_log:tx=",
+            "participant_1:after_vote_log:tx=0",
+            "participant_1:after_vote_log:tx=-1",
+            "participant_1:after_vote_log:tx=five",
+            "participant_1:after_vote_log:tx=5:again",
+            "participant_1:before_vote_log:tx=5",
+            "participant_:after_vote_log:tx=5",
+            "participant_x:after_vote_log:tx=5",
+            "client_0:after_vote_log:tx=5",
+            "acceptor_0:after_decision_log:tx=5",
+        ] {
+            assert_eq!(CrashSpec::from_name(spec), None, "{}", spec);
+        }
+    }
+
+    #[test]
+    fn crash_note_path() {
+        assert_eq!(path_for("/tmp/logs//participant_1.log"), "/tmp/logs//participant_1.crash");
+        assert_eq!(path_for("/tmp/logs/participant_1"), "/tmp/logs/participant_1.crash");
+    }
+}
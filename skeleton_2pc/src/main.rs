@@ -4,9 +4,11 @@ extern crate stderrlog;
 extern crate clap;
 extern crate ctrlc;
 extern crate ipc_channel;
+use std::collections::HashMap;
 use std::env;
 use std::fs;
 use std::sync::Arc;
+use std::sync::mpsc;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::process::{Child,Command};
 use ipc_channel::ipc::IpcSender as Sender;
@@ -20,26 +22,60 @@ pub mod participant;
 pub mod client;
 pub mod checker;
 pub mod tpcoptions;
+pub mod stats;
+pub mod acceptor;
+pub mod transport;
+pub mod crash;
+pub mod partition;
+pub mod seed;
 use message::ProtocolMessage;
 use std::thread;
+use acceptor::Acceptor;
 use client::Client;
+use client::Workload;
 use participant::Participant;
+use participant::ParticipantLinks;
+use transport::Transport;
 use std::{time::Duration};
 
 ///
-/// pub fn spawn_child_and_connect(child_opts: &mut tpcoptions::TPCOptions) -> (std::process::Child, Sender<ProtocolMessage>, Receiver<ProtocolMessage>)
+/// PeerList
+/// The senders of a participant's peers, or of the acceptors, by name
+///
+type PeerList = Vec<(String, Sender<ProtocolMessage>)>;
+
+///
+/// PeerSetup
+/// What a participant hands the parent so it can be connected to its peers:
+/// a sender the peers reach it on, and a sender for the list of its peers'
+/// senders, which the parent sends once every participant is up. The list of
+/// the acceptors' senders follows on the same channel (empty unless the
+/// protocol is Paxos Commit).
+///
+type PeerSetup = (Sender<ProtocolMessage>, Sender<PeerList>);
+
+///
+/// Handshake
+/// What a child sends back over the one-shot server: its channels to and from
+/// the coordinator, and for participants, its PeerSetup
+///
+type Handshake = (Sender<ProtocolMessage>, Receiver<ProtocolMessage>, Option<PeerSetup>);
+
+///
+/// pub fn spawn_child_and_connect(child_opts: &mut tpcoptions::TPCOptions) -> (std::process::Child, Sender<ProtocolMessage>, Receiver<ProtocolMessage>, Option<PeerSetup>)
 ///
 ///     child_opts: CLI options for child process
 ///
 /// 1. Set up IPC
 /// 2. Spawn a child process using the child CLI options
 /// 3. Do any required communication to set up the parent / child communication channels
-/// 4. Return the child process handle and the communication channels for the parent
+/// 4. Return the child process handle, the communication channels for the
+///    parent and, for participants, what it takes to connect the peers
 ///
 /// HINT: You can change the signature of the function if necessary
 ///
-fn spawn_child_and_connect(child_opts: &mut tpcoptions::TPCOptions) -> (Child, Sender<ProtocolMessage>, Receiver<ProtocolMessage>) {
-    let (server, server_name) = IpcOneShotServer::<(Sender<ProtocolMessage>, Receiver<ProtocolMessage>)>::new().unwrap();
+fn spawn_child_and_connect(child_opts: &mut tpcoptions::TPCOptions) -> (Child, Sender<ProtocolMessage>, Receiver<ProtocolMessage>, Option<PeerSetup>) {
+    let (server, server_name) = IpcOneShotServer::<Handshake>::new().unwrap();
     //println!("{}",server_name.to_string());
     child_opts.ipc_path = server_name.clone();
     //println!("{}",child_opts.ipc_path.to_string());
@@ -52,28 +88,84 @@ fn spawn_child_and_connect(child_opts: &mut tpcoptions::TPCOptions) -> (Child, S
     //let (tx, rx):(Sender::<ProtocolMessage>, Receiver::<ProtocolMessage>) = channel().unwrap();
     // TODO
 
-    let (_, (tx,rx)) = server.accept().unwrap();
+    let (_, (tx,rx,peer_setup)) = server.accept().unwrap();
 
-    (child, tx, rx )
+    (child, tx, rx, peer_setup)
 }
 
 ///
-/// pub fn connect_to_coordinator(opts: &tpcoptions::TPCOptions) -> (Sender<ProtocolMessage>, Receiver<ProtocolMessage>)
-///
-///     opts: CLI options for this process
+/// pub fn connect_peers(setups: &[(String, PeerSetup)], acceptors: &[(String, Sender<ProtocolMessage>)])
 ///
-/// 1. Connect to the parent via IPC
-/// 2. Do any required communication to set up the parent / child communication channels
-/// 3. Return the communication channels for the child
+///     setups: name and PeerSetup of every participant
+///     acceptors: name and sender of every acceptor
 ///
-/// HINT: You can change the signature of the function if necessasry
+/// Sends every participant the senders of all the other participants, then
+/// the senders of the acceptors
 ///
-fn connect_to_coordinator(opts: &tpcoptions::TPCOptions) -> (Sender<ProtocolMessage>, Receiver<ProtocolMessage>) {
-    let (tx, rx) = channel().unwrap();
-    
-
+fn connect_peers(setups: &[(String, PeerSetup)], acceptors: &[(String, Sender<ProtocolMessage>)]) {
+    for (name, (_, setup_tx)) in setups {
+        let peers: Vec<(String, Sender<ProtocolMessage>)> = setups.iter()
+            .filter(|(peer, _)| peer != name)
+            .map(|(peer, (peer_tx, _))| (peer.clone(), peer_tx.clone()))
+            .collect();
+        setup_tx.send(peers).unwrap();
+        setup_tx.send(acceptors.to_vec()).unwrap();
+    }
+}
 
-    (tx, rx)
+///
+/// pub fn supervise(opts: tpcoptions::TPCOptions, transport: Arc<Transport>, participants: Vec<Child>, setups: Vec<(String, PeerSetup)>, acceptors: Vec<(String, Sender<ProtocolMessage>)>, restarts: mpsc::Sender<coordinator::Restart>, done: Arc<AtomicBool>)
+///
+///     opts: CLI options of the run
+///     transport: the coordinator's transport, for the senders to restarted participants
+///     participants: participant processes, in the order of setups
+///     setups: name and PeerSetup of every participant
+///     acceptors: name and sender of every acceptor
+///     restarts: where the coordinator gets the channels of restarted participants
+///     done: set once the coordinator is done
+///
+/// --restart: until done is set, restarts a participant that exits at its
+/// crash point, with the same --num, recovering from its log. The other
+/// participants get the new sender to it as a peer update on their
+/// PeerSetup, and it gets theirs and the acceptors' as at the start.
+///
+fn supervise(opts: tpcoptions::TPCOptions, transport: Arc<Transport>, mut participants: Vec<Child>, mut setups: Vec<(String, PeerSetup)>, acceptors: Vec<(String, Sender<ProtocolMessage>)>, restarts: mpsc::Sender<coordinator::Restart>, done: Arc<AtomicBool>) {
+    while !done.load(Ordering::SeqCst) {
+        for i in 0..participants.len() {
+            match participants[i].try_wait() {
+                Ok(Some(status)) if status.code() == Some(crash::CRASH_EXIT_CODE) => {},
+                _ => continue,
+            }
+            let name = setups[i].0.clone();
+            info!("participant_{} crashed, restarting it", name);
+            let mut part_opts = opts.clone();
+            part_opts.mode = "participant".to_string();
+            part_opts.num = i as u32;
+            part_opts.recover = true;
+            part_opts.crash = None;
+            part_opts.restarted = true;
+            let ( participant, coor_part_tx, part_coor_rx, peer_setup) = spawn_child_and_connect( &mut part_opts);
+            let (peer_tx, setup_tx) = peer_setup.unwrap();
+            for (peer, (_, peer_setup_tx)) in &setups {
+                if *peer != name && peer_setup_tx.send(vec![(name.clone(), peer_tx.clone())]).is_err() {
+                    debug!("participant_{} is gone", peer);
+                }
+            }
+            let peers: Vec<(String, Sender<ProtocolMessage>)> = setups.iter()
+                .filter(|(peer, _)| *peer != name)
+                .map(|(peer, (peer_tx, _))| (peer.clone(), peer_tx.clone()))
+                .collect();
+            setup_tx.send(peers).unwrap();
+            setup_tx.send(acceptors.clone()).unwrap();
+            setups[i].1 = (peer_tx, setup_tx);
+            participants[i] = participant;
+            let coor_part_tx = Transport::sender(&transport, coor_part_tx, format!("participant_{}", name));
+            if restarts.send((name.clone(), coor_part_tx, part_coor_rx)).is_err() {
+                break;
+            }
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
 }
 
 ///
@@ -82,20 +174,34 @@ fn connect_to_coordinator(opts: &tpcoptions::TPCOptions) -> (Sender<ProtocolMess
 ///     running: An atomically reference counted (ARC) AtomicBool(ean) that is
 ///         set to be false whenever Ctrl+C is pressed
 ///
-/// 1. Creates a new coordinator
+/// 1. Creates a new coordinator, or recovers one from its log if --recover
 /// 2. Spawns and connects to new clients processes and then registers them with
 ///    the coordinator
 /// 3. Spawns and connects to new participant processes and then registers them
-///    with the coordinator
-/// 4. Starts the coordinator protocol
-/// 5. Wait until the children finish execution
+///    with the coordinator, which runs the protocol with its children (all of
+///    them unless there is a tree of sub-coordinators)
+/// 4. Paxos Commit: spawns and connects to the acceptor processes and then
+///    registers them with the coordinator
+/// 5. Connects the participants to each other and to the acceptors
+/// 6. With --restart, supervises the participants: one that crashes at its
+///    crash point is restarted and rejoins the coordinator and its peers
+/// 7. Starts the coordinator protocol
+/// 8. Wait until the children finish execution
 ///
 fn run(opts: & tpcoptions::TPCOptions, running: Arc<AtomicBool>) {
     let coord_log_path = format!("{}//{}", opts.log_path, "coordinator.log");
     println!("{}", opts.mode);
+    println!("seed {}", opts.seed);
+    seed::record(&opts.log_path, opts.recover, opts.seed);
 
 
-    let mut coor = coordinator::Coordinator::new( coord_log_path, &running, opts.num_requests.clone());
+    let mut coor = if opts.recover {
+        coordinator::Coordinator::recover( coord_log_path, &running, opts.coordinator_config())
+    } else {
+        coordinator::Coordinator::new( coord_log_path, &running, opts.coordinator_config())
+    };
+    let transport = Transport::new("coordinator".to_string(), &opts.log_path, opts.recover, opts.send_success_probability, opts.link_faults(), opts.seed);
+    transport.hold_for(coor.barrier());
     let mut counter = 0;
 
     loop{
@@ -103,12 +209,13 @@ fn run(opts: & tpcoptions::TPCOptions, running: Arc<AtomicBool>) {
         let mut client_opts = opts.clone();
 		client_opts.mode = "client".to_string();
 		client_opts.num =counter;
-		let ( client, coor_cl_tx, cl_coor_rx) = spawn_child_and_connect( &mut client_opts.clone());
+		client_opts.epoch = coor.epoch();
+		let ( _client, coor_cl_tx, cl_coor_rx, _) = spawn_child_and_connect( &mut client_opts.clone());
 		
-        let proc_name=format!("{}",client_opts.num.to_string());
+        let proc_name=client_opts.num.to_string();
 		
 		println!("{}",proc_name);
-        coor.client_join(&proc_name,coor_cl_tx, cl_coor_rx);
+        coor.client_join(&proc_name,Transport::sender(&transport, coor_cl_tx, format!("client_{}", proc_name)), cl_coor_rx);
 				
         counter+= 1;
         if counter == opts.num_clients{
@@ -116,22 +223,53 @@ fn run(opts: & tpcoptions::TPCOptions, running: Arc<AtomicBool>) {
         }
     }
     counter =0;
+    let mut participants = Vec::new();
+    let mut peer_setups = Vec::new();
     loop{
         let mut part_opts = opts.clone();
 		part_opts.mode = "participant".to_string();
 		part_opts.num =counter;
-		let ( participant, coor_part_tx, part_coor_rx) = spawn_child_and_connect( &mut part_opts.clone());
+		let ( participant, coor_part_tx, part_coor_rx, peer_setup) = spawn_child_and_connect( &mut part_opts.clone());
 
-        let proc_name=format!("{}",part_opts.num.to_string());
-        coor.participant_join(&proc_name, coor_part_tx, part_coor_rx);
+        let proc_name=part_opts.num.to_string();
+        coor.participant_join(&proc_name, Transport::sender(&transport, coor_part_tx, format!("participant_{}", proc_name)), part_coor_rx, opts.parent_of(counter).is_none());
+        participants.push(participant);
+        peer_setups.push((proc_name, peer_setup.unwrap()));
 
         counter+= 1;
         if counter == opts.num_participants{
             break;
         }
     };
+    let mut acceptors = Vec::new();
+    if opts.protocol == message::CommitProtocol::PaxosCommit {
+        for num in 0..opts.num_acceptors {
+            let mut acc_opts = opts.clone();
+            acc_opts.mode = "acceptor".to_string();
+            acc_opts.num = num;
+            let ( _acceptor, coor_acc_tx, acc_coor_rx, _) = spawn_child_and_connect( &mut acc_opts.clone());
+
+            let proc_name = format!("{}", num);
+            acceptors.push((proc_name.clone(), coor_acc_tx.clone()));
+            coor.acceptor_join(&proc_name, Transport::sender(&transport, coor_acc_tx, format!("acceptor_{}", proc_name)), acc_coor_rx);
+        }
+    }
+    connect_peers(&peer_setups, &acceptors);
+    let done = Arc::new(AtomicBool::new(false));
+    let supervisor = if opts.restart {
+        let (restarts_tx, restarts_rx) = mpsc::channel();
+        coor.restarts_from(restarts_rx);
+        let (opts, transport, done) = (opts.clone(), transport.clone(), done.clone());
+        Some(thread::spawn(move || supervise(opts, transport, participants, peer_setups, acceptors, restarts_tx, done)))
+    } else {
+        None
+    };
     println!{"starting coor"}
 	coor.protocol();
+	done.store(true, Ordering::SeqCst);
+	if let Some(supervisor) = supervisor {
+		supervisor.join().unwrap();
+	}
 }
 
 ///
@@ -146,13 +284,16 @@ fn run(opts: & tpcoptions::TPCOptions, running: Arc<AtomicBool>) {
 ///
 fn run_client(opts: & tpcoptions::TPCOptions, running: Arc<AtomicBool>) {
 
-	let server = Sender::connect(opts.ipc_path.clone()).unwrap();
+	let server: Sender<Handshake> = Sender::connect(opts.ipc_path.clone()).unwrap();
     let (cl_coor_tx, cl_coor_rx):(Sender::<ProtocolMessage>, Receiver::<ProtocolMessage>) = channel().unwrap();
 	let (coor_cl_tx, coor_cl_rx):(Sender::<ProtocolMessage>, Receiver::<ProtocolMessage>) = channel().unwrap();
-	server.send((coor_cl_tx,cl_coor_rx)).unwrap();
+	server.send((coor_cl_tx,cl_coor_rx,None)).unwrap();
 	
-	let mut client = Client::new(opts.num.to_string(),  running,  cl_coor_tx,coor_cl_rx);
-	client.protocol(opts.num_requests.clone());
+	let transport = Transport::new(format!("client_{}", opts.num), &opts.log_path, opts.recover, opts.send_success_probability, opts.link_faults(), opts.seed);
+	let cl_coor_tx = Transport::sender(&transport, cl_coor_tx, "coordinator".to_string());
+	let workload = Workload { num_participants: opts.num_participants, write_prob: opts.write_probability, rng: seed::rng(opts.seed, &format!("client_{}", opts.num)) };
+	let mut client = Client::new(opts.num.to_string(),  running, opts.epoch, workload, cl_coor_tx,coor_cl_rx);
+	client.protocol(opts.num_requests);
 	
 }
 
@@ -163,24 +304,60 @@ fn run_client(opts: & tpcoptions::TPCOptions, running: Arc<AtomicBool>) {
 ///         set to be false whenever Ctrl+C is pressed
 ///
 /// 1. Connects to the coordinator to get tx/rx
-/// 2. Constructs a new participant
-/// 3. Starts the participant protocol
+/// 2. Waits for the senders of its peers and of the acceptors; in tree 2PC its
+///    parent and children are among the peers. Peers that are restarted
+///    later come in on the same channel.
+/// 3. Constructs a new participant, replaying its log if --recover
+/// 4. Starts the participant protocol
 ///
 fn run_participant(opts: & tpcoptions::TPCOptions, running: Arc<AtomicBool>) {
     let participant_id_str = format!("participant_{}", opts.num);
-    let participant_log_path = format!("{}//{}.log", opts.log_path, participant_id_str);
 
-	let server = Sender::connect(opts.ipc_path.clone()).unwrap();
+	let server: Sender<Handshake> = Sender::connect(opts.ipc_path.clone()).unwrap();
     let (part_coor_tx, part_coor_rx):(Sender::<ProtocolMessage>, Receiver::<ProtocolMessage>) = channel().unwrap();
 	let (coor_part_tx, coor_part_rx):(Sender::<ProtocolMessage>, Receiver::<ProtocolMessage>) = channel().unwrap();
-	server.send((coor_part_tx,part_coor_rx)).unwrap();
+	let (peer_tx, peer_rx):(Sender::<ProtocolMessage>, Receiver::<ProtocolMessage>) = channel().unwrap();
+	let (setup_tx, setup_rx):(Sender::<PeerList>, Receiver::<PeerList>) = channel().unwrap();
+	server.send((coor_part_tx,part_coor_rx,Some((peer_tx,setup_tx)))).unwrap();
+	let transport = Transport::new(participant_id_str.clone(), &opts.log_path, opts.recover, opts.send_success_probability, opts.link_faults(), opts.seed);
+	let peers: HashMap<String, transport::Sender> = setup_rx.recv().unwrap().into_iter()
+		.map(|(name, tx)| (name.clone(), Transport::sender(&transport, tx, format!("participant_{}", name)))).collect();
+	let acceptors: Vec<transport::Sender> = setup_rx.recv().unwrap().into_iter()
+		.map(|(name, tx)| Transport::sender(&transport, tx, format!("acceptor_{}", name))).collect();
 	
+	let coordinator = Transport::sender(&transport, part_coor_tx, "coordinator".to_string());
+	let links = ParticipantLinks { tx: coordinator, rx: coor_part_rx, peers, peer_rx, peer_updates: setup_rx, transport, acceptors };
 	
-	let mut participant = Participant::new( opts.num.to_string(), participant_log_path, running,opts.send_success_probability, opts.operation_success_probability,part_coor_tx,coor_part_rx);
+	let mut participant = Participant::new(opts.participant_config(), running, links);
 	participant.protocol();
 	
 }
 
+///
+/// pub fn run_acceptor(opts: &tpcoptions:TPCOptions, running: Arc<AtomicBool>)
+///     opts: An options structure containing the CLI arguments
+///     running: An atomically reference counted (ARC) AtomicBool(ean) that is
+///         set to be false whenever Ctrl+C is pressed
+///
+/// 1. Connects to the coordinator to get tx/rx
+/// 2. Constructs a new acceptor, replaying its log if --recover
+/// 3. Starts the acceptor protocol
+///
+fn run_acceptor(opts: & tpcoptions::TPCOptions, running: Arc<AtomicBool>) {
+    let acceptor_id_str = format!("acceptor_{}", opts.num);
+    let acceptor_log_path = format!("{}//{}.log", opts.log_path, acceptor_id_str);
+
+	let server: Sender<Handshake> = Sender::connect(opts.ipc_path.clone()).unwrap();
+    let (acc_coor_tx, acc_coor_rx):(Sender::<ProtocolMessage>, Receiver::<ProtocolMessage>) = channel().unwrap();
+	let (coor_acc_tx, coor_acc_rx):(Sender::<ProtocolMessage>, Receiver::<ProtocolMessage>) = channel().unwrap();
+	server.send((coor_acc_tx,acc_coor_rx,None)).unwrap();
+
+	let transport = Transport::new(acceptor_id_str, &opts.log_path, opts.recover, opts.send_success_probability, opts.link_faults(), opts.seed);
+	let acc_coor_tx = Transport::sender(&transport, acc_coor_tx, "coordinator".to_string());
+	let mut acceptor = Acceptor::new( opts.num.to_string(), acceptor_log_path, opts.recover, opts.log_config(), running, acc_coor_tx, coor_acc_rx);
+	acceptor.protocol();
+}
+
 fn main() {
     // Parse CLI arguments
     let opts = tpcoptions::TPCOptions::new();
@@ -192,9 +369,8 @@ fn main() {
             .verbosity(opts.verbosity)
             .init()
             .unwrap();
-    match fs::create_dir_all(opts.log_path.clone()) {
-        Err(e) => error!("Failed to create log_path: \"{:?}\". Error \"{:?}\"", opts.log_path, e),
-        _ => (),
+    if let Err(e) = fs::create_dir_all(opts.log_path.clone()) {
+        error!("Failed to create log_path: \"{:?}\". Error \"{:?}\"", opts.log_path, e);
     }
 
     // Set-up Ctrl-C / SIGINT handler
@@ -204,17 +380,26 @@ fn main() {
     ctrlc::set_handler(move || {
         r.store(false, Ordering::SeqCst);
         if m == "run" {
-            print!("\n");
+            println!();
         }
     }).expect("Error setting signal handler!");
 
 
+    if opts.mode != "run" && opts.mode != "check" {
+        info!("{}_{}::Seed {}", opts.mode, opts.num, opts.seed);
+    }
+
     // Execute main logic
     match opts.mode.as_ref() {
         "run" => run(&opts, running),
         "client" => run_client(&opts, running),
         "participant" => run_participant(&opts, running),
-        "check" => checker::check_last_run(opts.num_clients, opts.num_requests, opts.num_participants, &opts.log_path),
+        "acceptor" => run_acceptor(&opts, running),
+        "check" => {
+            if !checker::check_last_run(&opts.check_config()) {
+                std::process::exit(1);
+            }
+        },
         _ => panic!("Unknown mode"),
     }
 }
@@ -15,7 +15,7 @@ use self::serde_json::Value;
 ///
 /// HINT: You should not need to modify this, but can add to it if necessary
 ///
-#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum MessageType {
     ClientRequest,          // Request a transaction from the coordinator
     CoordinatorPropose,     // Coordinator sends propose work to participants
@@ -26,6 +26,161 @@ pub enum MessageType {
     ClientResultCommit,     // result (success/fail) communicated to client
     ClientResultAbort,      // result (success/fail) communicated to client
     CoordinatorExit,        // Coordinator telling client/participant about shut down
+    ParticipantDecisionRequest, // Participant asking for the outcome of an in-doubt txid
+    CheckpointBegin,        // Log only: opens a checkpoint, opid is the number of in-flight txns in it
+    CheckpointEnd,          // Log only: closes a checkpoint, opid is the number of in-flight txns in it
+    CoordinatorPreCommit,   // 3PC: every participant voted commit, commit unless the coordinator fails
+    ParticipantAckPreCommit, // 3PC: participant received the PreCommit
+    ParticipantStateRequest, // 3PC termination: asks a peer for its state of txid. The reply is the
+                            // peer's latest record type for txid; a peer that has not voted aborts
+    ParticipantAck,         // Participant received the global decision
+    CoordinatorCollecting,  // Log only, presumed commit: votes are being collected for txid
+    ParticipantVoteReadOnly, // Participant made no writes in txid: it does not care about the outcome
+                            // and takes no part in phase 2
+    CoordinatorCompleted,   // Log only: every participant acknowledged the decision of txid
+    // Paxos Commit: the three votes double as phase 2a (to the acceptors) and 2b (acceptors to the
+    // leader) messages and as acceptor log records; senderid is the participant whose instance it
+    // is, opid the ballot
+    PaxosPrepare,           // Paxos Commit phase 1a: the leader asks acceptors to promise ballot opid for
+                            // the instance of participant senderid
+    PaxosPromise,           // Paxos Commit phase 1b: the acceptor promised ballot opid, `accepted` is what
+                            // it accepted before. Also logged by acceptors
+}
+
+///
+/// CommitProtocol
+/// Atomic commit protocol run between the coordinator and participants
+///
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CommitProtocol {
+    TwoPhase,               // blocking 2PC: participants wait for the coordinator's decision
+    ThreePhase,             // 3PC: PreCommit phase, participants terminate without the coordinator
+    PaxosCommit,            // Paxos Commit: votes are chosen by acceptors, any leader can finish a txid
+}
+
+impl CommitProtocol {
+
+    ///
+    /// from_name(name)
+    /// Parses a protocol as spelled on the command line
+    ///
+    pub fn from_name(name: &str) -> Option<CommitProtocol> {
+        match name {
+            "2pc" => Some(CommitProtocol::TwoPhase),
+            "3pc" => Some(CommitProtocol::ThreePhase),
+            "paxos" => Some(CommitProtocol::PaxosCommit),
+            _ => None,
+        }
+    }
+
+    ///
+    /// name()
+    /// Spelling of the protocol on the command line
+    ///
+    pub fn name(&self) -> &'static str {
+        match *self {
+            CommitProtocol::TwoPhase => "2pc",
+            CommitProtocol::ThreePhase => "3pc",
+            CommitProtocol::PaxosCommit => "paxos",
+        }
+    }
+}
+
+///
+/// Presumption
+/// What a 2PC coordinator presumes about a txid it has no record of. It
+/// decides which records are forced, which decisions are acknowledged, and
+/// how in-doubt participants are answered.
+///
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Presumption {
+    Nothing,                // every decision is forced and acknowledged; unknown txids are aborted and logged
+    Abort,                  // aborts are neither logged by the coordinator, forced nor acknowledged
+    Commit,                 // a forced collecting record up front; commits are not forced by participants nor acknowledged
+}
+
+impl Presumption {
+
+    ///
+    /// from_name(name)
+    /// Parses a presumption as spelled on the command line
+    ///
+    pub fn from_name(name: &str) -> Option<Presumption> {
+        match name {
+            "nothing" => Some(Presumption::Nothing),
+            "abort" => Some(Presumption::Abort),
+            "commit" => Some(Presumption::Commit),
+            _ => None,
+        }
+    }
+
+    ///
+    /// name()
+    /// Spelling of the presumption on the command line
+    ///
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Presumption::Nothing => "nothing",
+            Presumption::Abort => "abort",
+            Presumption::Commit => "commit",
+        }
+    }
+
+    ///
+    /// presumed()
+    /// Outcome of a txid with no decision on record
+    ///
+    pub fn presumed(&self) -> MessageType {
+        match *self {
+            Presumption::Commit => MessageType::CoordinatorCommit,
+            _ => MessageType::CoordinatorAbort,
+        }
+    }
+
+    ///
+    /// explicit(decision)
+    /// True if the decision goes against the presumption, so it has to be
+    /// forced to the logs and acknowledged by participants
+    ///
+    pub fn explicit(&self, decision: MessageType) -> bool {
+        match *self {
+            Presumption::Nothing => true,
+            _ => decision != self.presumed(),
+        }
+    }
+}
+
+///
+/// OpKind
+/// Intent of an operation: whether it changes the data it touches
+///
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OpKind {
+    Read,                   // reads a key, the participant's data stays as it is
+    Write,                  // writes a key once the transaction commits
+}
+
+///
+/// Operation
+/// One read or write of a key by a client request. Keys are spread over
+/// the participants: each one owns the keys equal to its number modulo the
+/// number of participants.
+///
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Operation {
+    pub kind: OpKind,
+    pub key: u32,
+}
+
+impl Operation {
+
+    ///
+    /// owner(num_participants)
+    /// Number of the participant that owns the key
+    ///
+    pub fn owner(&self, num_participants: u32) -> u32 {
+        self.key % num_participants
+    }
 }
 
 ///
@@ -62,6 +217,10 @@ pub struct ProtocolMessage {
     pub senderid: String,    // Sender ID (unique across all senders)
     pub opid: u32,           // Operation ID (relative to the original client who started this transaction)
 	pub cl_id: u32,
+    #[serde(default)]
+    pub ops: Vec<Operation>, // Reads and writes the client requested (not kept in logs)
+    #[serde(default)]
+    pub accepted: Option<(u32, MessageType)>, // Paxos Commit promise: ballot and vote accepted last (not kept in logs)
 }
 
 ///
@@ -76,6 +235,8 @@ impl ProtocolMessage {
             senderid: sid,
             opid: oid,
 			cl_id: cid,
+            ops: Vec::new(),
+            accepted: None,
         }
     }
     pub fn instantiate(t: MessageType, u: u32, tid: String, sid: String, oid: u32,cid: u32) -> ProtocolMessage {
@@ -86,6 +247,8 @@ impl ProtocolMessage {
             senderid: sid,
             opid: oid,
 			cl_id: cid,
+            ops: Vec::new(),
+            accepted: None,
         }
     }
     pub fn from_string(line: &String) -> ProtocolMessage {
@@ -1,109 +1,1900 @@
 extern crate serde;
 extern crate serde_json;
 extern crate bincode;
+extern crate crc32fast;
 
 use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs;
 use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
 use std::io::BufReader;
 use std::io::prelude::*;
+use std::path::Path;
 use std::sync::Arc;
+use std::sync::Condvar;
 use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
 
 use message;
 
+/// default number of records per group commit
+pub const DEFAULT_GROUP_BATCH: usize = 64;
+/// default time the first record of a group waits for company, in microseconds
+pub const DEFAULT_GROUP_DELAY_US: u64 = 1000;
+
+///
+/// DurabilityPolicy
+/// How hard append() works to get a record onto stable storage before it
+/// returns. Anything weaker than Fsync can lose acknowledged records on a
+/// machine crash; it is there to measure what durability costs.
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DurabilityPolicy {
+    None,           // write(), leave the data in the OS page cache
+    Flush,          // write() + flush(), which is a no-op for an unbuffered File
+    Fsync,          // write() + sync_data() for every record
+    GroupCommit {   // appends that arrive together share one write() + sync_data()
+        max_batch: usize,       // flush as soon as this many records are pending
+        max_delay: Duration,    // or once the oldest pending record waited this long
+    },
+}
+
+impl DurabilityPolicy {
+
+    ///
+    /// from_name(name)
+    /// Parses a policy as spelled on the command line
+    ///
+    pub fn from_name(name: &str) -> Option<DurabilityPolicy> {
+        match name {
+            "none" => Some(DurabilityPolicy::None),
+            "flush" => Some(DurabilityPolicy::Flush),
+            "fsync" => Some(DurabilityPolicy::Fsync),
+            "group" => Some(DurabilityPolicy::GroupCommit {
+                max_batch: DEFAULT_GROUP_BATCH,
+                max_delay: Duration::from_micros(DEFAULT_GROUP_DELAY_US),
+            }),
+            _ => None,
+        }
+    }
+
+    ///
+    /// name()
+    /// Spelling of the policy on the command line
+    ///
+    pub fn name(&self) -> &'static str {
+        match *self {
+            DurabilityPolicy::None => "none",
+            DurabilityPolicy::Flush => "flush",
+            DurabilityPolicy::Fsync => "fsync",
+            DurabilityPolicy::GroupCommit { .. } => "group",
+        }
+    }
+}
+
+/// first bytes of a binary log; JSON logs start with '{'. The last byte is
+/// the version of the frame format.
+const BINARY_MAGIC: &[u8] = b"TPCLOG\x00\x02";
+/// length, CRC32 of the length and CRC32 of the record that precede every
+/// binary record
+const FRAME_HEADER_LEN: usize = 12;
+/// longest record a frame may hold; a longer length is corruption
+const MAX_FRAME_LEN: usize = 1 << 20;
+
+///
+/// LogFormat
+/// On-disk encoding of log records
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    Json,       // one serde_json record per line
+    Binary,     // magic header, then [len: u32 LE][crc32(len): u32 LE][crc32(record): u32 LE][bincode record] frames
+}
+
+impl LogFormat {
+
+    ///
+    /// from_name(name)
+    /// Parses a format as spelled on the command line
+    ///
+    pub fn from_name(name: &str) -> Option<LogFormat> {
+        match name {
+            "json" => Some(LogFormat::Json),
+            "binary" => Some(LogFormat::Binary),
+            _ => None,
+        }
+    }
+
+    ///
+    /// name()
+    /// Spelling of the format on the command line
+    ///
+    pub fn name(&self) -> &'static str {
+        match *self {
+            LogFormat::Json => "json",
+            LogFormat::Binary => "binary",
+        }
+    }
+}
+
+///
+/// LogConfig
+/// Settings for a log that is going to be written
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LogConfig {
+    pub durability: DurabilityPolicy,
+    pub format: LogFormat,
+    pub segment_size: u64,      // rotate to a new segment file past this many bytes, 0 for one file
+    pub truncate: bool,         // delete segments that no checkpointed transaction needs
+}
+
+///
+/// LogRecord
+/// One record of an OpLog as it is stored: the message, the record's log
+/// sequence number and when it was appended. LSNs number the records of a
+/// log from 1 in file order, across restarts and segments, so an LSN names
+/// the same record in a live log, a reloaded one and on disk. JSON logs
+/// written before records had this shape hold bare messages, one per line;
+/// they are read with the message's uid as the LSN, which is what numbered
+/// them then, and no timestamp.
+///
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct LogRecord {
+    pub lsn: u64,
+    pub timestamp: u64,                 // microseconds since the UNIX epoch
+    pub msg: message::ProtocolMessage,
+}
+
+///
+/// now_micros()
+///
+/// Returns the wall clock time in microseconds since the UNIX epoch
+///
+fn now_micros() -> u64 {
+    let since = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    since.as_secs() * 1_000_000 + since.subsec_micros() as u64
+}
+
+///
+/// encode(format, record)
+///
+/// Returns the bytes of one log record in the given format
+///
+fn encode(format: LogFormat, record: &LogRecord) -> Vec<u8> {
+    match format {
+        LogFormat::Json => {
+            let mut bytes = serde_json::to_vec(record).unwrap();
+            bytes.push(b'\n');
+            bytes
+        },
+        LogFormat::Binary => {
+            let payload = bincode::serialize(record).unwrap();
+            let mut bytes = Vec::with_capacity(FRAME_HEADER_LEN + payload.len());
+            let len = (payload.len() as u32).to_le_bytes();
+            bytes.extend_from_slice(&len);
+            bytes.extend_from_slice(&crc32fast::hash(&len).to_le_bytes());
+            bytes.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+            bytes.extend_from_slice(&payload);
+            bytes
+        },
+    }
+}
+
+///
+/// read_full(reader, buf)
+///
+/// Like read_exact(), but returns how many bytes were read before EOF
+/// instead of failing, so a torn frame header can be told from a clean end
+///
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+///
+/// read_binary_record(reader)
+///
+/// Reads the next frame of a binary log. Returns Ok(None) at a clean end of
+/// file, and Err with the reason when the frame is torn or fails its CRC.
+/// The flag in the error tells whether the whole frame was there. The
+/// length is only trusted once its own CRC checks out and it is not over
+/// MAX_FRAME_LEN; a whole header that fails either check is a bad frame,
+/// not a torn one.
+///
+fn read_binary_record<R: Read>(reader: &mut R) -> Result<Option<(LogRecord, u64)>, (String, bool)> {
+    let mut header = [0u8; FRAME_HEADER_LEN];
+    let n = read_full(reader, &mut header).map_err(|e| (e.to_string(), false))?;
+    if n == 0 {
+        return Ok(None);
+    }
+    if n < FRAME_HEADER_LEN {
+        return Err((format!("torn frame header ({} of {} bytes)", n, FRAME_HEADER_LEN), false));
+    }
+    let mut len_bytes = [0u8; 4];
+    let mut len_crc_bytes = [0u8; 4];
+    let mut crc_bytes = [0u8; 4];
+    len_bytes.copy_from_slice(&header[0..4]);
+    len_crc_bytes.copy_from_slice(&header[4..8]);
+    crc_bytes.copy_from_slice(&header[8..12]);
+    if crc32fast::hash(&len_bytes) != u32::from_le_bytes(len_crc_bytes) {
+        return Err(("frame header CRC mismatch".to_string(), true));
+    }
+    let len = u32::from_le_bytes(len_bytes) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err((format!("frame length {} over {}", len, MAX_FRAME_LEN), true));
+    }
+    let crc = u32::from_le_bytes(crc_bytes);
+    let mut payload = Vec::new();
+    let n = reader.take(len as u64).read_to_end(&mut payload).map_err(|e| (e.to_string(), false))?;
+    if n < len {
+        return Err((format!("torn record ({} of {} bytes)", n, len), false));
+    }
+    if crc32fast::hash(&payload) != crc {
+        return Err(("CRC mismatch".to_string(), true));
+    }
+    match bincode::deserialize(&payload) {
+        Ok(record) => Ok(Some((record, (FRAME_HEADER_LEN + len) as u64))),
+        Err(e) => Err((format!("undecodable record: {}", e), true)),
+    }
+}
+
+///
+/// read_json_record(reader)
+///
+/// Reads the next line of a JSON log, like read_binary_record(). A last line
+/// without its newline counts as torn even if it parses: the record and its
+/// newline go out in one write, so the write did not complete. A line that
+/// is a bare message is a record of the old shape.
+///
+fn read_json_record<R: BufRead>(reader: &mut R) -> Result<Option<(LogRecord, u64)>, (String, bool)> {
+    let mut line = Vec::new();
+    let n = reader.read_until(b'\n', &mut line).map_err(|e| (e.to_string(), false))?;
+    if n == 0 {
+        return Ok(None);
+    }
+    if line[n - 1] != b'\n' {
+        return Err((format!("torn line ({} bytes, no newline)", n), false));
+    }
+    let e = match serde_json::from_slice(&line[..n - 1]) {
+        Ok(record) => return Ok(Some((record, n as u64))),
+        Err(e) => e,
+    };
+    match serde_json::from_slice::<message::ProtocolMessage>(&line[..n - 1]) {
+        Ok(msg) => Ok(Some((LogRecord { lsn: msg.uid as u64, timestamp: 0, msg }, n as u64))),
+        Err(_) => Err((format!("unparsable line: {}", e), true)),
+    }
+}
+
+///
+/// Corruption
+/// Where a log stops being readable
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Corruption {
+    None,       // every byte belongs to a valid record
+    TornTail,   // the last record is incomplete, its write cut short; safe to truncate there
+    Middle,     // a whole record is bad, or records follow a bad one; never truncated
+}
+
+///
+/// LogScan
+/// Result of reading a log in recovery mode
+///
+#[derive(Clone, Debug)]
+pub struct LogScan {
+    pub path: String,
+    pub format: LogFormat,
+    pub records: usize,         // valid records before the corruption
+    pub valid_len: u64,         // byte offset where the corruption starts
+    pub file_len: u64,
+    pub corruption: Corruption,
+    pub reason: Option<String>, // what was wrong with the first bad record
+}
+
+impl fmt::Display for LogScan {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {} valid {} records", self.path, self.records, self.format.name())?;
+        match self.corruption {
+            Corruption::None => Ok(()),
+            Corruption::TornTail => write!(f, ", torn tail at byte {} of {} ({})",
+                                           self.valid_len, self.file_len, self.reason.clone().unwrap_or_default()),
+            Corruption::Middle => write!(f, ", corrupt at byte {} of {} ({})",
+                                         self.valid_len, self.file_len, self.reason.clone().unwrap_or_default()),
+        }
+    }
+}
+
+///
+/// LogError
+/// Why a log could not be read in recovery mode
+///
+#[derive(Debug)]
+pub enum LogError {
+    Io(io::Error),
+    TornTail(LogScan),
+    CorruptMiddle(LogScan),
+}
+
+impl fmt::Display for LogError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LogError::Io(ref e) => write!(f, "{}", e),
+            LogError::TornTail(ref scan) => write!(f, "{}", scan),
+            LogError::CorruptMiddle(ref scan) => write!(f, "{}", scan),
+        }
+    }
+}
+
+impl From<io::Error> for LogError {
+    fn from(e: io::Error) -> LogError {
+        LogError::Io(e)
+    }
+}
+
+///
+/// RecordReader
+/// Reads the records of one log file in order, keeping track of how many
+/// valid records and bytes it has seen
+///
+#[derive(Debug)]
+struct RecordReader {
+    path: String,
+    reader: BufReader<File>,
+    format: LogFormat,
+    file_len: u64,
+    valid_len: u64,
+    records: usize,
+}
+
+impl RecordReader {
+
+    ///
+    /// open(fpath)
+    /// Opens a log file of either format, detected from its first bytes. A
+    /// binary log of another version of the frame format is refused.
+    ///
+    fn open(fpath: &str) -> io::Result<RecordReader> {
+        let file = File::open(fpath)?;
+        let file_len = file.metadata()?.len();
+        let mut reader = BufReader::new(file);
+        let version = BINARY_MAGIC.len() - 1;
+        let format = {
+            let head = reader.fill_buf()?;
+            if head.starts_with(BINARY_MAGIC) {
+                LogFormat::Binary
+            } else if head.len() > version && head.starts_with(&BINARY_MAGIC[..version]) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                    format!("{}: binary log of frame format version {}, only version {} can be read",
+                            fpath, head[version], BINARY_MAGIC[version])));
+            } else {
+                LogFormat::Json
+            }
+        };
+        if format == LogFormat::Binary {
+            reader.consume(BINARY_MAGIC.len());
+        }
+        let valid_len = match format {
+            LogFormat::Json => 0,
+            LogFormat::Binary => BINARY_MAGIC.len() as u64,
+        };
+        Ok(RecordReader {
+            path: fpath.to_string(),
+            reader,
+            format,
+            file_len,
+            valid_len,
+            records: 0,
+        })
+    }
+
+    ///
+    /// next_record()
+    /// Like read_binary_record() and read_json_record(), for this file's format
+    ///
+    fn next_record(&mut self) -> Result<Option<LogRecord>, (String, bool)> {
+        let next = match self.format {
+            LogFormat::Json => read_json_record(&mut self.reader),
+            LogFormat::Binary => read_binary_record(&mut self.reader),
+        };
+        match next {
+            Ok(Some((record, len))) => {
+                self.records += 1;
+                self.valid_len += len;
+                Ok(Some(record))
+            },
+            Ok(None) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    ///
+    /// finish(failure)
+    ///
+    /// Describes the file read so far, given the error that stopped reading,
+    /// if any. Only a record cut short by the end of the file is a torn tail:
+    /// appends write a record in one go, so a crash can tear the last one,
+    /// but a record that is all there and still bad was damaged after it was
+    /// written, and the records around it can not be trusted either.
+    ///
+    fn finish(self, failure: Option<(String, bool)>) -> LogScan {
+        let (corruption, reason) = match failure {
+            Some((why, true)) => (Corruption::Middle, Some(why)),
+            Some((why, false)) => (Corruption::TornTail, Some(why)),
+            None => (Corruption::None, None),
+        };
+        LogScan {
+            path: self.path,
+            format: self.format,
+            records: self.records,
+            valid_len: self.valid_len,
+            file_len: self.file_len,
+            corruption,
+            reason,
+        }
+    }
+}
+
+///
+/// scan_file(fpath, visit)
+///
+/// Reads a log of either format and hands every valid record, in file order,
+/// to visit(). Reading stops at the first bad record.
+///
+fn scan_file<F>(fpath: &str, mut visit: F) -> io::Result<LogScan>
+    where F: FnMut(LogRecord) {
+    let mut reader = RecordReader::open(fpath)?;
+    let mut failure = None;
+    loop {
+        match reader.next_record() {
+            Ok(Some(record)) => visit(record),
+            Ok(None) => break,
+            Err(e) => {
+                failure = Some(e);
+                break;
+            },
+        }
+    }
+    Ok(reader.finish(failure))
+}
+
+///
+/// LogIter
+/// Streams the records of a log, one file or all of its segments, in file
+/// order without holding more than one record in memory. Returned by
+/// OpLog::iter_file(). The first error ends the stream: a torn tail of the
+/// last file is LogError::TornTail, anything else that is unreadable is
+/// LogError::CorruptMiddle.
+///
+#[derive(Debug)]
+pub struct LogIter {
+    files: VecDeque<String>,
+    current: Option<RecordReader>,
+    done: bool,
+}
+
+impl Iterator for LogIter {
+    type Item = Result<LogRecord, LogError>;
+
+    fn next(&mut self) -> Option<Result<LogRecord, LogError>> {
+        while !self.done {
+            if self.current.is_none() {
+                match self.files.pop_front() {
+                    Some(file) => match RecordReader::open(&file) {
+                        Ok(reader) => self.current = Some(reader),
+                        Err(e) => {
+                            self.done = true;
+                            return Some(Err(LogError::Io(e)));
+                        },
+                    },
+                    None => self.done = true,
+                }
+                continue;
+            }
+            let next = self.current.as_mut().unwrap().next_record();
+            match next {
+                Ok(Some(record)) => return Some(Ok(record)),
+                Ok(None) => self.current = None,
+                Err(failure) => {
+                    self.done = true;
+                    let mut scan = self.current.take().unwrap().finish(Some(failure));
+                    if !self.files.is_empty() {
+                        scan.corruption = Corruption::Middle;
+                    }
+                    return match scan.corruption {
+                        Corruption::Middle => Some(Err(LogError::CorruptMiddle(scan))),
+                        _ => Some(Err(LogError::TornTail(scan))),
+                    };
+                },
+            }
+        }
+        None
+    }
+}
+
+///
+/// GroupWrite
+/// Something for the group commit flusher to do, in append order
+///
+#[derive(Debug)]
+enum GroupWrite {
+    Record(Vec<u8>),    // bytes of one encoded record
+    Switch(File),       // segment rotation: sync the current file, continue in this one
+}
+
+///
+/// GroupQueue
+/// Records waiting for the group commit flusher, and how far it has got.
+/// Tickets number the records in append order; every ticket up to and
+/// including `durable` is on stable storage. Once a write or sync fails,
+/// `failed` holds the error and nothing after `durable` ever will be.
+///
+#[derive(Debug)]
+struct GroupQueue {
+    pending: Vec<GroupWrite>,
+    pending_count: usize,
+    oldest: Option<Instant>,
+    next_ticket: u64,
+    forced: u64,
+    durable: u64,
+    failed: Option<(io::ErrorKind, String)>,
+    shutdown: bool,
+}
+
+impl GroupQueue {
+
+    ///
+    /// wait_result(ticket)
+    /// Ok once ticket is durable, the flusher's error if it never will be,
+    /// None while it is pending
+    ///
+    fn wait_result(&self, ticket: u64) -> Option<io::Result<()>> {
+        if self.durable >= ticket {
+            return Some(Ok(()));
+        }
+        self.failed.as_ref().map(|&(kind, ref why)| Err(io::Error::new(kind, why.clone())))
+    }
+}
+
+///
+/// GroupCommit
+/// State shared by appenders and the flusher thread. Appenders signal
+/// `work` when they queue a record; the flusher signals `done` after every
+/// sync_data() so waiting appenders can check their ticket.
+///
+#[derive(Debug)]
+struct GroupCommit {
+    queue: Mutex<GroupQueue>,
+    work: Condvar,
+    done: Condvar,
+}
+
+///
+/// CommitHandle
+/// Returned by append_async(). wait() blocks until the record is as
+/// durable as the log's DurabilityPolicy makes it.
+///
+#[derive(Debug)]
+pub struct CommitHandle {
+    lsn: u64,
+    ticket: u64,
+    group: Option<Arc<GroupCommit>>,
+}
+
+impl CommitHandle {
+
+    ///
+    /// lsn()
+    /// LSN of the record behind this handle
+    ///
+    pub fn lsn(&self) -> u64 {
+        self.lsn
+    }
+
+    ///
+    /// wait()
+    /// Blocks until the record behind this handle is durable. Returns the
+    /// error of the group commit flusher if it failed to write the record.
+    ///
+    pub fn wait(self) -> io::Result<()> {
+        match self.group {
+            Some(group) => Barrier { group }.wait(self.ticket),
+            None => Ok(()),
+        }
+    }
+}
+
+///
+/// Barrier
+/// The group commit of a log as its process's transport sees it. A message
+/// sent after a forced append may depend on the record (write-ahead), so
+/// the transport holds it back until the record is durable. That lets a
+/// process append the records of several transactions, send what depends
+/// on them, and have one sync_data() make them durable before any of it
+/// goes out.
+///
+#[derive(Clone, Debug)]
+pub struct Barrier {
+    group: Arc<GroupCommit>,
+}
+
+impl Barrier {
+
+    ///
+    /// pending()
+    /// The ticket of the newest forced record, if it is not durable yet
+    ///
+    pub fn pending(&self) -> Option<u64> {
+        let queue = self.group.queue.lock().unwrap();
+        if queue.forced > queue.durable {
+            Some(queue.forced)
+        } else {
+            None
+        }
+    }
+
+    ///
+    /// durable()
+    /// The ticket up to which every record is durable
+    ///
+    pub fn durable(&self) -> u64 {
+        self.group.queue.lock().unwrap().durable
+    }
+
+    ///
+    /// wait(ticket)
+    /// Blocks until every record up to ticket is durable, or the group
+    /// commit flusher failed to make it so
+    ///
+    pub fn wait(&self, ticket: u64) -> io::Result<()> {
+        let mut queue = self.group.queue.lock().unwrap();
+        loop {
+            if let Some(result) = queue.wait_result(ticket) {
+                return result;
+            }
+            queue = self.group.done.wait(queue).unwrap();
+        }
+    }
+}
+
+///
+/// group_flusher(group, lf, max_batch, max_delay)
+///
+/// Body of the group commit thread: waits for a batch to fill up or for its
+/// oldest record to time out, then writes the whole batch with one write()
+/// and one sync_data(), or one per segment if the batch spans a rotation.
+/// Drains whatever is pending before a shutdown. The first write or sync
+/// that fails stops it, and the error goes to everyone waiting.
+///
+fn group_flusher(group: Arc<GroupCommit>, mut lf: File, max_batch: usize, max_delay: Duration) {
+    loop {
+        let (batch, last_ticket) = {
+            let mut queue = group.queue.lock().unwrap();
+            loop {
+                if queue.pending_count >= max_batch || (queue.shutdown && queue.pending_count > 0) {
+                    break;
+                }
+                if queue.shutdown {
+                    return;
+                }
+                match queue.oldest {
+                    Some(oldest) => {
+                        let waited = oldest.elapsed();
+                        if waited >= max_delay {
+                            break;
+                        }
+                        queue = group.work.wait_timeout(queue, max_delay - waited).unwrap().0;
+                    },
+                    None => {
+                        queue = group.work.wait(queue).unwrap();
+                    },
+                }
+            }
+            let batch = std::mem::take(&mut queue.pending);
+            queue.pending_count = 0;
+            queue.oldest = None;
+            (batch, queue.next_ticket)
+        };
+        let written = write_batch(&mut lf, batch);
+        let mut queue = group.queue.lock().unwrap();
+        match written {
+            Ok(()) => queue.durable = last_ticket,
+            Err(e) => {
+                error!("group commit failed: {}", e);
+                queue.failed = Some((e.kind(), e.to_string()));
+            },
+        }
+        group.done.notify_all();
+        if queue.failed.is_some() {
+            return;
+        }
+    }
+}
+
+///
+/// write_batch(lf, batch)
+///
+/// Writes and syncs one batch of the group commit flusher, switching lf to
+/// the next segment where the log rotated
+///
+fn write_batch(lf: &mut File, batch: Vec<GroupWrite>) -> io::Result<()> {
+    let mut bytes = Vec::new();
+    for write in batch {
+        match write {
+            GroupWrite::Record(record) => bytes.extend_from_slice(&record),
+            GroupWrite::Switch(next) => {
+                lf.write_all(&bytes)?;
+                lf.sync_data()?;
+                bytes.clear();
+                *lf = next;
+            },
+        }
+    }
+    lf.write_all(&bytes)?;
+    lf.sync_data()
+}
+
+///
+/// segment_path(base, segment)
+///
+/// Returns the file name of a segment of a segmented log, e.g.
+/// coordinator.log.000001
+///
+pub fn segment_path(base: &str, segment: u32) -> String {
+    format!("{}.{:06}", base, segment)
+}
+
+///
+/// list_segments(base)
+///
+/// Returns the numbers of the existing segments of a log, oldest first
+///
+pub fn list_segments(base: &str) -> Vec<u32> {
+    let path = Path::new(base);
+    let dir = match path.parent() {
+        Some(d) if !d.as_os_str().is_empty() => d.to_path_buf(),
+        _ => Path::new(".").to_path_buf(),
+    };
+    let prefix = match path.file_name() {
+        Some(name) => format!("{}.", name.to_string_lossy()),
+        None => return Vec::new(),
+    };
+    let mut segments: Vec<u32> = match fs::read_dir(dir) {
+        Ok(entries) => entries.filter_map(|e| e.ok())
+            .filter_map(|e| {
+                let name = e.file_name().to_string_lossy().into_owned();
+                if name.starts_with(&prefix) && name.len() == prefix.len() + 6 {
+                    name[prefix.len()..].parse::<u32>().ok()
+                } else {
+                    None
+                }
+            })
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    segments.sort();
+    segments
+}
+
+///
+/// create_log_file(fpath, format)
+///
+/// Creates (or truncates) a log file and writes the header its format needs.
+/// Returns the file and its length.
+///
+fn create_log_file(fpath: &str, format: LogFormat) -> (File, u64) {
+    let mut lf = File::create(fpath).unwrap();
+    let mut len = 0;
+    if format == LogFormat::Binary {
+        lf.write_all(BINARY_MAGIC).unwrap();
+        len = BINARY_MAGIC.len() as u64;
+    }
+    (lf, len)
+}
+
+///
+/// is_checkpoint_marker(mtype)
+///
+/// True for the records that open and close a checkpoint. The records in
+/// between are copies of the latest record of each in-flight transaction.
+///
+pub fn is_checkpoint_marker(mtype: message::MessageType) -> bool {
+    mtype == message::MessageType::CheckpointBegin || mtype == message::MessageType::CheckpointEnd
+}
+
 #[derive(Debug)]
 pub struct OpLog {
-    seqno: u32,
-    log_arc: Arc<Mutex<HashMap<u32, message::ProtocolMessage>>>,
+    lsn: u64,                               // LSN of the last record appended or replayed
+    log_arc: Arc<Mutex<HashMap<u64, LogRecord>>>,
     path: String,
     lf: File,
+    durability: DurabilityPolicy,
+    format: LogFormat,
+    valid_len: u64,
+    group: Option<Arc<GroupCommit>>,
+    flusher: Option<thread::JoinHandle<()>>,
+    segment: u32,                           // current segment, 0 when the log is a single file
+    segment_size: u64,
+    truncate: bool,
+    segment_lsn: Vec<(u32, u64)>,           // (segment, LSN of its first record) for loaded segments
+    first_segment: HashMap<String, u32>,    // txid -> segment of its oldest record
+    txid_index: HashMap<String, Vec<u64>>,  // txid -> LSNs of its loaded records, oldest first
+    retain_from: u32,                       // oldest segment the last checkpoint still needs
+    checkpoint_due: bool,
+    in_checkpoint: bool,
+    forced_writes: u64,                     // records appended durably, checkpoints aside
+    lazy_writes: u64,                       // records appended with append_lazy()
 }
 
 impl OpLog {
 
     /// 
-    /// new(fpath: String)
-    /// Creates a new Operations Log at the designated file path
+    /// new(fpath: String, config: LogConfig)
+    /// Creates a new Operations Log at the designated file path. With a
+    /// segment size in the config, the log is written to numbered segment
+    /// files next to that path instead, and old segments are removed.
     ///
-    pub fn new(fpath: String) -> OpLog {
+    pub fn new(fpath: String, config: LogConfig) -> OpLog {
         let l = HashMap::new();
         let lck = Mutex::new(l);
         let arc = Arc::new(lck);
+        let mut segment = 0;
+        let mut file_path = fpath.clone();
+        if config.segment_size > 0 {
+            for old in list_segments(&fpath) {
+                fs::remove_file(segment_path(&fpath, old)).unwrap();
+            }
+            segment = 1;
+            file_path = segment_path(&fpath, segment);
+        }
+        let (lf, valid_len) = create_log_file(&file_path, config.format);
         OpLog {
-            seqno: 0,
+            lsn: 0,
             log_arc: arc,
             path: fpath.to_string(),
-            lf: File::create(fpath).unwrap(),
-        }
+            lf,
+            durability: DurabilityPolicy::Flush,
+            format: config.format,
+            valid_len,
+            group: None,
+            flusher: None,
+            segment,
+            segment_size: config.segment_size,
+            truncate: config.truncate,
+            segment_lsn: vec![(segment, 1)],
+            first_segment: HashMap::new(),
+            txid_index: HashMap::new(),
+            retain_from: segment,
+            checkpoint_due: false,
+            in_checkpoint: false,
+            forced_writes: 0,
+            lazy_writes: 0,
+        }.with_durability(config.durability)
+    }
+
+    ///
+    /// exists(fpath: String)
+    ///
+    /// True if there is a log at the path, as one file or as segments
+    ///
+    pub fn exists(fpath: &str) -> bool {
+        Path::new(fpath).exists() || !list_segments(fpath).is_empty()
     }
 
     ///
     /// from_file(fpath: String)
     ///
-    /// Reads in and returns an existing Operations Log from the designated file
+    /// Reads in and returns an existing Operations Log from the designated file.
+    /// The format is detected from the first bytes of the file. Reading stops
+    /// at the first torn or corrupt record; recover_file() says where and why.
     ///
     pub fn from_file(fpath: String) -> OpLog {
-        let mut seqno = 0;
+        let (log, scan) = OpLog::load(fpath, false).unwrap();
+        if scan.corruption != Corruption::None {
+            warn!("{}", scan);
+        }
+        log
+    }
+
+    ///
+    /// iter_file(fpath: String)
+    ///
+    /// Streams the records of an existing log, one file or all of its
+    /// segments, in file order. Unlike from_file(), memory use does not grow
+    /// with the size of the log.
+    ///
+    pub fn iter_file(fpath: String) -> Result<LogIter, LogError> {
+        let files: VecDeque<String> = match Path::new(&fpath).exists() {
+            true => vec![fpath.clone()].into_iter().collect(),
+            false => list_segments(&fpath).iter().map(|n| segment_path(&fpath, *n)).collect(),
+        };
+        if files.is_empty() {
+            return Err(LogError::Io(io::Error::new(io::ErrorKind::NotFound, fpath)));
+        }
+        Ok(LogIter {
+            files,
+            current: None,
+            done: false,
+        })
+    }
+
+    ///
+    /// recover_file(fpath: String)
+    ///
+    /// Reads an existing Operations Log in recovery mode: a torn tail is
+    /// reported in the returned LogScan, corruption in the middle of the log
+    /// is an error
+    ///
+    pub fn recover_file(fpath: String) -> Result<(OpLog, LogScan), LogError> {
+        let (log, scan) = OpLog::load(fpath, false)?;
+        if scan.corruption == Corruption::Middle {
+            return Err(LogError::CorruptMiddle(scan));
+        }
+        Ok((log, scan))
+    }
+
+    ///
+    /// repair(fpath: String)
+    ///
+    /// Truncates a torn tail off the log file in place. Corruption in the
+    /// middle of the log is left alone and returned as an error. For a
+    /// segmented log only the last segment may have a torn tail.
+    ///
+    pub fn repair(fpath: String) -> Result<LogScan, LogError> {
+        let files = match Path::new(&fpath).exists() {
+            true => vec![fpath.clone()],
+            false => list_segments(&fpath).iter().map(|n| segment_path(&fpath, *n)).collect(),
+        };
+        let mut last = None;
+        for (i, file) in files.iter().enumerate() {
+            let mut scan = scan_file(file, |_| {})?;
+            if scan.corruption != Corruption::None && i + 1 < files.len() {
+                scan.corruption = Corruption::Middle;
+            }
+            match scan.corruption {
+                Corruption::None => {},
+                Corruption::TornTail => {
+                    let lf = OpenOptions::new().write(true).open(file)?;
+                    lf.set_len(scan.valid_len)?;
+                    lf.sync_all()?;
+                },
+                Corruption::Middle => return Err(LogError::CorruptMiddle(scan)),
+            }
+            last = Some(scan);
+        }
+        last.ok_or(LogError::Io(io::Error::new(io::ErrorKind::NotFound, fpath)))
+    }
+
+    ///
+    /// load(fpath: String, from_checkpoint: bool)
+    ///
+    /// Reads a log, one file or segments, into a read-only OpLog. With
+    /// from_checkpoint, a segmented log is replayed from its latest complete
+    /// checkpoint instead of its oldest segment. The returned LogScan
+    /// describes the last file read.
+    ///
+    fn load(fpath: String, from_checkpoint: bool) -> io::Result<(OpLog, LogScan)> {
+        if Path::new(&fpath).exists() {
+            return OpLog::load_files(fpath.clone(), vec![(0, fpath)], 0);
+        }
+        let segments = list_segments(&fpath);
+        if segments.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, fpath));
+        }
+        if !from_checkpoint {
+            let files = segments.iter().map(|n| (*n, segment_path(&fpath, *n))).collect();
+            return OpLog::load_files(fpath, files, 0);
+        }
+
+        // Find the newest complete checkpoint, searching from the newest segment
+        let mut start = 0;
+        let mut from_lsn = 0;
+        for (i, segment) in segments.iter().enumerate().rev() {
+            let mut begin = None;
+            let mut found = None;
+            scan_file(&segment_path(&fpath, *segment), |record| {
+                match record.msg.mtype {
+                    message::MessageType::CheckpointBegin => begin = Some(record.lsn),
+                    message::MessageType::CheckpointEnd => found = begin,
+                    _ => {},
+                }
+            })?;
+            if let Some(lsn) = found {
+                start = i;
+                from_lsn = lsn;
+                break;
+            }
+        }
+        let files = segments[start..].iter().map(|n| (*n, segment_path(&fpath, *n))).collect();
+        let (mut log, scan) = OpLog::load_files(fpath, files, from_lsn)?;
+        log.retain_from = segments[start];
+        Ok((log, scan))
+    }
+
+    ///
+    /// load_files(fpath, files, from_lsn)
+    ///
+    /// Replays the given (segment, file) list in order, skipping records
+    /// before from_lsn. Corruption anywhere but the end of the last file
+    /// counts as Middle.
+    ///
+    fn load_files(fpath: String, files: Vec<(u32, String)>, from_lsn: u64) -> io::Result<(OpLog, LogScan)> {
+        let mut lsn = 0;
         let mut l = HashMap::new();
-        let scopy = fpath.clone();
-        let tlf = File::open(fpath).unwrap();
-        let mut reader = BufReader::new(&tlf);
-        let mut line = String::new();
-        let mut len = reader.read_line(&mut line).unwrap();
-        while len > 0 {
-            let pm = message::ProtocolMessage::from_string(&line);
-            if pm.uid > seqno {
-                seqno = pm.uid;
-            }
-            l.insert(pm.uid, pm);
-            line.clear();
-            len = reader.read_line(&mut line).unwrap();
+        let mut first_segment = HashMap::new();
+        let mut txid_index: HashMap<String, Vec<u64>> = HashMap::new();
+        let mut segment_lsn = Vec::new();
+        let mut records = 0;
+        let mut last_scan = None;
+        let num_files = files.len();
+        for (i, &(segment, ref file)) in files.iter().enumerate() {
+            let mut first_lsn = None;
+            // Key by LSN rather than uid: uids restart with every process,
+            // so a reopened log holds several runs with equal uids.
+            let mut scan = scan_file(file, |record| {
+                lsn = record.lsn;
+                first_lsn.get_or_insert(record.lsn);
+                // records before the checkpoint still count, so truncation keeps in-flight transactions whole
+                if segment > 0 && !is_checkpoint_marker(record.msg.mtype) {
+                    first_segment.entry(record.msg.txid.clone()).or_insert(segment);
+                }
+                if record.lsn < from_lsn {
+                    return;
+                }
+                if !is_checkpoint_marker(record.msg.mtype) {
+                    txid_index.entry(record.msg.txid.clone()).or_default().push(record.lsn);
+                }
+                l.insert(record.lsn, record);
+            })?;
+            segment_lsn.push((segment, first_lsn.unwrap_or(lsn + 1)));
+            records += scan.records;
+            if scan.corruption != Corruption::None && i + 1 < num_files {
+                scan.corruption = Corruption::Middle;
+                last_scan = Some(scan);
+                break;
+            }
+            last_scan = Some(scan);
         }
+        let mut scan = last_scan.unwrap();
+        scan.records = records;
+        let (segment, ref file) = files[files.len() - 1];
+        let tlf = File::open(file)?;
         let lck = Mutex::new(l);
         let arc = Arc::new(lck);
-        OpLog {
-            seqno: seqno,
+        let log = OpLog {
+            lsn,
             log_arc: arc,
-            path: scopy,
+            path: fpath,
             lf: tlf,
+            durability: DurabilityPolicy::Flush,
+            format: scan.format,
+            valid_len: scan.valid_len,
+            group: None,
+            flusher: None,
+            segment,
+            segment_size: 0,
+            truncate: false,
+            segment_lsn,
+            first_segment,
+            txid_index,
+            retain_from: files[0].0,
+            checkpoint_due: false,
+            in_checkpoint: false,
+            forced_writes: 0,
+            lazy_writes: 0,
+        };
+        Ok((log, scan))
+    }
+
+    ///
+    /// reopen(fpath: String, config: LogConfig)
+    ///
+    /// Reads in an existing Operations Log and reopens it for appending, so a
+    /// restarted process keeps its history. Creates a new log if none exists.
+    /// An existing log keeps its own format and layout (one file or
+    /// segments), and is replayed from its latest checkpoint. A torn tail is
+    /// cut off before appending; a log that is corrupt in the middle is not
+    /// touched.
+    ///
+    pub fn reopen(fpath: String, config: LogConfig) -> OpLog {
+        if !OpLog::exists(&fpath) {
+            return OpLog::new(fpath, config);
         }
+        let (mut log, scan) = OpLog::load(fpath.clone(), true).unwrap();
+        if scan.corruption == Corruption::Middle {
+            panic!("can not reopen log: {}", scan);
+        }
+        let lf = OpenOptions::new().append(true).open(&scan.path).unwrap();
+        if scan.corruption == Corruption::TornTail {
+            warn!("{}, truncating it", scan);
+            lf.set_len(scan.valid_len).unwrap();
+        }
+        log.lf = lf;
+        log.segment_size = config.segment_size;
+        log.truncate = config.truncate;
+        log.with_durability(config.durability)
+    }
+
+    ///
+    /// with_durability(durability)
+    ///
+    /// Sets the durability policy of a log that is about to be written, and
+    /// starts the flusher thread if the policy is group commit
+    ///
+    fn with_durability(mut self, durability: DurabilityPolicy) -> OpLog {
+        if let DurabilityPolicy::GroupCommit { max_batch, max_delay } = durability {
+            let group = Arc::new(GroupCommit {
+                queue: Mutex::new(GroupQueue {
+                    pending: Vec::new(),
+                    pending_count: 0,
+                    oldest: None,
+                    next_ticket: 0,
+                    forced: 0,
+                    durable: 0,
+                    failed: None,
+                    shutdown: false,
+                }),
+                work: Condvar::new(),
+                done: Condvar::new(),
+            });
+            let lf = self.lf.try_clone().unwrap();
+            let shared = Arc::clone(&group);
+            self.flusher = Some(thread::spawn(move || group_flusher(shared, lf, max_batch, max_delay)));
+            self.group = Some(group);
+        }
+        self.durability = durability;
+        self
     }
 
     ///
     /// append(t, tid, sender, op)
     ///
-    /// Appends an entry to the Operations Log
+    /// Appends an entry to the Operations Log. When it returns, the entry is
+    /// as durable as the log's DurabilityPolicy makes it, so callers can send
+    /// messages that depend on it (write-ahead). Returns the record's LSN, or
+    /// the error that kept group commit from making it durable.
+    ///
+    pub fn append(&mut self, t: message::MessageType, tid: String, sender: String, op: u32) -> io::Result<u64> {
+        let handle = self.append_async(t, tid, sender, op);
+        let lsn = handle.lsn();
+        handle.wait()?;
+        Ok(lsn)
+    }
+
+    ///
+    /// append_async(t, tid, sender, op)
+    ///
+    /// Appends an entry to the Operations Log without waiting for it to be
+    /// durable; wait() on the returned handle does that. Only group commit
+    /// defers the write, so several appends made before waiting share one
+    /// sync_data(). Threads that share an OpLog behind a Mutex get the same
+    /// effect by calling append_async() under the lock and wait() outside it.
+    /// A process whose transport holds messages for the log's barrier() need
+    /// not wait at all before sending what depends on the record.
+    ///
+    pub fn append_async(&mut self, t: message::MessageType, tid: String, sender: String, op: u32) -> CommitHandle {
+        self.write_record(t, tid, sender, op, true)
+    }
+
+    ///
+    /// append_lazy(t, tid, sender, op)
+    ///
+    /// Appends an entry to the Operations Log without making it durable: it
+    /// reaches stable storage with whatever record is forced next. For
+    /// records that recovery can do without, e.g. decisions a protocol
+    /// variant presumes anyway. Returns the record's LSN.
+    ///
+    pub fn append_lazy(&mut self, t: message::MessageType, tid: String, sender: String, op: u32) -> u64 {
+        self.write_record(t, tid, sender, op, false).lsn()
+    }
+
+    ///
+    /// write_record(t, tid, sender, op, force)
+    ///
+    /// Body of append_async() and append_lazy(). Only a forced record is
+    /// synced (or waited for) according to the DurabilityPolicy.
     ///
-    pub fn append(&mut self, t: message::MessageType, tid: String, sender: String, op: u32) {
+    fn write_record(&mut self, t: message::MessageType, tid: String, sender: String, op: u32, force: bool) -> CommitHandle {
+        if !self.in_checkpoint {
+            match force {
+                true => self.forced_writes += 1,
+                false => self.lazy_writes += 1,
+            }
+        }
+        if self.segment > 0 && self.segment_size > 0 && !self.in_checkpoint && self.valid_len >= self.segment_size {
+            self.rotate();
+        }
+        if self.segment > 0 && !self.in_checkpoint {
+            self.first_segment.entry(tid.clone()).or_insert(self.segment);
+        }
         let lck = Arc::clone(&self.log_arc);
         let mut log = lck.lock().unwrap();
-        self.seqno += 1;
-        let id = self.seqno;
-        let pm = message::ProtocolMessage::generate(t, tid, sender, op,0);
-        serde_json::to_writer(&mut self.lf, &pm).unwrap();
-        writeln!(&mut self.lf).unwrap();
-        self.lf.flush().unwrap();
-        log.insert(id, pm);
+        self.lsn += 1;
+        let record = LogRecord {
+            lsn: self.lsn,
+            timestamp: now_micros(),
+            msg: message::ProtocolMessage::generate(t, tid, sender, op,0),
+        };
+        let bytes = encode(self.format, &record);
+        self.valid_len += bytes.len() as u64;
+        if !is_checkpoint_marker(record.msg.mtype) {
+            self.txid_index.entry(record.msg.txid.clone()).or_default().push(record.lsn);
+        }
+        log.insert(record.lsn, record);
+
+        let ticket = match self.group {
+            Some(ref group) => {
+                let mut queue = group.queue.lock().unwrap();
+                queue.pending.push(GroupWrite::Record(bytes));
+                queue.pending_count += 1;
+                if queue.oldest.is_none() {
+                    queue.oldest = Some(Instant::now());
+                }
+                queue.next_ticket += 1;
+                group.work.notify_one();
+                match force {
+                    true => {
+                        queue.forced = queue.next_ticket;
+                        queue.next_ticket
+                    },
+                    false => 0,
+                }
+            },
+            None => {
+                self.lf.write_all(&bytes).unwrap();
+                match self.durability {
+                    _ if !force => {},
+                    DurabilityPolicy::None => {},
+                    DurabilityPolicy::Flush => self.lf.flush().unwrap(),
+                    _ => self.lf.sync_data().unwrap(),
+                }
+                0
+            },
+        };
+        CommitHandle {
+            lsn: self.lsn,
+            ticket,
+            group: self.group.clone(),
+        }
     }
 
     ///
-    /// read(offset)
+    /// rotate()
     ///
-    /// Returns the log entry corresponding with the given offset
+    /// Continues the log in a new segment. Records already appended stay in
+    /// the old segment, with the durability they were appended with.
     ///
-    pub fn read(&mut self, offset: &u32) -> message::ProtocolMessage {
-        let lck = Arc::clone(&self.log_arc);
-        let log = lck.lock().unwrap();
-        let pm = log[&offset].clone();
-        pm
+    fn rotate(&mut self) {
+        let next = self.segment + 1;
+        let (lf, len) = create_log_file(&segment_path(&self.path, next), self.format);
+        if let Some(ref group) = self.group {
+            let mut queue = group.queue.lock().unwrap();
+            queue.pending.push(GroupWrite::Switch(lf.try_clone().unwrap()));
+            group.work.notify_one();
+        }
+        info!("{}: rotating to segment {}", self.path, next);
+        self.lf = lf;
+        self.valid_len = len;
+        self.segment = next;
+        self.segment_lsn.push((next, self.lsn + 1));
+        self.checkpoint_due = true;
+    }
+
+    ///
+    /// barrier()
+    ///
+    /// The Barrier of a group commit log, for the transport of its process
+    /// to hold messages for; None if appends are durable when they return
+    ///
+    pub fn barrier(&self) -> Option<Barrier> {
+        self.group.as_ref().map(|group| Barrier { group: Arc::clone(group) })
+    }
+
+    ///
+    /// sync()
+    ///
+    /// Blocks until every forced record appended so far is durable, or group
+    /// commit failed to make them so
+    ///
+    pub fn sync(&self) -> io::Result<()> {
+        if let Some(barrier) = self.barrier() {
+            if let Some(ticket) = barrier.pending() {
+                return barrier.wait(ticket);
+            }
+        }
+        Ok(())
+    }
+
+    ///
+    /// checkpoint_due()
+    ///
+    /// True once the log has rotated to a segment that has no checkpoint yet
+    ///
+    pub fn checkpoint_due(&self) -> bool {
+        self.checkpoint_due
+    }
+
+    ///
+    /// checkpoint(in_flight)
+    ///
+    /// Writes a checkpoint: a CheckpointBegin record, a copy of the latest
+    /// record of every in-flight transaction, and a CheckpointEnd record. Recovery can start at the latest complete
+    /// checkpoint. Segments older than the checkpoint and than the oldest
+    /// record of every in-flight transaction are no longer needed, and are
+    /// deleted if the log truncates. A resolved transaction may lose its
+    /// older records that way; recovery does not need any of them. Fails if the checkpoint could not be made durable.
+    ///
+    pub fn checkpoint(&mut self, in_flight: &Vec<message::ProtocolMessage>) -> io::Result<()> {
+        self.in_checkpoint = true;
+        self.append_async(message::MessageType::CheckpointBegin, "checkpoint".to_string(), String::new(), in_flight.len() as u32);
+        for pm in in_flight {
+            self.append_async(pm.mtype, pm.txid.clone(), pm.senderid.clone(), pm.opid);
+        }
+        let end = self.append(message::MessageType::CheckpointEnd, "checkpoint".to_string(), String::new(), in_flight.len() as u32);
+        self.in_checkpoint = false;
+        end?;
+        self.checkpoint_due = false;
+
+        let mut retain_from = self.segment;
+        for pm in in_flight {
+            if let Some(segment) = self.first_segment.get(&pm.txid) {
+                retain_from = retain_from.min(*segment);
+            }
+        }
+        self.retain_from = retain_from;
+        if self.truncate {
+            self.truncate();
+        }
+        Ok(())
+    }
+
+    ///
+    /// truncate()
+    ///
+    /// Deletes the segments the last checkpoint no longer needs, and forgets
+    /// their records. Returns the number of segments deleted.
+    ///
+    pub fn truncate(&mut self) -> usize {
+        if self.segment == 0 {
+            return 0;
+        }
+        let mut deleted = 0;
+        for segment in list_segments(&self.path) {
+            if segment < self.retain_from {
+                fs::remove_file(segment_path(&self.path, segment)).unwrap();
+                deleted += 1;
+            }
+        }
+        let retain_from = self.retain_from;
+        let keep_lsn = self.segment_lsn.iter()
+            .filter(|&&(segment, _)| segment >= retain_from)
+            .map(|&(_, lsn)| lsn)
+            .min()
+            .unwrap_or(self.lsn + 1);
+        self.segment_lsn.retain(|&(segment, _)| segment >= retain_from);
+        self.first_segment.retain(|_, segment| *segment >= retain_from);
+        self.log_arc.lock().unwrap().retain(|lsn, _| *lsn >= keep_lsn);
+        for lsns in self.txid_index.values_mut() {
+            lsns.retain(|lsn| *lsn >= keep_lsn);
+        }
+        self.txid_index.retain(|_, lsns| !lsns.is_empty());
+        if deleted > 0 {
+            info!("{}: deleted {} segments before segment {}", self.path, deleted, retain_from);
+        }
+        deleted
+    }
+
+    ///
+    /// lsn()
+    ///
+    /// LSN of the last record appended or replayed. It keeps counting across
+    /// restarts and checkpoints, so it grows even when segments are deleted.
+    ///
+    pub fn lsn(&self) -> u64 {
+        self.lsn
+    }
+
+    ///
+    /// read(lsn)
+    ///
+    /// Returns the log record with the given LSN. Live and reloaded logs
+    /// agree on it; None if it was not loaded or was truncated away.
+    ///
+    pub fn read(&self, lsn: u64) -> Option<LogRecord> {
+        self.log_arc.lock().unwrap().get(&lsn).cloned()
+    }
+
+    ///
+    /// path()
+    /// Path the log was created with: the file, or the base name of its segments
+    ///
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    ///
+    /// writes()
+    ///
+    /// Number of (forced, lazy) records this OpLog appended, not counting
+    /// checkpoints or records replayed from an earlier run
+    ///
+    pub fn writes(&self) -> (u64, u64) {
+        (self.forced_writes, self.lazy_writes)
+    }
+
+    ///
+    /// txids()
+    ///
+    /// Returns every txid with a record in the loaded part of the log, in
+    /// no particular order. Checkpoint markers are not indexed.
+    ///
+    pub fn txids(&self) -> Vec<String> {
+        self.txid_index.keys().cloned().collect()
+    }
+
+    ///
+    /// records_for_txid(txid)
+    ///
+    /// Returns the records of one transaction in LSN order, including the
+    /// copies a checkpoint made of it
+    ///
+    pub fn records_for_txid(&self, txid: &str) -> Vec<LogRecord> {
+        let log = self.log_arc.lock().unwrap();
+        match self.txid_index.get(txid) {
+            Some(lsns) => lsns.iter().map(|lsn| log[lsn].clone()).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    ///
+    /// last_record_for_txid(txid)
+    ///
+    /// Returns the newest record of one transaction, if it has any
+    ///
+    pub fn last_record_for_txid(&self, txid: &str) -> Option<LogRecord> {
+        let log = self.log_arc.lock().unwrap();
+        self.txid_index.get(txid)
+            .and_then(|lsns| lsns.last())
+            .map(|lsn| log[lsn].clone())
+    }
+
+    ///
+    /// last_state_for_txid(txid)
+    ///
+    /// Returns the message type of the newest record of one transaction,
+    /// e.g. ParticipantVoteCommit for a transaction that is still in doubt
+    ///
+    pub fn last_state_for_txid(&self, txid: &str) -> Option<message::MessageType> {
+        self.last_record_for_txid(txid).map(|record| record.msg.mtype)
     }
 
     ///
     /// arc
     ///
-    /// Returns an ARC of the mutex locked log
+    /// Returns an ARC of the mutex locked log, keyed by LSN
     ///
-    pub fn arc(&self) -> Arc<Mutex<HashMap<u32, message::ProtocolMessage>>> {
+    pub fn arc(&self) -> Arc<Mutex<HashMap<u64, LogRecord>>> {
         Arc::clone(&self.log_arc)
     }
 }
+
+impl Drop for OpLog {
+
+    ///
+    /// drop()
+    /// Lets the group commit flusher drain what is pending before the log goes
+    ///
+    fn drop(&mut self) {
+        if let Some(ref group) = self.group {
+            group.queue.lock().unwrap().shutdown = true;
+            group.work.notify_one();
+        }
+        if let Some(flusher) = self.flusher.take() {
+            if flusher.join().is_err() {
+                error!("{}: group commit flusher panicked", self.path);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::io::Cursor;
+    use std::process;
+
+    use message::MessageType;
+    use message::ProtocolMessage;
+
+    // A log path of its own for every test, tests run in parallel
+    fn temp_log(name: &str) -> String {
+        let path = env::temp_dir().join(format!("oplog_test_{}_{}.log", process::id(), name));
+        let path = path.to_string_lossy().into_owned();
+        remove_log(&path);
+        path
+    }
+
+    fn remove_log(path: &str) {
+        let _ = fs::remove_file(path);
+        for segment in list_segments(path) {
+            let _ = fs::remove_file(segment_path(path, segment));
+        }
+    }
+
+    fn config(format: LogFormat, segment_size: u64) -> LogConfig {
+        LogConfig {
+            durability: DurabilityPolicy::Flush,
+            format,
+            segment_size,
+            truncate: false,
+        }
+    }
+
+    fn record(lsn: u64, txid: &str) -> LogRecord {
+        LogRecord {
+            lsn,
+            timestamp: 0,
+            msg: ProtocolMessage::instantiate(MessageType::ParticipantVoteCommit, 7, txid.to_string(), "participant_0".to_string(), 3, 0),
+        }
+    }
+
+    #[test]
+    fn binary_frame_round_trip() {
+        let bytes = encode(LogFormat::Binary, &record(5, "client_0_tx_1"));
+        let mut reader = Cursor::new(bytes.clone());
+        let (read, len) = read_binary_record(&mut reader).unwrap().unwrap();
+        assert_eq!(len, bytes.len() as u64);
+        assert_eq!(read.lsn, 5);
+        assert_eq!(read.msg.txid, "client_0_tx_1");
+        assert_eq!(read.msg.mtype, MessageType::ParticipantVoteCommit);
+        assert_eq!(read.msg.opid, 3);
+        assert!(read_binary_record(&mut reader).unwrap().is_none());
+    }
+
+    #[test]
+    fn binary_log_round_trip() {
+        let path = temp_log("binary_round_trip");
+        {
+            let mut log = OpLog::new(path.clone(), config(LogFormat::Binary, 0));
+            for tx in 0..3 {
+                log.append(MessageType::ParticipantVoteCommit, format!("tx_{}", tx), "participant_0".to_string(), tx).unwrap();
+            }
+        }
+        let records: Vec<LogRecord> = OpLog::iter_file(path.clone()).unwrap().map(|r| r.unwrap()).collect();
+        assert_eq!(records.iter().map(|r| r.lsn).collect::<Vec<u64>>(), vec![1, 2, 3]);
+        assert_eq!(records[2].msg.txid, "tx_2");
+        let scan = scan_file(&path, |_| {}).unwrap();
+        assert_eq!(scan.format, LogFormat::Binary);
+        assert_eq!(scan.corruption, Corruption::None);
+        assert_eq!(scan.valid_len, scan.file_len);
+        remove_log(&path);
+    }
+
+    #[test]
+    fn bad_header_crc() {
+        // a damaged length is caught by its CRC instead of being read as a torn record
+        for byte in 0..8 {
+            let mut bytes = encode(LogFormat::Binary, &record(1, "tx"));
+            bytes[byte] ^= 0x40;
+            match read_binary_record(&mut Cursor::new(bytes)) {
+                Err((reason, complete)) => {
+                    assert!(complete);
+                    assert!(reason.contains("header CRC"), "{}", reason);
+                },
+                Ok(_) => panic!("bad header at byte {} read", byte),
+            }
+        }
+    }
+
+    #[test]
+    fn oversized_length() {
+        let len = ((MAX_FRAME_LEN + 1) as u32).to_le_bytes();
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&len);
+        bytes.extend_from_slice(&crc32fast::hash(&len).to_le_bytes());
+        bytes.extend_from_slice(&0u32.to_le_bytes());
+        bytes.extend_from_slice(&[0u8; 64]);
+        match read_binary_record(&mut Cursor::new(bytes)) {
+            Err((reason, complete)) => {
+                assert!(complete);
+                assert!(reason.contains("over"), "{}", reason);
+            },
+            Ok(_) => panic!("oversized frame read"),
+        }
+    }
+
+    #[test]
+    fn version_refusal() {
+        let path = temp_log("version_refusal");
+        let mut bytes = b"TPCLOG\x00\x01".to_vec();
+        bytes.extend_from_slice(&encode(LogFormat::Binary, &record(1, "tx")));
+        fs::write(&path, &bytes).unwrap();
+        let e = RecordReader::open(&path).err().unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert!(e.to_string().contains("version 1"), "{}", e);
+        match OpLog::iter_file(path.clone()).unwrap().next() {
+            Some(Err(LogError::Io(_))) => {},
+            other => panic!("read a log of another version: {:?}", other),
+        }
+        remove_log(&path);
+    }
+
+    // Three records by a log that is then dropped, so all of them are on disk
+    fn write_log(name: &str, format: LogFormat) -> String {
+        let path = temp_log(name);
+        let mut log = OpLog::new(path.clone(), config(format, 0));
+        for tx in 0..3 {
+            log.append(MessageType::ParticipantVoteCommit, format!("tx_{}", tx), "participant_0".to_string(), tx).unwrap();
+        }
+        path
+    }
+
+    fn flip_byte(path: &str, offset: u64) {
+        let mut bytes = fs::read(path).unwrap();
+        bytes[offset as usize] ^= 0x40;
+        fs::write(path, &bytes).unwrap();
+    }
+
+    fn cut_bytes(path: &str, n: u64) {
+        let lf = OpenOptions::new().write(true).open(path).unwrap();
+        let len = lf.metadata().unwrap().len();
+        lf.set_len(len - n).unwrap();
+    }
+
+    #[test]
+    fn torn_tail() {
+        for &format in &[LogFormat::Binary, LogFormat::Json] {
+            let path = write_log(&format!("torn_tail_{}", format.name()), format);
+            cut_bytes(&path, 5);
+            let scan = scan_file(&path, |_| {}).unwrap();
+            assert_eq!(scan.corruption, Corruption::TornTail, "{}", scan);
+            assert_eq!(scan.records, 2);
+            let (log, scan) = OpLog::recover_file(path.clone()).unwrap();
+            assert_eq!(scan.corruption, Corruption::TornTail);
+            assert_eq!(log.lsn(), 2);
+            drop(log);
+
+            let repaired = OpLog::repair(path.clone()).unwrap();
+            assert_eq!(fs::metadata(&path).unwrap().len(), repaired.valid_len);
+            let scan = scan_file(&path, |_| {}).unwrap();
+            assert_eq!(scan.corruption, Corruption::None);
+            assert_eq!(scan.records, 2);
+            remove_log(&path);
+        }
+    }
+
+    #[test]
+    fn corrupt_middle() {
+        let path = write_log("corrupt_middle", LogFormat::Binary);
+        flip_byte(&path, (BINARY_MAGIC.len() + FRAME_HEADER_LEN + 2) as u64);
+        let scan = scan_file(&path, |_| {}).unwrap();
+        assert_eq!(scan.corruption, Corruption::Middle, "{}", scan);
+        assert_eq!(scan.records, 0);
+        match OpLog::recover_file(path.clone()) {
+            Err(LogError::CorruptMiddle(_)) => {},
+            other => panic!("recovered a log corrupt in the middle: {:?}", other.map(|(_, scan)| scan)),
+        }
+        let len = fs::metadata(&path).unwrap().len();
+        match OpLog::repair(path.clone()) {
+            Err(LogError::CorruptMiddle(_)) => {},
+            other => panic!("repaired a log corrupt in the middle: {:?}", other),
+        }
+        assert_eq!(fs::metadata(&path).unwrap().len(), len);
+        remove_log(&path);
+    }
+
+    #[test]
+    fn corrupt_last_record() {
+        // a last record that is all there but bad was not torn by a crash
+        for &format in &[LogFormat::Binary, LogFormat::Json] {
+            let path = write_log(&format!("corrupt_last_{}", format.name()), format);
+            let len = fs::metadata(&path).unwrap().len();
+            flip_byte(&path, len - 3);
+            let scan = scan_file(&path, |_| {}).unwrap();
+            assert_eq!(scan.corruption, Corruption::Middle, "{}", scan);
+            assert_eq!(scan.records, 2);
+            match OpLog::iter_file(path.clone()).unwrap().last() {
+                Some(Err(LogError::CorruptMiddle(_))) => {},
+                other => panic!("corrupt last record read as {:?}", other),
+            }
+            remove_log(&path);
+        }
+    }
+
+    // Decided transactions tx_0..tx_(n-2), with tx_(n-1) in doubt: voted, no decision
+    fn write_txns(log: &mut OpLog, n: u32) {
+        for tx in 0..n {
+            log.append(MessageType::ParticipantVoteCommit, format!("tx_{}", tx), "participant_0".to_string(), tx).unwrap();
+            if tx + 1 < n {
+                log.append(MessageType::CoordinatorCommit, format!("tx_{}", tx), "participant_0".to_string(), tx).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn segment_rotation() {
+        let path = temp_log("segment_rotation");
+        {
+            let mut log = OpLog::new(path.clone(), config(LogFormat::Json, 512));
+            assert_eq!(list_segments(&path), vec![1]);
+            assert!(!log.checkpoint_due());
+            write_txns(&mut log, 20);
+            assert!(log.checkpoint_due());
+        }
+        assert!(!Path::new(&path).exists());
+        let segments = list_segments(&path);
+        assert!(segments.len() > 1);
+        assert_eq!(segments, (1..segments.len() as u32 + 1).collect::<Vec<u32>>());
+        for segment in &segments[..segments.len() - 1] {
+            assert!(fs::metadata(segment_path(&path, *segment)).unwrap().len() >= 512);
+        }
+        let lsns: Vec<u64> = OpLog::iter_file(path.clone()).unwrap().map(|r| r.unwrap().lsn).collect();
+        assert_eq!(lsns, (1..40).collect::<Vec<u64>>());
+
+        // a new log starts over at segment 1
+        drop(OpLog::new(path.clone(), config(LogFormat::Json, 512)));
+        assert_eq!(list_segments(&path), vec![1]);
+        remove_log(&path);
+    }
+
+    #[test]
+    fn checkpoint_replay() {
+        for &truncate in &[false, true] {
+            let path = temp_log(&format!("checkpoint_replay_{}", truncate));
+            let mut log_config = config(LogFormat::Binary, 512);
+            log_config.truncate = truncate;
+            {
+                let mut log = OpLog::new(path.clone(), log_config);
+                write_txns(&mut log, 20);
+                let in_flight = vec![log.last_record_for_txid("tx_19").unwrap().msg];
+                log.checkpoint(&in_flight).unwrap();
+                assert!(!log.checkpoint_due());
+                log.append(MessageType::ParticipantVoteAbort, "tx_20".to_string(), "participant_0".to_string(), 20).unwrap();
+            }
+            let segments = list_segments(&path);
+            assert_eq!(segments[0] > 1, truncate);
+
+            // replayed from the checkpoint: the in-doubt copy and what came after
+            let log = OpLog::reopen(path.clone(), log_config);
+            assert_eq!(log.lsn(), 39 + 3 + 1);
+            let mut txids = log.txids();
+            txids.sort();
+            assert_eq!(txids, vec!["tx_19".to_string(), "tx_20".to_string()]);
+            assert_eq!(log.last_state_for_txid("tx_19"), Some(MessageType::ParticipantVoteCommit));
+            assert_eq!(log.last_state_for_txid("tx_20"), Some(MessageType::ParticipantVoteAbort));
+            drop(log);
+
+            // a truncated log still has every record of the in-doubt transaction
+            let records: Vec<LogRecord> = OpLog::iter_file(path.clone()).unwrap().map(|r| r.unwrap()).collect();
+            let copies = records.iter().filter(|r| r.msg.txid == "tx_19").count();
+            assert_eq!(copies, 2);
+            assert_eq!(records.last().unwrap().lsn, 43);
+            remove_log(&path);
+        }
+    }
+
+    #[test]
+    fn group_commit_failure() {
+        // a flusher that can not write hands the error to everyone waiting
+        let path = temp_log("group_commit_failure");
+        fs::write(&path, b"").unwrap();
+        let group = Arc::new(GroupCommit {
+            queue: Mutex::new(GroupQueue {
+                pending: vec![GroupWrite::Record(b"record\n".to_vec())],
+                pending_count: 1,
+                oldest: Some(Instant::now()),
+                next_ticket: 1,
+                forced: 1,
+                durable: 0,
+                failed: None,
+                shutdown: false,
+            }),
+            work: Condvar::new(),
+            done: Condvar::new(),
+        });
+        let lf = File::open(&path).unwrap();
+        let shared = Arc::clone(&group);
+        let flusher = thread::spawn(move || group_flusher(shared, lf, 1, Duration::from_millis(1)));
+        let barrier = Barrier { group: Arc::clone(&group) };
+        assert!(barrier.wait(1).is_err());
+        assert_eq!(barrier.durable(), 0);
+        let handle = CommitHandle { lsn: 1, ticket: 1, group: Some(group) };
+        assert!(handle.wait().is_err());
+        flusher.join().unwrap();
+        remove_log(&path);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn append_reports_group_commit_failure() {
+        // every write to /dev/full fails with ENOSPC
+        let mut log_config = config(LogFormat::Json, 0);
+        log_config.durability = DurabilityPolicy::GroupCommit { max_batch: 1, max_delay: Duration::from_millis(1) };
+        let mut log = OpLog::new("/dev/full".to_string(), log_config);
+        assert!(log.append(MessageType::ParticipantVoteCommit, "tx_0".to_string(), "participant_0".to_string(), 0).is_err());
+        log.append_async(MessageType::ParticipantVoteCommit, "tx_1".to_string(), "participant_0".to_string(), 1);
+        assert!(log.sync().is_err());
+    }
+
+    #[test]
+    fn truncation_deletes_resolved_segments() {
+        let path = temp_log("truncation");
+        let mut log_config = config(LogFormat::Json, 256);
+        log_config.truncate = true;
+        let mut log = OpLog::new(path.clone(), log_config);
+        write_txns(&mut log, 30);
+
+        // the votes and decisions of resolved transactions straddle segment boundaries
+        let mut segments_of: HashMap<String, Vec<u32>> = HashMap::new();
+        for segment in list_segments(&path) {
+            scan_file(&segment_path(&path, segment), |record| {
+                segments_of.entry(record.msg.txid).or_default().push(segment);
+            }).unwrap();
+        }
+        assert!(segments_of.values().filter(|segments| segments.len() > 1).count() > 3);
+
+        // only the in-doubt transaction pins the segment of its oldest record
+        let last = *list_segments(&path).last().unwrap();
+        let pinned = segments_of["tx_29"][0];
+        assert!(pinned > 1 && pinned <= last);
+        let in_doubt = log.last_record_for_txid("tx_29").unwrap().msg;
+        log.checkpoint(&vec![in_doubt]).unwrap();
+        assert_eq!(list_segments(&path), (pinned..last + 1).collect::<Vec<u32>>());
+        assert_eq!(log.records_for_txid("tx_29").len(), 2);
+        assert!(log.records_for_txid("tx_0").is_empty());
+
+        // once it is resolved, nothing but the current segment is needed
+        log.append(MessageType::CoordinatorCommit, "tx_29".to_string(), "participant_0".to_string(), 29).unwrap();
+        for tx in 30..40 {
+            log.append(MessageType::ParticipantVoteAbort, format!("tx_{}", tx), "participant_0".to_string(), tx).unwrap();
+        }
+        let current = *list_segments(&path).last().unwrap();
+        assert!(current > last);
+        log.checkpoint(&Vec::new()).unwrap();
+        assert_eq!(list_segments(&path), vec![current]);
+        drop(log);
+
+        let log = OpLog::reopen(path.clone(), log_config);
+        assert_eq!(log.txids(), Vec::<String>::new());
+        drop(log);
+        remove_log(&path);
+    }
+
+    #[test]
+    fn read_by_lsn() {
+        let path = temp_log("read_by_lsn");
+        let mut log_config = config(LogFormat::Json, 256);
+        log_config.truncate = true;
+        let mut log = OpLog::new(path.clone(), log_config);
+        write_txns(&mut log, 20);
+        assert_eq!(log.read(1).unwrap().msg.txid, "tx_0");
+        assert_eq!(log.read(39).unwrap().msg.txid, "tx_19");
+        assert!(log.read(40).is_none());
+        log.checkpoint(&Vec::new()).unwrap();
+        assert!(log.read(1).is_none());
+        assert_eq!(log.read(log.lsn()).unwrap().msg.mtype, MessageType::CheckpointEnd);
+        drop(log);
+
+        let log = OpLog::reopen(path.clone(), log_config);
+        assert!(log.read(1).is_none());
+        assert_eq!(log.read(log.lsn()).unwrap().lsn, log.lsn());
+        drop(log);
+        remove_log(&path);
+    }
+}
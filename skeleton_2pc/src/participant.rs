@@ -11,23 +11,33 @@ use std::collections::HashMap;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::Duration;
+use std::time::Instant;
 use std::thread;
 
-use participant::rand::prelude::*;
 use participant::ipc_channel::ipc::IpcReceiver as Receiver;
+use participant::ipc_channel::ipc::IpcSender;
 use participant::ipc_channel::ipc::TryRecvError;
-use participant::ipc_channel::ipc::IpcSender as Sender;
+use transport::Sender;
+use transport::Transport;
 
 
+use crash;
+use crash::CrashPoint;
+use crash::CrashSpec;
 use message;
+use message::CommitProtocol;
 use message::MessageType;
+use message::OpKind;
+use message::Operation;
+use message::Presumption;
 use message::ProtocolMessage;
-use message::RequestStatus;
 use oplog;
+use seed;
+use stats::RunStats;
 
 ///
 /// ParticipantState
-/// enum for Participant 2PC state machine
+/// enum for Participant 2PC / 3PC state machine
 ///
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum ParticipantState {
@@ -35,7 +45,62 @@ pub enum ParticipantState {
     ReceivedP1,
     VotedAbort,
     VotedCommit,
+    VotedReadOnly,
     AwaitingGlobalDecision,
+    PreCommitted,
+    Terminating,
+}
+
+///
+/// Subtree
+/// Tree 2PC: what a sub-coordinator knows of one txid of its subtree
+///
+#[derive(Debug)]
+struct Subtree {
+    request: ProtocolMessage,               // the proposal, with this participant's own vote as mtype
+    votes: HashMap<String, MessageType>,    // child -> vote of its subtree
+    voted: bool,                            // the subtree's vote went up
+    decision: Option<ProtocolMessage>,      // the decision, once passed down
+    pending: Vec<String>,                   // children yet to ack the decision
+    timer: Instant,                         // proposal passed down
+}
+
+///
+/// ParticipantConfig
+/// How a participant runs: its log, the protocol and its timeouts, its place
+/// in the tree, how it votes and where it crashes
+///
+#[derive(Clone, Debug)]
+pub struct ParticipantConfig {
+    pub id_str: String,
+    pub log_path: String,
+    pub recover: bool,
+    pub log_config: oplog::LogConfig,
+    pub protocol: CommitProtocol,
+    pub presumption: Presumption,
+    pub decision_timeout: Duration,
+    pub operation_success_prob: f64,
+    pub seed: u64,
+    pub parent: Option<String>,
+    pub children: Vec<String>,
+    pub crash: Option<CrashSpec>,
+    pub restarted: bool,
+}
+
+///
+/// ParticipantLinks
+/// The channels of a participant: to and from the coordinator, to and from
+/// its peers, the peers restarted later, and to the acceptors
+///
+#[derive(Debug)]
+pub struct ParticipantLinks {
+    pub tx: Sender,
+    pub rx: Receiver<message::ProtocolMessage>,
+    pub peers: HashMap<String, Sender>,
+    pub peer_rx: Receiver<message::ProtocolMessage>,
+    pub peer_updates: Receiver<Vec<(String, IpcSender<ProtocolMessage>)>>,
+    pub transport: Arc<Transport>,
+    pub acceptors: Vec<Sender>,
 }
 
 ///
@@ -48,10 +113,29 @@ pub struct Participant {
     state: ParticipantState,
     log: oplog::OpLog,
     running: Arc<AtomicBool>,
-    send_success_prob: f64,
     operation_success_prob: f64,
-	tx : Sender<message::ProtocolMessage>,
+    seed: u64,
+	tx : Sender,
 	rx : Receiver<message::ProtocolMessage>,
+	awaiting: HashMap<String, ProtocolMessage>,
+	recovering: bool,
+	protocol: CommitProtocol,
+	presumption: Presumption,
+	decision_timeout: Duration,
+	last_heard: Instant,
+	peers: HashMap<String, Sender>,
+	peer_rx: Receiver<message::ProtocolMessage>,
+	peer_updates: Receiver<Vec<(String, IpcSender<ProtocolMessage>)>>,
+	transport: Arc<Transport>,
+	acceptors: Vec<Sender>,
+	parent: Option<String>,
+	children: Vec<String>,
+	subtrees: HashMap<String, Subtree>,
+	crash: Option<CrashSpec>,
+	crash_txid: Option<String>,
+	restarted: bool,
+	stats: RunStats,
+	store: HashMap<u32, u64>,
 	successful_ops: u64,
     failed_ops: u64,
     unknown_ops: u64,
@@ -79,25 +163,57 @@ impl Participant {
     ///       the protocol is still running to this constructor. There are other
     ///       ways to communicate this, of course.
     ///
-    pub fn new(
-        id_str: String,
-        log_path: String,
-        r: Arc<AtomicBool>,
-        send_success_prob: f64,
-        operation_success_prob: f64,
-		sender: Sender<message::ProtocolMessage>,
-		recvr: Receiver<message::ProtocolMessage>
-		) -> Participant {
+    /// With config.recover set, the existing log is replayed instead of
+    /// truncated: every txid voted commit without a global decision is in
+    /// doubt and is resolved before any new proposal is taken, with the
+    /// coordinator in 2PC and Paxos Commit and with the peers in 3PC. In
+    /// Paxos Commit the votes go to the acceptors instead of the coordinator.
+    /// In tree 2PC the protocol runs with the parent, a peer, unless it is
+    /// None, and the participant is the sub-coordinator of its children. A
+    /// participant that run mode restarted after a crash (restarted set) asks
+    /// the coordinator, which is up, about its in-doubt txids in every
+    /// protocol, and takes new proposals meanwhile. Peers restarted in turn
+    /// come in on links.peer_updates, and are reached through
+    /// links.transport. Its votes are drawn from seed, for each txid.
+    ///
+    pub fn new(config: ParticipantConfig, r: Arc<AtomicBool>, links: ParticipantLinks) -> Participant {
+
+        if !config.recover {
+            crash::clear(&config.log_path);
+        }
+        let log = if config.recover { oplog::OpLog::reopen(config.log_path, config.log_config) } else { oplog::OpLog::new(config.log_path, config.log_config) };
+        let in_doubt = Participant::in_doubt(&log);
+        // records are appended without waiting; the messages that depend on them wait instead
+        links.transport.hold_for(log.barrier());
 
         Participant {
-            id_str: id_str,
+            id_str: config.id_str,
             state: ParticipantState::Quiescent,
-            log: oplog::OpLog::new(log_path),
+            log,
             running: r,
-            send_success_prob: send_success_prob,
-            operation_success_prob: operation_success_prob,
-			tx: sender,
-			rx: recvr,
+            operation_success_prob: config.operation_success_prob,
+            seed: config.seed,
+			tx: links.tx,
+			rx: links.rx,
+			awaiting: in_doubt,
+			recovering: config.recover,
+			protocol: config.protocol,
+			presumption: config.presumption,
+			decision_timeout: config.decision_timeout,
+			last_heard: Instant::now(),
+			peers: links.peers,
+			peer_rx: links.peer_rx,
+			peer_updates: links.peer_updates,
+			transport: links.transport,
+			acceptors: links.acceptors,
+			parent: config.parent,
+			children: config.children,
+			subtrees: HashMap::new(),
+			crash: config.crash,
+			crash_txid: None,
+			restarted: config.restarted,
+			stats: RunStats::new(config.protocol, config.presumption),
+			store: HashMap::new(),
 			successful_ops: 0,
 			failed_ops: 0,
 			unknown_ops: 0,
@@ -106,26 +222,146 @@ impl Participant {
     }
 
     ///
-    /// send()
-    /// Send a protocol message to the coordinator. This can fail depending on
-    /// the success probability. For testing purposes, make sure to not specify
-    /// the -S flag so the default value of 1 is used for failproof sending.
+    /// in_doubt()
+    /// Replay a log and return the votes to commit (and 3PC PreCommits) with
+    /// no global decision
     ///
-    /// HINT: You will need to implement the actual sending
+    fn in_doubt(log: &oplog::OpLog) -> HashMap<String, ProtocolMessage> {
+        let mut in_doubt = HashMap::new();
+        for txid in log.txids() {
+            let record = log.last_record_for_txid(&txid).unwrap();
+            match record.msg.mtype {
+                MessageType::ParticipantVoteCommit | MessageType::CoordinatorPreCommit => { in_doubt.insert(txid, record.msg); },
+                _ => {},
+            }
+        }
+        in_doubt
+    }
+
+    ///
+    /// send()
+    /// Send a protocol message to the coordinator. The transport drops it
+    /// depending on the send success probability (-S); a dropped message is
+    /// not retried.
     ///
     pub fn send(&mut self, pm: ProtocolMessage) {
-        let x: f64 = random();
-      //  if x <= self.send_success_prob {
-            // TODO: Send success
-			self.tx.send(pm).unwrap();
-		
-			
-      //  } 
-		//else {
-            // just do not retry
-			//println!("comm faile on p{}", self.id_str.clone());
-			
-      //  }
+		self.stats.messages += 1;
+		if self.tx.send(pm).is_err() {
+			// a crashed coordinator; the peers settle without it
+			debug!("{}::Coordinator is gone", self.id_str.clone());
+		}
+    }
+
+    ///
+    /// send_up()
+    /// Send a protocol message to the coordinator, or in tree 2PC to the
+    /// parent, with this participant as the sender
+    ///
+    fn send_up(&mut self, pm: ProtocolMessage) {
+		let parent = match self.parent.clone() {
+			Some(parent) => parent,
+			None => {
+				self.send(pm);
+				return;
+			},
+		};
+		let mut msg = pm;
+		msg.senderid = self.id_str.clone();
+		self.stats.messages += 1;
+		if self.peers[&parent].send(msg).is_err() {
+			debug!("{}::Parent {} is gone", self.id_str.clone(), parent);
+		}
+    }
+
+    ///
+    /// send_children()
+    /// Tree 2PC: send a message from the parent on to some of the children
+    ///
+    fn send_children(&mut self, pm: &ProtocolMessage, to: &Vec<String>) {
+		for id in to {
+			if self.peers[id].send(pm.clone()).is_err() {
+				debug!("{}::Child {} is gone", self.id_str.clone(), id);
+			}
+		}
+		self.stats.messages += to.len() as u64;
+    }
+
+    ///
+    /// flush()
+    /// Send the messages the transport held back for the log, once the
+    /// records they wait for are durable, after waiting for that if wait is
+    /// set. Before waiting for an answer, it has to be waited for. A log
+    /// that can not be written is fatal.
+    ///
+    fn flush(&self, wait: bool) {
+		self.tx.flush(wait).unwrap();
+		for tx in self.peers.values() {
+			tx.flush(wait).unwrap();
+		}
+		for tx in &self.acceptors {
+			tx.flush(wait).unwrap();
+		}
+    }
+
+    ///
+    /// in_tree()
+    /// True in tree 2PC unless every participant is a child of the coordinator
+    ///
+    fn in_tree(&self) -> bool {
+		self.parent.is_some() || !self.children.is_empty()
+    }
+
+    ///
+    /// crash_point()
+    /// Crash here if this is the point and the transaction to crash at.
+    /// What was logged is durable by then, and what was sent went out.
+    ///
+    fn crash_point(&self, point: CrashPoint, txid: &String) {
+		if let Some(ref crash) = self.crash {
+			if crash.point == point && self.crash_txid.as_ref() == Some(txid) {
+				match point {
+					CrashPoint::AfterVoteSend => self.flush(true),
+					_ => self.log.sync().unwrap(),
+				}
+				crash::crash(crash, txid, self.log.path());
+			}
+		}
+    }
+
+    ///
+    /// update_peers()
+    /// Reach peers that run mode restarted through their new senders
+    ///
+    fn update_peers(&mut self) {
+		while let Ok(update) = self.peer_updates.try_recv() {
+			for (name, tx) in update {
+				info!("{}::Peer {} restarted", self.id_str.clone(), name);
+				let sender = Transport::sender(&self.transport, tx, format!("participant_{}", name));
+				self.peers.insert(name, sender);
+			}
+		}
+    }
+
+    ///
+    /// send_vote()
+    /// Send a vote up, or in Paxos Commit to every acceptor: there it is the
+    /// ballot 0 proposal for this participant's instance of the txid, which
+    /// only this participant may make
+    ///
+    fn send_vote(&mut self, vote: ProtocolMessage) {
+		if self.protocol != CommitProtocol::PaxosCommit {
+			self.send_up(vote);
+			return;
+		}
+		let mut proposal = vote;
+		proposal.senderid = self.id_str.clone();
+		proposal.opid = 0;
+		for tx in &self.acceptors {
+			if tx.send(proposal.clone()).is_err() {
+				debug!("{}::Acceptor is gone", self.id_str.clone());
+			}
+		}
+		self.stats.messages += self.acceptors.len() as u64;
     }
 
     ///
@@ -140,10 +376,16 @@ impl Participant {
     ///       (it's ok to add parameters or return something other than
     ///       bool if it's more convenient for your design).
     ///
+    /// Reads of this participant's keys are done on the spot, writes are
+    /// applied once the transaction commits. Returns true if the request
+    /// writes any of its keys. Whether the operation succeeds is drawn for
+    /// the txid, so every run with the same seed votes the same on it.
+    ///
     pub fn perform_operation(&mut self, request_option: &Option<ProtocolMessage>) -> bool {
 
         trace!("{}::Performing operation", self.id_str.clone());
-        let x: f64 = random();
+        let txid = request_option.as_ref().map_or("", |request| request.txid.as_str());
+        let x: f64 = seed::draw(self.seed, &format!("participant_{}/{}", self.id_str, txid));
         if x <= self.operation_success_prob {
             // TODO: Successful operation
 			self.successful_ops+=1;
@@ -154,7 +396,39 @@ impl Participant {
 			self.state=ParticipantState::VotedAbort;
         }
 
-        true
+		let mut writes = false;
+		if let Some(ref request) = *request_option {
+			for op in self.own_ops(request) {
+				match op.kind {
+					OpKind::Read => trace!("{}::Read key {}: {}", self.id_str.clone(), op.key, self.store.get(&op.key).cloned().unwrap_or(0)),
+					OpKind::Write => writes = true,
+				}
+			}
+		}
+		writes
+    }
+
+    ///
+    /// own_ops()
+    /// The operations of a request on keys this participant owns
+    ///
+    fn own_ops(&self, request: &ProtocolMessage) -> Vec<Operation> {
+		let num_participants = self.peers.len() as u32 + 1;
+		let id: u32 = self.id_str.parse().unwrap();
+		request.ops.iter().filter(|op| op.owner(num_participants) == id).cloned().collect()
+    }
+
+    ///
+    /// apply_writes()
+    /// Apply the writes of a committed transaction to this participant's
+    /// keys; a key's value counts the committed writes to it
+    ///
+    fn apply_writes(&mut self, request: &ProtocolMessage) {
+		for op in self.own_ops(request) {
+			if op.kind == OpKind::Write {
+				*self.store.entry(op.key).or_insert(0) += 1;
+			}
+		}
     }
 
     ///
@@ -169,6 +443,11 @@ impl Participant {
         let unknown_ops: u64 = self.unknown_ops;
 
         println!("{:16}:\tCommitted: {:6}\tAborted: {:6}\tUnknown: {:6}", format!("particpant_{}",self.id_str.clone()), successful_ops, failed_ops, unknown_ops);
+
+        let (forced_writes, lazy_writes) = self.log.writes();
+        self.stats.forced_writes = forced_writes;
+        self.stats.lazy_writes = lazy_writes;
+        self.stats.write(&RunStats::path_for(self.log.path()));
     }
 
     ///
@@ -178,80 +457,1006 @@ impl Participant {
     pub fn wait_for_exit_signal(&mut self) {
         trace!("{}::Waiting for exit signal", self.id_str.clone());
 
-        // TODO
+        while self.running.load(Ordering::SeqCst) {
+            thread::sleep(Duration::from_millis(1));
+        }
 
         trace!("{}::Exiting", self.id_str.clone());
     }
 
     ///
-    /// protocol()
-    /// Implements the participant side of the 2PC protocol
-    /// HINT: If the simulation ends early, don't keep handling requests!
-    /// HINT: Wait for some kind of exit signal before returning from the protocol!
+    /// vote()
+    /// Perform the proposed operation and vote on it. The vote is durable in
+    /// the log before it is sent (write-ahead), and the txid is remembered until its decision
+    /// arrives. With presumed abort an abort vote is not forced: without it
+    /// the txid is aborted on recovery all the same. A participant that
+    /// only reads votes read-only: whatever the outcome, it has nothing to
+    /// undo or redo, so it forgets the txid right away and the vote is not
+    /// forced either. A sub-coordinator votes for its whole subtree, once
+    /// its children have. A proposal of a txid already on record (delivered
+    /// twice, or late, after its decision) is not voted on again. The
+    /// transaction set to crash in is the one with that number among those
+    /// voted on.
     ///
-    pub fn protocol(&mut self) {
+    fn vote(&mut self, mut request: ProtocolMessage) {
+		if let Some(vote) = self.awaiting.get(&request.txid).cloned() {
+			// already voted, e.g. aborted for a peer that was terminating
+			self.send_vote(vote);
+			return;
+		}
+		if self.subtrees.contains_key(&request.txid) {
+			return;
+		}
+		if let Some(state) = self.log.last_state_for_txid(&request.txid) {
+			debug!("{}::Ignoring proposal for {}, already {:?}", self.id_str.clone(), request.txid, state);
+			return;
+		}
+		self.state=ParticipantState::ReceivedP1;
+		self.stats.transactions += 1;
+		if self.crash.as_ref().is_some_and(|crash| crash.tx as u64 == self.stats.transactions) {
+			self.crash_txid = Some(request.txid.clone());
+		}
+		let optional = Some(request.clone());
+		let writes = self.perform_operation(&optional); //vote commit or not
 
-        trace!("{}::Beginning protocol", self.id_str.clone());
-		let mut kill: bool;
-        // voting
-		//let mut counter = 0;
-		loop{
-			//recvr
-			match self.rx.recv() {
-				Ok(res) => { 
-					self.state=ParticipantState::ReceivedP1;
-					let mut request = res.clone();
-					if request.mtype==MessageType::CoordinatorExit &&  request.txid == "done" {
-						trace!("{}::Exiting", self.id_str.clone());
-						//println!("exit!");
-						break;
+		match self.state{
+			ParticipantState::VotedCommit if !writes => {
+				request.mtype = MessageType::ParticipantVoteReadOnly;
+				self.state=ParticipantState::VotedReadOnly;
+			},
+			ParticipantState::VotedCommit => {
+				request.mtype = MessageType::ParticipantVoteCommit;
+			},
+			_ => {
+				request.mtype = MessageType::ParticipantVoteAbort;
+				self.state=ParticipantState::VotedAbort;
+			}
+		}
+		if !self.children.is_empty() {
+			self.propose_down(request);
+			return;
+		}
+		self.cast_vote(request);
+    }
+
+    ///
+    /// cast_vote()
+    /// Log a vote by the rules of vote() and send it up. The participant
+    /// may crash between the two, or right after sending.
+    ///
+    fn cast_vote(&mut self, request: ProtocolMessage) {
+		let txid = request.txid.clone();
+		if request.mtype == MessageType::ParticipantVoteReadOnly {
+			self.log.append_lazy( request.mtype, request.txid.clone(), request.senderid.clone(), request.opid);
+			self.crash_point(CrashPoint::AfterVoteLog, &txid);
+			self.send_vote(request);
+			self.crash_point(CrashPoint::AfterVoteSend, &txid);
+			self.state = ParticipantState::Quiescent;
+			return;
+		}
+		if self.presumption == Presumption::Abort && request.mtype == MessageType::ParticipantVoteAbort {
+			self.log.append_lazy( request.mtype, request.txid.clone(), request.senderid.clone(), request.opid);
+		} else {
+			self.log.append_async( request.mtype, request.txid.clone(), request.senderid.clone(), request.opid);
+		}
+		self.awaiting.insert(request.txid.clone(), request.clone());
+		self.crash_point(CrashPoint::AfterVoteLog, &txid);
+		self.send_vote(request);
+		self.crash_point(CrashPoint::AfterVoteSend, &txid);
+		self.state = ParticipantState::AwaitingGlobalDecision;
+    }
+
+    ///
+    /// propose_down()
+    /// Tree 2PC: a sub-coordinator passes the proposal on to its children
+    /// and holds its own vote back until theirs are in
+    ///
+    fn propose_down(&mut self, vote: ProtocolMessage) {
+		let mut proposal = vote.clone();
+		proposal.mtype = MessageType::CoordinatorPropose;
+		let children = self.children.clone();
+		self.send_children(&proposal, &children);
+		self.subtrees.insert(vote.txid.clone(), Subtree {
+			request: vote,
+			votes: HashMap::new(),
+			voted: false,
+			decision: None,
+			pending: Vec::new(),
+			timer: Instant::now(),
+		});
+    }
+
+    ///
+    /// child_vote()
+    /// Tree 2PC: note the vote of a child's subtree. An abort is the
+    /// subtree's vote right away. Votes that come after the subtree voted
+    /// (they timed out, or came after an abort) are dropped.
+    ///
+    fn child_vote(&mut self, vote: ProtocolMessage) {
+		let all_in = match self.subtrees.get_mut(&vote.txid) {
+			Some(ref mut subtree) if !subtree.voted => {
+				subtree.votes.insert(vote.senderid.clone(), vote.mtype);
+				vote.mtype == MessageType::ParticipantVoteAbort || subtree.votes.len() == self.children.len()
+			},
+			_ => {
+				debug!("{}::Late {:?} for {} from child {}", self.id_str.clone(), vote.mtype, vote.txid, vote.senderid);
+				false
+			},
+		};
+		if all_in {
+			self.subtree_voted(&vote.txid);
+		}
+    }
+
+    ///
+    /// subtree_voted()
+    /// Tree 2PC: every child voted, or the missing votes timed out and count
+    /// as aborts. The subtree votes abort if anyone in it did, read-only if
+    /// everyone in it only read (nothing comes back down then), and commit
+    /// otherwise.
+    ///
+    fn subtree_voted(&mut self, txid: &String) {
+		let vote = {
+			let subtree = self.subtrees.get_mut(txid).unwrap();
+			subtree.voted = true;
+			let mut votes: Vec<MessageType> = subtree.votes.values().cloned().collect();
+			votes.push(subtree.request.mtype);
+			let mut vote = subtree.request.clone();
+			vote.mtype = if votes.contains(&MessageType::ParticipantVoteAbort) {
+				MessageType::ParticipantVoteAbort
+			} else if votes.iter().all(|v| *v == MessageType::ParticipantVoteReadOnly) {
+				MessageType::ParticipantVoteReadOnly
+			} else {
+				MessageType::ParticipantVoteCommit
+			};
+			vote
+		};
+		if vote.mtype == MessageType::ParticipantVoteReadOnly {
+			self.subtrees.remove(txid);
+		}
+		self.cast_vote(vote);
+    }
+
+    ///
+    /// check_subtrees()
+    /// Tree 2PC: children that have not voted within the decision timeout
+    /// are taken to vote abort
+    ///
+    fn check_subtrees(&mut self) {
+		let mut expired: Vec<String> = Vec::new();
+		for (txid, subtree) in self.subtrees.iter_mut() {
+			if subtree.voted || subtree.timer.elapsed() < self.decision_timeout {
+				continue;
+			}
+			for id in &self.children {
+				subtree.votes.entry(id.clone()).or_insert(MessageType::ParticipantVoteAbort);
+			}
+			expired.push(txid.clone());
+		}
+		for txid in expired {
+			info!("{}::Children did not vote on {}", self.id_str.clone(), txid);
+			self.subtree_voted(&txid);
+		}
+    }
+
+    ///
+    /// pass_down()
+    /// Tree 2PC: pass a decision on to the children that did not vote
+    /// read-only. Returns true if they have to ack it before this
+    /// participant acks it up; a re-sent decision goes to the ones that
+    /// have not acked yet.
+    ///
+    fn pass_down(&mut self, decision: &ProtocolMessage) -> bool {
+		let explicit = self.presumption.explicit(decision.mtype);
+		let to = match self.subtrees.get_mut(&decision.txid) {
+			Some(ref mut subtree) if subtree.decision.is_none() => {
+				let mut to: Vec<String> = self.children.iter()
+					.filter(|id| subtree.votes.get(*id) != Some(&MessageType::ParticipantVoteReadOnly))
+					.cloned()
+					.collect();
+				to.sort();
+				subtree.decision = Some(decision.clone());
+				subtree.voted = true;
+				if explicit {
+					subtree.pending = to.clone();
+				}
+				to
+			},
+			Some(ref subtree) => subtree.pending.clone(),
+			None => return false,
+		};
+		self.send_children(decision, &to);
+		let relayed = explicit && !to.is_empty();
+		if !relayed {
+			self.subtrees.remove(&decision.txid);
+		}
+		relayed
+    }
+
+    ///
+    /// child_ack()
+    /// Tree 2PC: once every child it went to acked the decision of txid,
+    /// ack it up
+    ///
+    fn child_ack(&mut self, ack: ProtocolMessage) {
+		let done = match self.subtrees.get_mut(&ack.txid) {
+			Some(ref mut subtree) if subtree.decision.is_some() => {
+				subtree.pending.retain(|id| *id != ack.senderid);
+				subtree.pending.is_empty()
+			},
+			_ => false,
+		};
+		if done {
+			self.subtrees.remove(&ack.txid);
+			self.send_up(ack);
+		}
+    }
+
+    ///
+    /// decide()
+    /// Log a global decision for a txid this participant voted on. Decisions
+    /// for txids it is not waiting on (e.g. re-sent by a recovered
+    /// coordinator) are ignored, except an abort of a txid it never heard
+    /// of: the abort overtook the proposal, which is not to be voted on
+    /// when it turns up. Only a decision against the presumption is
+    /// forced; the presumed one is not needed to recover. A commit applies
+    /// the txid's writes. Checkpoints are taken here, between transactions,
+    /// with the votes still waiting for a decision in flight. The
+    /// participant may crash once the decision is logged, before it is
+    /// acknowledged.
+    ///
+    fn decide(&mut self, decision: ProtocolMessage) {
+		let vote = match self.awaiting.remove(&decision.txid) {
+			Some(vote) => vote,
+			None => {
+				if decision.mtype == MessageType::CoordinatorAbort && self.log.last_state_for_txid(&decision.txid).is_none() {
+					debug!("{}::Abort of {} before its proposal", self.id_str.clone(), decision.txid);
+					self.log.append_lazy( decision.mtype, decision.txid.clone(), decision.senderid.clone(), decision.opid);
+				} else {
+					debug!("{}::Ignoring decision for {}", self.id_str.clone(), decision.txid);
+				}
+				return;
+			},
+		};
+		if decision.mtype == MessageType::CoordinatorCommit {
+			self.apply_writes(&vote);
+		}
+		if self.presumption.explicit(decision.mtype) {
+			self.log.append_async( decision.mtype, decision.txid.clone(), decision.senderid.clone(), decision.opid);
+		} else {
+			self.log.append_lazy( decision.mtype, decision.txid.clone(), decision.senderid.clone(), decision.opid);
+		}
+		self.crash_point(CrashPoint::AfterDecisionLog, &decision.txid);
+		self.state = ParticipantState::Quiescent;
+		if self.log.checkpoint_due() {
+			let in_flight: Vec<ProtocolMessage> = self.awaiting.values().cloned().collect();
+			self.log.checkpoint(&in_flight).unwrap();
+		}
+    }
+
+    ///
+    /// receive_decision()
+    /// Decide a txid on the coordinator's word, and acknowledge the decision
+    /// if it goes against the presumption: the coordinator may only forget
+    /// such a txid once every participant has it on record. A decision that
+    /// was ignored is acknowledged all the same: the coordinator re-sends it
+    /// because an earlier ack got lost, or to a participant that only read
+    /// or never voted, which has nothing to record. In tree 2PC a decision
+    /// that came down the tree is passed on to the children and acked up
+    /// once they have acked it; one straight from the coordinator (during
+    /// recovery) is acked to the coordinator.
+    ///
+    fn receive_decision(&mut self, decision: ProtocolMessage, down_the_tree: bool) {
+		let explicit = self.presumption.explicit(decision.mtype);
+		let mut ack = decision.clone();
+		ack.mtype = MessageType::ParticipantAck;
+		ack.senderid = self.id_str.clone();
+		self.decide(decision.clone());
+		if !down_the_tree {
+			if explicit {
+				self.send(ack);
+			}
+			return;
+		}
+		if !self.pass_down(&decision) && explicit {
+			self.send_up(ack);
+		}
+    }
+
+    ///
+    /// precommit()
+    /// 3PC: log the PreCommit of a txid this participant voted commit on and
+    /// acknowledge it. From here on the termination protocol commits it.
+    ///
+    fn precommit(&mut self, pre: ProtocolMessage) {
+		match self.awaiting.get(&pre.txid) {
+			Some(vote) if vote.mtype == MessageType::ParticipantVoteCommit => {},
+			_ => {
+				debug!("{}::Ignoring PreCommit for {}", self.id_str.clone(), pre.txid);
+				return;
+			},
+		}
+		self.log.append_async( pre.mtype, pre.txid.clone(), pre.senderid.clone(), pre.opid);
+		self.awaiting.insert(pre.txid.clone(), pre.clone());
+		self.state = ParticipantState::PreCommitted;
+		let mut ack = pre;
+		ack.mtype = MessageType::ParticipantAckPreCommit;
+		self.send(ack);
+    }
+
+    ///
+    /// state_of()
+    /// This participant's state of txid, as told to a terminating peer. A
+    /// txid it has not voted on is aborted on the spot and stays aborted if
+    /// its proposal still turns up.
+    ///
+    fn state_of(&mut self, txid: &String) -> MessageType {
+		if let Some(pm) = self.awaiting.get(txid) {
+			return pm.mtype;
+		}
+		match self.log.last_state_for_txid(txid) {
+			Some(state) => state,
+			None => {
+				info!("{}::Aborting {} for a terminating peer before voting on it", self.id_str.clone(), txid);
+				let vote = ProtocolMessage::generate(MessageType::ParticipantVoteAbort, txid.clone(), self.id_str.clone(), 0, 0);
+				self.stats.transactions += 1;
+				self.log.append_async( vote.mtype, vote.txid.clone(), vote.senderid.clone(), vote.opid);
+				self.awaiting.insert(txid.clone(), vote);
+				MessageType::ParticipantVoteAbort
+			},
+		}
+    }
+
+    ///
+    /// serve_peers()
+    /// Answer the state requests of terminating peers. Replies that arrive
+    /// after their termination round gave up on them are dropped. In tree
+    /// 2PC the proposals, decisions and exit of the parent come in here, as
+    /// do the votes and acks of the children. Returns true once the parent
+    /// said to exit.
+    ///
+    fn serve_peers(&mut self) -> bool {
+		self.update_peers();
+		loop {
+			let request = match self.peer_rx.try_recv() {
+				Ok(pm) => pm,
+				Err(_) => return false,
+			};
+			let from_parent = self.parent.is_some();
+			let from_children = !self.children.is_empty();
+			match request.mtype {
+				MessageType::ParticipantStateRequest => self.answer_state_request(request),
+				MessageType::CoordinatorPropose if from_parent => {
+					self.last_heard = Instant::now();
+					self.vote(request);
+				},
+				MessageType::CoordinatorCommit | MessageType::CoordinatorAbort if from_parent => {
+					self.last_heard = Instant::now();
+					self.receive_decision(request, true);
+				},
+				MessageType::CoordinatorExit if from_parent => return true,
+				MessageType::ParticipantVoteCommit | MessageType::ParticipantVoteAbort | MessageType::ParticipantVoteReadOnly if from_children => self.child_vote(request),
+				MessageType::ParticipantAck if from_children => self.child_ack(request),
+				_ => debug!("{}::Dropping late {:?} for {} from peer {}", self.id_str.clone(), request.mtype, request.txid, request.senderid),
+			}
+		}
+    }
+
+    ///
+    /// answer_state_request()
+    /// Tell a terminating peer this participant's state of a txid
+    ///
+    fn answer_state_request(&mut self, request: ProtocolMessage) {
+		let mut reply = request;
+		reply.mtype = self.state_of(&reply.txid);
+		let peer = reply.senderid.clone();
+		reply.senderid = self.id_str.clone();
+		match self.peers.get(&peer) {
+			Some(tx) => {
+				self.stats.messages += 1;
+				if tx.send(reply).is_err() {
+					debug!("{}::Peer {} is gone", self.id_str.clone(), peer);
+				}
+			},
+			None => warn!("{}::State request from unknown peer {}", self.id_str.clone(), peer),
+		}
+    }
+
+    ///
+    /// ask_peers()
+    /// Collect the peers' states of txid. Peers that do not answer within
+    /// the decision timeout are taken to have failed. State requests of
+    /// other peers are answered while waiting, so peers that terminate at
+    /// the same time do not wait on each other.
+    ///
+    fn ask_peers(&mut self, txid: &String) -> Vec<MessageType> {
+		self.update_peers();
+		let request = ProtocolMessage::generate(MessageType::ParticipantStateRequest, txid.clone(), self.id_str.clone(), 0, 0);
+		let mut asked = 0;
+		for tx in self.peers.values() {
+			if tx.send(request.clone()).is_ok() {
+				asked += 1;
+			}
+		}
+		self.stats.messages += self.peers.len() as u64;
+		self.flush(true);
+		let mut states: HashMap<String, MessageType> = HashMap::new();
+		let timer = Instant::now();
+		while states.len() < asked && timer.elapsed() < self.decision_timeout {
+			match self.peer_rx.try_recv() {
+				Ok(pm) => {
+					if pm.mtype == MessageType::ParticipantStateRequest {
+						self.answer_state_request(pm);
+						self.flush(true);
+					} else if pm.txid == *txid {
+						states.insert(pm.senderid.clone(), pm.mtype);
 					}
-					let optional = None;
-					self.perform_operation(&optional); //vote commit or not
-					
-					match self.state{
-						ParticipantState::VotedCommit => {
-							request.mtype = MessageType::ParticipantVoteCommit;
-							self.state = ParticipantState::VotedCommit;
+				},
+				Err(TryRecvError::Empty) => thread::sleep(Duration::from_millis(1)),
+				Err(_) => break,
+			}
+		}
+		if states.len() < self.peers.len() {
+			info!("{}::{} of {} peers answered about {}", self.id_str.clone(), states.len(), self.peers.len(), txid);
+		}
+		states.values().cloned().collect()
+    }
+
+    ///
+    /// terminate()
+    /// 3PC termination protocol, run when the coordinator has been silent for
+    /// the decision timeout or is gone. Every txid still awaiting a decision
+    /// commits if this participant or a peer is PreCommitted or committed,
+    /// and aborts otherwise: someone voted abort or never voted, or everyone
+    /// is uncertain, in which case the coordinator can not have committed.
+    /// Like 3PC itself this assumes the timeout only expires on failures.
+    ///
+    fn terminate(&mut self) {
+		self.state = ParticipantState::Terminating;
+		let mut txids: Vec<String> = self.awaiting.keys().cloned().collect();
+		txids.sort();
+		for txid in txids {
+			let own = match self.awaiting.get(&txid) {
+				Some(pm) => pm.clone(),
+				None => continue,
+			};
+			let mut states = vec![own.mtype];
+			if own.mtype != MessageType::ParticipantVoteAbort {
+				states.extend(self.ask_peers(&txid));
+			}
+			let commit = states.iter().any(|s| *s == MessageType::CoordinatorPreCommit || *s == MessageType::CoordinatorCommit);
+			let mut decision = own;
+			decision.mtype = if commit { MessageType::CoordinatorCommit } else { MessageType::CoordinatorAbort };
+			info!("{}::Terminated {} with {:?}", self.id_str.clone(), txid, decision.mtype);
+			self.decide(decision);
+		}
+		self.state = ParticipantState::Quiescent;
+		self.last_heard = Instant::now();
+    }
+
+    ///
+    /// cooperate()
+    /// 2PC cooperative termination, run when the coordinator has been silent
+    /// for the decision timeout or is gone. A txid this participant voted
+    /// abort on is aborted. For a vote to commit the peers are asked: one
+    /// that has the decision, voted abort, or had not voted yet (and aborts
+    /// now) settles it. If every peer that answers is uncertain as well, the
+    /// txid stays blocked until the coordinator is back; this is the
+    /// blocking window of 2PC.
+    ///
+    fn cooperate(&mut self) {
+		self.state = ParticipantState::Terminating;
+		let mut txids: Vec<String> = self.awaiting.keys().cloned().collect();
+		txids.sort();
+		let mut blocked = 0;
+		for txid in txids {
+			let own = match self.awaiting.get(&txid) {
+				Some(pm) => pm.clone(),
+				None => continue,
+			};
+			let mut states = vec![own.mtype];
+			if own.mtype != MessageType::ParticipantVoteAbort {
+				states.extend(self.ask_peers(&txid));
+			}
+			let commit = states.contains(&MessageType::CoordinatorCommit);
+			let abort = states.iter().any(|s| *s == MessageType::CoordinatorAbort || *s == MessageType::ParticipantVoteAbort);
+			if !commit && !abort {
+				blocked += 1;
+				continue;
+			}
+			let mut decision = own;
+			decision.mtype = if commit { MessageType::CoordinatorCommit } else { MessageType::CoordinatorAbort };
+			info!("{}::Terminated {} with {:?} by asking peers", self.id_str.clone(), txid, decision.mtype);
+			self.decide(decision);
+		}
+		if blocked > 0 {
+			info!("{}::{} transactions blocked until the coordinator is back", self.id_str.clone(), blocked);
+			self.state = ParticipantState::AwaitingGlobalDecision;
+		} else {
+			self.state = ParticipantState::Quiescent;
+		}
+		self.last_heard = Instant::now();
+    }
+
+    ///
+    /// settle_with_peers()
+    /// Settle what is awaiting a decision without the coordinator: with the
+    /// termination protocol in 3PC, cooperatively in 2PC
+    ///
+    fn settle_with_peers(&mut self) {
+		if self.protocol == CommitProtocol::ThreePhase {
+			self.terminate();
+		} else {
+			self.cooperate();
+		}
+    }
+
+    ///
+    /// linger()
+    /// Keep answering peers for one decision timeout after the
+    /// coordinator is gone, so that peers still terminating hear from us.
+    /// In tree 2PC the parent may still pass a decision down, and its exit
+    /// ends the wait.
+    ///
+    fn linger(&mut self) {
+		let timer = Instant::now();
+		while timer.elapsed() < self.decision_timeout {
+			if self.serve_peers() {
+				return;
+			}
+			self.flush(true);
+			thread::sleep(Duration::from_millis(1));
+		}
+    }
+
+    ///
+    /// resolve_in_doubt()
+    /// Settle every in-doubt txid. In 2PC, ask the coordinator for the
+    /// outcome of each and wait until all of them are decided (in Paxos
+    /// Commit the coordinator learns it from the acceptors); in 3PC, run
+    /// the termination protocol with the peers. Either way the coordinator
+    /// gets a closing "done" request so it knows this participant has caught
+    /// up. A restarted participant asks the coordinator in 3PC too, and
+    /// leaves the answers to poll(): the coordinator is busy with new
+    /// transactions, which may come first.
+    ///
+    pub fn resolve_in_doubt(&mut self) {
+        info!("{}::Resolving {} in-doubt transactions", self.id_str.clone(), self.awaiting.len());
+        if self.protocol == CommitProtocol::ThreePhase && !self.restarted {
+            let pm = ProtocolMessage::generate(MessageType::ParticipantDecisionRequest, "done".to_string(), self.id_str.clone(), 0, 0);
+            self.send(pm);
+            self.terminate();
+            return;
+        }
+        let mut txids: Vec<String> = self.awaiting.keys().cloned().collect();
+        txids.push("done".to_string());
+        for txid in txids {
+            let pm = ProtocolMessage::generate(MessageType::ParticipantDecisionRequest, txid, self.id_str.clone(), 0, 0);
+            self.send(pm);
+        }
+        if self.restarted {
+            return;
+        }
+        while !self.awaiting.is_empty() {
+            self.flush(true);
+            match self.rx.recv() {
+                Ok(res) => {
+                    match res.mtype {
+                        MessageType::CoordinatorCommit | MessageType::CoordinatorAbort => self.receive_decision(res, false),
+                        _ => warn!("{}::Unexpected {:?} while resolving in-doubt transactions", self.id_str.clone(), res.mtype),
+                    }
+                },
+                Err(_) => break,
+            }
+        }
+    }
+
+    ///
+    /// exit_children()
+    /// Tree 2PC: tell the children to exit, after everything passed down
+    /// to them before
+    ///
+    fn exit_children(&mut self) {
+		let pm = ProtocolMessage::generate(MessageType::CoordinatorExit, "done".to_string(), self.id_str.clone(), 0, 0);
+		for id in &self.children {
+			if self.peers[id].send(pm.clone()).is_err() {
+				debug!("{}::Child {} is gone", self.id_str.clone(), id);
+			}
+		}
+    }
+
+    ///
+    /// poll()
+    /// Polls the coordinator and the peers; when the coordinator is silent
+    /// for too long or gone, whatever is awaiting a decision is settled with
+    /// the peers. Not in Paxos Commit: the outcome is in the votes the
+    /// acceptors chose, and the next coordinator learns it from them. Nor
+    /// in tree 2PC, where it comes down the tree: a participant with a
+    /// parent leaves when its parent does.
+    ///
+    fn poll(&mut self) {
+		let mut coordinator_gone = false;
+		loop {
+			if self.serve_peers() {
+				trace!("{}::Exiting with the parent", self.id_str.clone());
+				break;
+			}
+			if coordinator_gone {
+				// tree 2PC: the parent has the last word
+				self.flush(true);
+				thread::sleep(Duration::from_millis(1));
+				continue;
+			}
+			match self.rx.try_recv() {
+				Ok(res) => {
+					self.last_heard = Instant::now();
+					// votes and acks go out as their records become durable,
+					// so the ones for what else came in share a sync_data()
+					self.flush(false);
+					match res.mtype {
+						MessageType::CoordinatorExit => {
+							// tree 2PC: the exit comes after the parent's last decision
+							if res.txid == "done" && self.parent.is_none() {
+								trace!("{}::Exiting", self.id_str.clone());
+								break;
+							}
+						},
+						MessageType::CoordinatorPropose => self.vote(res),
+						MessageType::CoordinatorPreCommit => self.precommit(res),
+						MessageType::CoordinatorCommit | MessageType::CoordinatorAbort => {
+							let down_the_tree = self.parent.is_none();
+							self.receive_decision(res, down_the_tree);
 						},
 						_ => {
-							request.mtype = MessageType::ParticipantVoteAbort;
-							self.state=ParticipantState::VotedAbort;
+							warn!("{}::Unexpected message {:?}", self.id_str.clone(), res.mtype);
 						}
 					}
-					self.log.append( request.mtype.clone(), request.txid.clone(), request.senderid.clone(), request.opid.clone());
-					self.send(request);
-					
 				},
-				Err(_) => {
-
-				}
-
-			}
-			/*counter +=1;
-				if counter ==2{
+				Err(TryRecvError::Empty) => {
+					self.flush(true);
+					self.check_subtrees();
+					if self.protocol != CommitProtocol::PaxosCommit && !self.in_tree() && !self.awaiting.is_empty() && self.last_heard.elapsed() >= self.decision_timeout {
+						info!("{}::No word from the coordinator for {:?}, asking peers", self.id_str.clone(), self.decision_timeout);
+						self.settle_with_peers();
+					}
+					thread::sleep(Duration::from_millis(1));
+				},
+				Err(_) if self.protocol == CommitProtocol::PaxosCommit => {
+					info!("{}::Coordinator is gone, {} transactions wait for the next one", self.id_str.clone(), self.awaiting.len());
+					break;
+				},
+				Err(_) if self.parent.is_some() => {
+					info!("{}::Coordinator is gone, waiting on the parent", self.id_str.clone());
+					coordinator_gone = true;
+				},
+				Err(_) if self.in_tree() => {
+					info!("{}::Coordinator is gone, {} transactions wait for the next one", self.id_str.clone(), self.awaiting.len());
+					self.linger();
 					break;
-				}*/
-			//println!("voted p{}",self.id_str);
-			//await decision
-			self.state = ParticipantState::AwaitingGlobalDecision;	
-			
-			match self.rx.recv() {
-				Ok(res) => { 
-					let mut request = res.clone();
-					self.log.append( request.mtype.clone(), request.txid.clone(), request.senderid.clone(), request.opid.clone());
 				},
 				Err(_) => {
-				}		
+					// coordinator is gone
+					info!("{}::Coordinator is gone, asking peers", self.id_str.clone());
+					self.settle_with_peers();
+					self.linger();
+					break;
+				}
 			}
-			//println!("decision part {}",self.id_str);
-			self.state = ParticipantState::Quiescent;				
 		}
-	    //self.wait_for_exit_signal();
-		
-		
-	
+    }
+
+    ///
+    /// protocol()
+    /// Implements the participant side of the 2PC / 3PC / Paxos Commit protocol
+    /// HINT: If the simulation ends early, don't keep handling requests!
+    /// HINT: Wait for some kind of exit signal before returning from the protocol!
+    ///
+    pub fn protocol(&mut self) {
+
+        trace!("{}::Beginning protocol", self.id_str.clone());
+		if self.recovering {
+			self.resolve_in_doubt();
+		}
+		self.poll();
+		self.exit_children();
+		self.flush(true);
+
+		// blocked: voted, but the coordinator left without a decision
+		self.unknown_ops += self.awaiting.len() as u64;
 		
         self.report_status();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    use participant::ipc_channel::ipc;
+    use oplog::DurabilityPolicy;
+    use oplog::LogConfig;
+    use oplog::LogFormat;
+    use transport::Latency;
+    use transport::LinkFaults;
+
+    use message::MessageType::CoordinatorAbort as Abort;
+    use message::MessageType::CoordinatorCommit as Commit;
+    use message::MessageType::ParticipantAck as Ack;
+    use message::MessageType::ParticipantDecisionRequest as DecisionRequest;
+    use message::MessageType::ParticipantVoteAbort as VoteAbort;
+    use message::MessageType::ParticipantVoteCommit as VoteCommit;
+
+    // A directory of its own for every test, tests run in parallel
+    fn temp_dir(name: &str) -> String {
+        let dir = env::temp_dir().join(format!("participant_test_{}_{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.to_string_lossy().into_owned()
+    }
+
+    fn log_config(durability: DurabilityPolicy) -> LogConfig {
+        LogConfig { durability, format: LogFormat::Json, segment_size: 0, truncate: false }
+    }
+
+    // Participant 0 of a 2PC run that replays its log if recover is set
+    fn config(dir: &str, recover: bool) -> ParticipantConfig {
+        ParticipantConfig {
+            id_str: "0".to_string(),
+            log_path: format!("{}//participant_0.log", dir),
+            recover,
+            log_config: log_config(DurabilityPolicy::Flush),
+            protocol: CommitProtocol::TwoPhase,
+            presumption: Presumption::Nothing,
+            decision_timeout: Duration::from_millis(500),
+            operation_success_prob: 1.0,
+            seed: 0,
+            parent: None,
+            children: Vec::new(),
+            crash: None,
+            restarted: false,
+        }
+    }
+
+    // The other ends of a participant's links, as the test drives them:
+    // the coordinator, and each peer
+    struct Others {
+        from_participant: Receiver<ProtocolMessage>,
+        to_participant: IpcSender<ProtocolMessage>,
+        from_participant_to: HashMap<String, Receiver<ProtocolMessage>>,
+        to_participant_from_peers: IpcSender<ProtocolMessage>,
+        _peer_updates: IpcSender<Vec<(String, IpcSender<ProtocolMessage>)>>,
+    }
+
+    // A participant with the given peers, over links with no faults
+    fn participant(dir: &str, config: ParticipantConfig, peers: &[&str]) -> (Participant, Others) {
+        let faults = LinkFaults { latency: Latency::None, links: Vec::new(), reorder: 0, duplicate: 0.0, partitions: Vec::new() };
+        let transport = Transport::new(format!("participant_{}", config.id_str), dir, config.recover, 1.0, faults, 0);
+        let (part_coor_tx, part_coor_rx) = ipc::channel().unwrap();
+        let (coor_part_tx, coor_part_rx) = ipc::channel().unwrap();
+        let (peer_tx, peer_rx) = ipc::channel().unwrap();
+        let (setup_tx, setup_rx) = ipc::channel().unwrap();
+        let mut senders = HashMap::new();
+        let mut receivers = HashMap::new();
+        for peer in peers {
+            let (tx, rx) = ipc::channel().unwrap();
+            senders.insert(peer.to_string(), Transport::sender(&transport, tx, format!("participant_{}", peer)));
+            receivers.insert(peer.to_string(), rx);
+        }
+        let links = ParticipantLinks {
+            tx: Transport::sender(&transport, part_coor_tx, "coordinator".to_string()),
+            rx: coor_part_rx,
+            peers: senders,
+            peer_rx,
+            peer_updates: setup_rx,
+            transport,
+            acceptors: Vec::new(),
+        };
+        let participant = Participant::new(config, Arc::new(AtomicBool::new(true)), links);
+        let others = Others {
+            from_participant: part_coor_rx,
+            to_participant: coor_part_tx,
+            from_participant_to: receivers,
+            to_participant_from_peers: peer_tx,
+            _peer_updates: setup_tx,
+        };
+        (participant, others)
+    }
+
+    // Everything sent on rx so far, as (txid, type), in order
+    fn received(rx: &Receiver<ProtocolMessage>) -> Vec<(String, MessageType)> {
+        let mut received = Vec::new();
+        while let Ok(pm) = rx.try_recv() {
+            received.push((pm.txid, pm.mtype));
+        }
+        received
+    }
+
+    fn message(mtype: MessageType, txid: &str, senderid: &str) -> ProtocolMessage {
+        ProtocolMessage::generate(mtype, txid.to_string(), senderid.to_string(), 1, 0)
+    }
+
+    // A proposal with one operation of the given kind, on participant 0's key
+    fn proposal(txid: &str, kind: OpKind) -> ProtocolMessage {
+        let mut pm = message(MessageType::CoordinatorPropose, txid, "coordinator");
+        pm.ops = vec![Operation { kind, key: 0 }];
+        pm
+    }
+
+    fn entry(txid: &str, mtype: MessageType) -> (String, MessageType) {
+        (txid.to_string(), mtype)
+    }
+
+    #[test]
+    fn in_doubt_votes_resolved_with_the_coordinator() {
+        let dir = temp_dir("in_doubt");
+        let config = config(&dir, true);
+        {
+            let mut log = oplog::OpLog::new(config.log_path.clone(), config.log_config);
+            for (mtype, txid) in &[(VoteCommit, "tx_1"), (VoteCommit, "tx_2"), (Commit, "tx_2"), (VoteAbort, "tx_3"), (VoteCommit, "tx_4")] {
+                log.append(*mtype, txid.to_string(), "0".to_string(), 1).unwrap();
+            }
+        }
+
+        let (mut participant, others) = participant(&dir, config, &[]);
+        let mut in_doubt: Vec<&String> = participant.awaiting.keys().collect();
+        in_doubt.sort();
+        assert_eq!(in_doubt, vec!["tx_1", "tx_4"]);
+
+        others.to_participant.send(message(Commit, "tx_1", "coordinator")).unwrap();
+        others.to_participant.send(message(Abort, "tx_4", "coordinator")).unwrap();
+        participant.resolve_in_doubt();
+        assert!(participant.awaiting.is_empty());
+        assert_eq!(participant.log.last_state_for_txid("tx_1"), Some(Commit));
+        assert_eq!(participant.log.last_state_for_txid("tx_4"), Some(Abort));
+        let sent = received(&others.from_participant);
+        let acks: Vec<_> = sent.iter().filter(|(_, mtype)| *mtype == Ack).cloned().collect();
+        let mut requests: Vec<_> = sent.iter().filter(|(_, mtype)| *mtype != Ack).cloned().collect();
+        requests.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(requests, vec![entry("done", DecisionRequest), entry("tx_1", DecisionRequest), entry("tx_4", DecisionRequest)]);
+        assert_eq!(acks, vec![entry("tx_1", Ack), entry("tx_4", Ack)]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn vote_durable_before_it_is_sent() {
+        let dir = temp_dir("vote_durable");
+        let mut config = config(&dir, false);
+        config.log_config = log_config(DurabilityPolicy::Fsync);
+        let log_path = config.log_path.clone();
+        let (mut participant, others) = participant(&dir, config, &[]);
+
+        participant.vote(proposal("tx_1", OpKind::Write));
+        assert_eq!(received(&others.from_participant), vec![entry("tx_1", VoteCommit)]);
+        let on_disk: Vec<_> = oplog::OpLog::iter_file(log_path).unwrap()
+            .map(|record| { let record = record.unwrap(); (record.msg.txid, record.msg.mtype) })
+            .collect();
+        assert_eq!(on_disk, vec![entry("tx_1", VoteCommit)]);
+        assert_eq!(participant.log.writes(), (1, 0));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn three_phase_termination_commits_only_after_a_precommit() {
+        let dir = temp_dir("terminate");
+        let mut config = config(&dir, false);
+        config.protocol = CommitProtocol::ThreePhase;
+        let (mut participant, others) = participant(&dir, config, &["1"]);
+        for txid in &["tx_1", "tx_2"] {
+            participant.awaiting.insert(txid.to_string(), message(VoteCommit, txid, "0"));
+        }
+
+        // participant 1 got the PreCommit for tx_1, and is as uncertain as
+        // this one about tx_2
+        others.to_participant_from_peers.send(message(MessageType::CoordinatorPreCommit, "tx_1", "1")).unwrap();
+        others.to_participant_from_peers.send(message(VoteCommit, "tx_2", "1")).unwrap();
+        participant.terminate();
+        assert!(participant.awaiting.is_empty());
+        assert_eq!(participant.log.last_state_for_txid("tx_1"), Some(Commit));
+        assert_eq!(participant.log.last_state_for_txid("tx_2"), Some(Abort));
+        assert_eq!(received(&others.from_participant_to["1"]),
+                   vec![entry("tx_1", MessageType::ParticipantStateRequest), entry("tx_2", MessageType::ParticipantStateRequest)]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn presumed_decisions_are_lazy_and_not_acked() {
+        let dir = temp_dir("presumed");
+
+        // presumed abort: neither the vote to abort nor the abort is forced
+        let mut pa = config(&dir, false);
+        pa.log_path = format!("{}//participant_pa.log", dir);
+        pa.presumption = Presumption::Abort;
+        pa.operation_success_prob = 0.0;
+        let (mut presumed_abort, others) = participant(&dir, pa, &[]);
+        presumed_abort.vote(proposal("tx_1", OpKind::Write));
+        presumed_abort.receive_decision(message(Abort, "tx_1", "coordinator"), false);
+        assert_eq!(presumed_abort.log.writes(), (0, 2));
+        assert_eq!(presumed_abort.log.last_state_for_txid("tx_1"), Some(Abort));
+        assert_eq!(received(&others.from_participant), vec![entry("tx_1", VoteAbort)]);
+
+        // presumed commit: the vote to commit is forced, the commit is not
+        let mut pc = config(&dir, false);
+        pc.log_path = format!("{}//participant_pc.log", dir);
+        pc.presumption = Presumption::Commit;
+        let (mut presumed_commit, others) = participant(&dir, pc, &[]);
+        presumed_commit.vote(proposal("tx_1", OpKind::Write));
+        presumed_commit.receive_decision(message(Commit, "tx_1", "coordinator"), false);
+        assert_eq!(presumed_commit.log.writes(), (1, 1));
+        assert_eq!(presumed_commit.log.last_state_for_txid("tx_1"), Some(Commit));
+        assert_eq!(received(&others.from_participant), vec![entry("tx_1", VoteCommit)]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn read_only_vote_leaves_nothing_in_doubt() {
+        let dir = temp_dir("read_only");
+        let (mut participant, others) = participant(&dir, config(&dir, false), &[]);
+        participant.vote(proposal("tx_1", OpKind::Read));
+        assert_eq!(received(&others.from_participant), vec![entry("tx_1", MessageType::ParticipantVoteReadOnly)]);
+        assert!(participant.awaiting.is_empty());
+        assert_eq!(participant.log.writes(), (0, 1));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn cooperative_termination_blocks_only_when_everyone_is_uncertain() {
+        let dir = temp_dir("cooperate");
+        let (mut participant, others) = participant(&dir, config(&dir, false), &["1"]);
+        for txid in &["tx_1", "tx_2", "tx_3"] {
+            participant.awaiting.insert(txid.to_string(), message(VoteCommit, txid, "0"));
+        }
+
+        others.to_participant_from_peers.send(message(Commit, "tx_1", "1")).unwrap();
+        others.to_participant_from_peers.send(message(VoteCommit, "tx_2", "1")).unwrap();
+        others.to_participant_from_peers.send(message(VoteAbort, "tx_3", "1")).unwrap();
+        participant.cooperate();
+        assert_eq!(participant.log.last_state_for_txid("tx_1"), Some(Commit));
+        assert_eq!(participant.log.last_state_for_txid("tx_3"), Some(Abort));
+        assert_eq!(participant.awaiting.keys().collect::<Vec<_>>(), vec!["tx_2"]);
+        assert_eq!(participant.state, ParticipantState::AwaitingGlobalDecision);
+
+        // a peer asking about a txid this one never voted on aborts it
+        received(&others.from_participant_to["1"]);
+        participant.answer_state_request(message(MessageType::ParticipantStateRequest, "tx_4", "1"));
+        assert_eq!(received(&others.from_participant_to["1"]), vec![entry("tx_4", VoteAbort)]);
+        participant.vote(proposal("tx_4", OpKind::Write));
+        assert_eq!(received(&others.from_participant), vec![entry("tx_4", VoteAbort)]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn sub_coordinator_votes_for_its_subtree() {
+        let dir = temp_dir("subtree");
+        let mut config = config(&dir, false);
+        config.children = vec!["1".to_string(), "2".to_string()];
+        let (mut participant, others) = participant(&dir, config, &["1", "2"]);
+
+        participant.vote(proposal("tx_1", OpKind::Write));
+        for id in &["1", "2"] {
+            assert_eq!(received(&others.from_participant_to[*id]), vec![entry("tx_1", MessageType::CoordinatorPropose)]);
+        }
+        participant.child_vote(message(MessageType::ParticipantVoteReadOnly, "tx_1", "1"));
+        assert!(received(&others.from_participant).is_empty());
+        participant.child_vote(message(VoteCommit, "tx_1", "2"));
+        assert_eq!(received(&others.from_participant), vec![entry("tx_1", VoteCommit)]);
+
+        // the decision goes down to the child that wrote, and is acked up
+        // once that child acked it
+        participant.receive_decision(message(Commit, "tx_1", "coordinator"), true);
+        assert!(received(&others.from_participant_to["1"]).is_empty());
+        assert_eq!(received(&others.from_participant_to["2"]), vec![entry("tx_1", Commit)]);
+        assert!(received(&others.from_participant).is_empty());
+        participant.child_ack(message(Ack, "tx_1", "2"));
+        assert_eq!(received(&others.from_participant), vec![entry("tx_1", Ack)]);
+        assert!(participant.subtrees.is_empty());
+
+        // an abort in the subtree is its vote right away
+        participant.vote(proposal("tx_2", OpKind::Write));
+        participant.child_vote(message(VoteAbort, "tx_2", "2"));
+        assert_eq!(received(&others.from_participant), vec![entry("tx_2", VoteAbort)]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,413 @@
+//!
+//! partition.rs
+//! Network partitions on a schedule. A Partition cuts every link between
+//! two groups of processes for a window of the run, e.g.
+//! "participant_2/coordinator@tx=10-20" isolates participant_2 from the
+//! coordinator from the coordinator's 10th transaction through its 20th,
+//! and "participant_0+participant_1/coordinator+participant_2@ms=200-900"
+//! splits the run in two from 200ms after it started until 900ms.
+//!
+//! Every process cuts the messages it sends itself. They all go by the
+//! clock of the coordinator, which counts the transactions it proposes and
+//! keeps the count, with the time the run started, in a file next to the
+//! logs.
+//!
+use std::fs;
+use std::sync::Mutex;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use message::MessageType;
+use message::ProtocolMessage;
+
+///
+/// Window
+/// When a partition is in place: from a transaction of the coordinator
+/// through another, or from a number of milliseconds into the run until
+/// another. The end is open if it is u64::MAX.
+///
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Window {
+    Txns(u64, u64),
+    Millis(u64, u64),
+}
+
+impl Window {
+
+    ///
+    /// from_name(name)
+    /// Parse "tx=FROM-THROUGH" or "ms=FROM-UNTIL"; the end may be left out
+    ///
+    pub fn from_name(name: &str) -> Option<Window> {
+        let mut parts = name.splitn(2, '=');
+        let unit = parts.next()?;
+        let mut bounds = parts.next()?.splitn(2, '-');
+        let from = bounds.next()?.parse::<u64>().ok()?;
+        let until = match bounds.next()? {
+            "" => u64::MAX,
+            until => until.parse::<u64>().ok()?,
+        };
+        if until < from {
+            return None;
+        }
+        match unit {
+            "tx" => Some(Window::Txns(from, until)),
+            "ms" => Some(Window::Millis(from, until)),
+            _ => None,
+        }
+    }
+
+    ///
+    /// name()
+    /// The inverse of from_name()
+    ///
+    pub fn name(&self) -> String {
+        let (unit, from, until) = match *self {
+            Window::Txns(from, until) => ("tx", from, until),
+            Window::Millis(from, until) => ("ms", from, until),
+        };
+        match until {
+            u64::MAX => format!("{}={}-", unit, from),
+            _ => format!("{}={}-{}", unit, from, until),
+        }
+    }
+
+    ///
+    /// open(clock)
+    /// True if the window is on at clock: (transactions proposed, ms into the run)
+    ///
+    fn open(&self, clock: (u64, u64)) -> bool {
+        match *self {
+            Window::Txns(from, through) => from <= clock.0 && clock.0 <= through,
+            Window::Millis(from, until) => from <= clock.1 && clock.1 < until,
+        }
+    }
+}
+
+///
+/// Partition
+/// Two groups of processes that can not reach each other during a window
+///
+#[derive(Clone, Debug, PartialEq)]
+pub struct Partition {
+    pub sides: (Vec<String>, Vec<String>),
+    pub window: Window,
+}
+
+impl Partition {
+
+    ///
+    /// from_name(spec)
+    /// Parse "GROUP/GROUP@WINDOW", where a GROUP is a "+" separated list of
+    /// "coordinator", "participant_N" and "acceptor_N". Returns None if the
+    /// spec is bad, or a process is on both sides.
+    ///
+    pub fn from_name(spec: &str) -> Option<Partition> {
+        let mut parts = spec.splitn(2, '@');
+        let mut groups = parts.next()?.splitn(2, '/');
+        let window = Window::from_name(parts.next()?)?;
+        let a = Partition::group(groups.next()?)?;
+        let b = Partition::group(groups.next()?)?;
+        if a.iter().any(|name| b.contains(name)) {
+            return None;
+        }
+        Some(Partition {
+            sides: (a, b),
+            window,
+        })
+    }
+
+    ///
+    /// group(names)
+    /// Parse a "+" separated list of process names
+    ///
+    fn group(names: &str) -> Option<Vec<String>> {
+        let mut group = Vec::new();
+        for name in names.split('+') {
+            let known = name == "coordinator"
+                || ["participant_", "acceptor_"].iter().any(|role| name.starts_with(role) && name[role.len()..].parse::<u32>().is_ok());
+            if !known {
+                return None;
+            }
+            group.push(name.to_string());
+        }
+        Some(group)
+    }
+
+    ///
+    /// name()
+    /// The inverse of from_name()
+    ///
+    pub fn name(&self) -> String {
+        format!("{}/{}@{}", self.sides.0.join("+"), self.sides.1.join("+"), self.window.name())
+    }
+
+    ///
+    /// separates(from, to)
+    /// True if from and to are on opposite sides
+    ///
+    fn separates(&self, from: &str, to: &str) -> bool {
+        let (ref a, ref b) = self.sides;
+        let on = |side: &Vec<String>, name: &str| side.iter().any(|p| p == name);
+        (on(a, from) && on(b, to)) || (on(b, from) && on(a, to))
+    }
+}
+
+///
+/// parse_schedule(spec)
+/// Parse a comma separated list of partitions. Returns None if one is bad.
+///
+pub fn parse_schedule(spec: &str) -> Option<Vec<Partition>> {
+    spec.split(',').filter(|entry| !entry.is_empty()).map(Partition::from_name).collect()
+}
+
+///
+/// read_scenario(path)
+/// Read a scenario file: a partition per line, blank lines and lines
+/// starting with "#" left out. Returns None if a partition is bad; panics
+/// if the file can not be read.
+///
+pub fn read_scenario(path: &str) -> Option<Vec<Partition>> {
+    let text = fs::read_to_string(path).unwrap();
+    text.lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(Partition::from_name)
+        .collect()
+}
+
+///
+/// schedule_spec(partitions)
+/// The inverse of parse_schedule()
+///
+pub fn schedule_spec(partitions: &[Partition]) -> String {
+    partitions.iter().map(|partition| partition.name()).collect::<Vec<String>>().join(",")
+}
+
+///
+/// now_ms()
+/// The wall clock time in milliseconds since the UNIX epoch
+///
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64
+}
+
+///
+/// Schedule
+/// The partitions of one process, and its view of the coordinator's clock.
+/// The coordinator keeps the clock: the time the run started and the
+/// number of transactions proposed so far, with the txid of the last one.
+/// The other processes read it from the clock file when they send on a
+/// link that some partition cuts.
+///
+#[derive(Debug)]
+pub struct Schedule {
+    partitions: Vec<Partition>,
+    clock_path: String,
+    keeper: bool,
+    clock: Mutex<(u64, u64, String)>,
+}
+
+impl Schedule {
+
+    ///
+    /// new(name, log_path, partitions)
+    /// Schedule of process `name`; the coordinator starts the clock of the run
+    ///
+    pub fn new(name: &str, log_path: &str, partitions: Vec<Partition>) -> Schedule {
+        let schedule = Schedule {
+            partitions,
+            clock_path: format!("{}//partition.clock", log_path),
+            keeper: name == "coordinator",
+            clock: Mutex::new((now_ms(), 0, String::new())),
+        };
+        if schedule.keeper && !schedule.partitions.is_empty() {
+            let clock = schedule.clock.lock().unwrap();
+            schedule.publish(clock.0, clock.1);
+        }
+        schedule
+    }
+
+    ///
+    /// publish(start, txns)
+    /// Write the clock; the rename makes sure readers never see half of it
+    ///
+    fn publish(&self, start: u64, txns: u64) {
+        let tmp = format!("{}.tmp", self.clock_path);
+        fs::write(&tmp, format!("{} {}", start, txns)).unwrap();
+        fs::rename(&tmp, &self.clock_path).unwrap();
+    }
+
+    ///
+    /// observe(pm)
+    /// The coordinator counts a transaction when its proposal first goes out
+    ///
+    pub fn observe(&self, pm: &ProtocolMessage) {
+        if !self.keeper || self.partitions.is_empty() || pm.mtype != MessageType::CoordinatorPropose {
+            return;
+        }
+        let mut clock = self.clock.lock().unwrap();
+        if clock.2 != pm.txid {
+            clock.1 += 1;
+            clock.2 = pm.txid.clone();
+            self.publish(clock.0, clock.1);
+        }
+    }
+
+    ///
+    /// now()
+    /// The clock as (transactions proposed, ms into the run). A process other
+    /// than the coordinator reads it from the clock file; if that is not
+    /// there (yet), no window is on.
+    ///
+    fn now(&self) -> Option<(u64, u64)> {
+        let (start, txns) = if self.keeper {
+            let clock = self.clock.lock().unwrap();
+            (clock.0, clock.1)
+        } else {
+            let text = fs::read_to_string(&self.clock_path).ok()?;
+            let mut fields = text.split_whitespace().map(|field| field.parse::<u64>());
+            match (fields.next(), fields.next()) {
+                (Some(Ok(start)), Some(Ok(txns))) => (start, txns),
+                _ => return None,
+            }
+        };
+        Some((txns, now_ms().saturating_sub(start)))
+    }
+
+    ///
+    /// cut(from, to)
+    /// True if a partition in place now separates from and to
+    ///
+    pub fn cut(&self, from: &str, to: &str) -> bool {
+        let windows: Vec<Window> = self.partitions.iter()
+            .filter(|partition| partition.separates(from, to))
+            .map(|partition| partition.window)
+            .collect();
+        if windows.is_empty() {
+            return false;
+        }
+        match self.now() {
+            Some(clock) => windows.iter().any(|window| window.open(clock)),
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    // A directory of its own for every test, tests run in parallel
+    fn temp_dir(name: &str) -> String {
+        let dir = env::temp_dir().join(format!("partition_test_{}_{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.to_string_lossy().into_owned()
+    }
+
+    fn propose(txid: &str) -> ProtocolMessage {
+        ProtocolMessage::generate(MessageType::CoordinatorPropose, txid.to_string(), "coordinator".to_string(), 0, 0)
+    }
+
+    #[test]
+    fn parse_window() {
+        assert_eq!(Window::from_name("tx=10-20"), Some(Window::Txns(10, 20)));
+        assert_eq!(Window::from_name("ms=200-900"), Some(Window::Millis(200, 900)));
+        assert_eq!(Window::from_name("tx=5-"), Some(Window::Txns(5, u64::MAX)));
+        assert_eq!(Window::from_name("tx=7-7"), Some(Window::Txns(7, 7)));
+        for name in &["", "tx", "tx=", "tx=10", "tx=-20", "tx=20-10", "tx=a-b", "tx=1-2-3", "s=1-2", "10-20"] {
+            assert_eq!(Window::from_name(name), None, "{}", name);
+        }
+        for name in &["tx=10-20", "ms=200-900", "tx=5-", "ms=0-"] {
+            assert_eq!(Window::from_name(name).unwrap().name(), *name);
+        }
+    }
+
+    #[test]
+    fn window_bounds() {
+        // a window of transactions includes its end, one of milliseconds does not
+        let txns = Window::Txns(10, 20);
+        assert!(!txns.open((9, 0)));
+        assert!(txns.open((10, 0)));
+        assert!(txns.open((20, 0)));
+        assert!(!txns.open((21, 0)));
+        let millis = Window::Millis(200, 900);
+        assert!(!millis.open((0, 199)));
+        assert!(millis.open((0, 200)));
+        assert!(millis.open((0, 899)));
+        assert!(!millis.open((0, 900)));
+        assert!(Window::Txns(5, u64::MAX).open((1 << 40, 0)));
+    }
+
+    #[test]
+    fn parse_partition() {
+        let partition = Partition::from_name("participant_0+participant_1/coordinator+acceptor_2@ms=200-900").unwrap();
+        assert_eq!(partition.sides.0, vec!["participant_0".to_string(), "participant_1".to_string()]);
+        assert_eq!(partition.sides.1, vec!["coordinator".to_string(), "acceptor_2".to_string()]);
+        assert_eq!(partition.window, Window::Millis(200, 900));
+        assert_eq!(partition.name(), "participant_0+participant_1/coordinator+acceptor_2@ms=200-900");
+
+        assert!(partition.separates("participant_1", "coordinator"));
+        assert!(partition.separates("acceptor_2", "participant_0"));
+        assert!(!partition.separates("participant_0", "participant_1"));
+        assert!(!partition.separates("participant_2", "coordinator"));
+    }
+
+    #[test]
+    fn bad_partitions() {
+        for spec in &[
+            "",
+            "participant_2/coordinator",
+            "participant_2@tx=10-20",
+            "participant_2/coordinator@",
+            "participant_2/coordinator@tx=20-10",
+            "participant_2/@tx=10-20",
+            "participant_2/coordinator+@tx=10-20",
+            "participant_x/coordinator@tx=10-20",
+            "client_0/coordinator@tx=10-20",
+            "participant_2/coordinator/acceptor_0@tx=10-20",
+            "participant_2+coordinator/coordinator@tx=10-20",
+        ] {
+            assert_eq!(Partition::from_name(spec), None, "{}", spec);
+        }
+    }
+
+    #[test]
+    fn schedules() {
+        let spec = "participant_2/coordinator@tx=10-20,participant_0/participant_1@ms=0-";
+        let partitions = parse_schedule(spec).unwrap();
+        assert_eq!(partitions.len(), 2);
+        assert_eq!(schedule_spec(&partitions), spec);
+        assert_eq!(parse_schedule(",participant_2/coordinator@tx=10-20,").unwrap().len(), 1);
+        assert_eq!(parse_schedule(""), Some(Vec::new()));
+        assert_eq!(parse_schedule("participant_2/coordinator@tx=10-20,participant_2"), None);
+
+        let dir = temp_dir("scenario");
+        let path = format!("{}/scenario", dir);
+        fs::write(&path, "# isolate participant_2\n\n  participant_2/coordinator@tx=10-20  \n#participant_0/participant_1@ms=0-\n").unwrap();
+        assert_eq!(read_scenario(&path), parse_schedule("participant_2/coordinator@tx=10-20"));
+        fs::write(&path, "participant_2/coordinator@tx=10-20\nparticipant_2\n").unwrap();
+        assert_eq!(read_scenario(&path), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn cut_by_transactions() {
+        let dir = temp_dir("cut_by_transactions");
+        let partitions = parse_schedule("participant_0/coordinator@tx=2-3").unwrap();
+        let coordinator = Schedule::new("coordinator", &dir, partitions.clone());
+        let participant = Schedule::new("participant_0", &dir, partitions);
+        let mut cut = Vec::new();
+        for txid in &["tx_1", "tx_1", "tx_2", "tx_3", "tx_4"] {
+            coordinator.observe(&propose(txid));
+            cut.push((coordinator.cut("coordinator", "participant_0"), participant.cut("participant_0", "coordinator")));
+            assert!(!coordinator.cut("coordinator", "participant_1"));
+        }
+        // a proposal sent twice counts once
+        assert_eq!(cut, vec![(false, false), (false, false), (true, true), (true, true), (false, false)]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
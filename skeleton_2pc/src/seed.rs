@@ -0,0 +1,136 @@
+//!
+//! seed.rs
+//! Seeded randomness. Every random decision of a process comes from the
+//! seed of the run and a name. The requests of a
+//! client, and the delays, reordering and duplicates on a link, are drawn
+//! from an RNG named after the client, e.g. "client_0", or the link, e.g.
+//! "participant_1->coordinator/delivery". A vote, or whether a message is
+//! dropped, is drawn for the transaction or message it is about, e.g.
+//! "participant_1/client0_op_3", so it comes out the same however the
+//! messages of a run interleave. The seed of every run goes to a file next
+//! to the logs. A run with the same seed is no replay, though: thread
+//! scheduling and timeouts are not reproduced, and they decide e.g. how
+//! often a decision is re-sent, which changes the messages there are to
+//! drop and the order the RNG of a link is drawn from.
+//!
+extern crate rand;
+
+use std::fs;
+use std::fs::OpenOptions;
+use std::io::Write;
+
+use seed::rand::prelude::*;
+
+///
+/// random_seed()
+/// A seed for a run that was not given one
+///
+pub fn random_seed() -> u64 {
+    random()
+}
+
+///
+/// derive(seed, name)
+/// The seed of the RNG called name: FNV-1a over the seed and the name, which
+/// unlike the std hashers is the same in every build
+///
+pub fn derive(seed: u64, name: &str) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in seed.to_le_bytes().iter().chain(name.as_bytes()) {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+///
+/// rng(seed, name)
+/// The RNG called name in the run seeded with seed
+///
+pub fn rng(seed: u64, name: &str) -> StdRng {
+    StdRng::seed_from_u64(derive(seed, name))
+}
+
+///
+/// draw(seed, name)
+/// A number in [0, 1) for the decision called name. Unlike one drawn from a
+/// stream, it does not change when the timing of the run changes the order
+/// the decisions are made in.
+///
+pub fn draw(seed: u64, name: &str) -> f64 {
+    rng(seed, name).gen()
+}
+
+///
+/// record(log_path, recover, seed)
+/// Note the seed of a run in log_path, after those of the runs before it if
+/// it recovers from them
+///
+pub fn record(log_path: &str, recover: bool, seed: u64) {
+    let path = format!("{}//run.seed", log_path);
+    let mut file = OpenOptions::new().create(true).write(true).append(recover).truncate(!recover).open(&path).unwrap();
+    writeln!(file, "{}", seed).unwrap();
+}
+
+///
+/// read(log_path)
+/// The seeds of the runs recorded in log_path, first to last
+///
+pub fn read(log_path: &str) -> Vec<u64> {
+    match fs::read_to_string(format!("{}//run.seed", log_path)) {
+        Ok(text) => text.lines().filter_map(|line| line.trim().parse::<u64>().ok()).collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    #[test]
+    fn derive_is_fixed() {
+        // FNV-1a, so a seed derives the same in every build
+        assert_eq!(derive(0, ""), 0xa8c7f832281a39c5);
+        assert_eq!(derive(42, "client_0"), 0xc681d6bca89e8287);
+        assert_eq!(derive(42, "participant_1/client0_op_3"), 0xb05a6b202e62389e);
+        assert!(derive(42, "client_0") != derive(43, "client_0"));
+        assert!(derive(42, "client_0") != derive(42, "client_1"));
+    }
+
+    #[test]
+    fn rng_is_deterministic() {
+        let a: Vec<u64> = rng(7, "participant_1->coordinator/delivery").sample_iter(&rand::distributions::Standard).take(16).collect();
+        let b: Vec<u64> = rng(7, "participant_1->coordinator/delivery").sample_iter(&rand::distributions::Standard).take(16).collect();
+        let c: Vec<u64> = rng(7, "participant_2->coordinator/delivery").sample_iter(&rand::distributions::Standard).take(16).collect();
+        assert_eq!(a, b);
+        assert!(a != c);
+    }
+
+    #[test]
+    fn draw_is_deterministic() {
+        let names: Vec<String> = (0..100).map(|op| format!("participant_0/client0_op_{}", op)).collect();
+        let forward: Vec<f64> = names.iter().map(|name| draw(99, name)).collect();
+        let backward: Vec<f64> = names.iter().rev().map(|name| draw(99, name)).collect();
+        assert_eq!(forward, backward.into_iter().rev().collect::<Vec<f64>>());
+        assert!(forward.iter().all(|x| *x >= 0.0 && *x < 1.0));
+        assert!(forward.iter().any(|x| *x != forward[0]));
+        assert!(draw(99, &names[0]) != draw(100, &names[0]));
+    }
+
+    #[test]
+    fn seeds_of_runs() {
+        let dir = env::temp_dir().join(format!("seed_test_{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let log_path = dir.to_string_lossy().into_owned();
+        assert_eq!(read(&log_path), Vec::<u64>::new());
+        record(&log_path, false, 1);
+        record(&log_path, true, 2);
+        assert_eq!(read(&log_path), vec![1, 2]);
+        record(&log_path, false, u64::MAX);
+        assert_eq!(read(&log_path), vec![u64::MAX]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
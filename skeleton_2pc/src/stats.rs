@@ -0,0 +1,86 @@
+//!
+//! stats.rs
+//! Per-process cost counters of a run. Every coordinator and participant
+//! writes its counters next to its log on exit, so check mode can report
+//! what a protocol variant costs per transaction.
+//!
+extern crate serde;
+extern crate serde_json;
+
+use std::fs;
+
+use message::CommitProtocol;
+use message::Presumption;
+
+///
+/// RunStats
+/// Log writes and protocol messages of one process over a run
+///
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, Default)]
+pub struct RunStats {
+    pub variant: String,        // protocol and presumption, e.g. "2pc presumed abort"
+    pub transactions: u64,      // transactions the process took part in
+    pub forced_writes: u64,     // log records forced to stable storage
+    pub lazy_writes: u64,       // log records written without forcing
+    pub messages: u64,          // protocol messages sent (clients not included)
+    #[serde(default)]
+    pub decide_micros: u64,     // coordinator: time from proposal to decision, summed over transactions
+}
+
+impl RunStats {
+
+    ///
+    /// new(protocol, presumption)
+    /// Zeroed stats for a process running this protocol variant
+    ///
+    pub fn new(protocol: CommitProtocol, presumption: Presumption) -> RunStats {
+        RunStats {
+            variant: match protocol {
+                CommitProtocol::TwoPhase => format!("{} presumed {}", protocol.name(), presumption.name()),
+                _ => protocol.name().to_string(),
+            },
+            ..RunStats::default()
+        }
+    }
+
+    ///
+    /// path_for(log_path)
+    /// Stats file that goes with a log: its name with .stats for .log
+    ///
+    pub fn path_for(log_path: &str) -> String {
+        match log_path.ends_with(".log") {
+            true => format!("{}.stats", &log_path[..log_path.len() - 4]),
+            false => format!("{}.stats", log_path),
+        }
+    }
+
+    ///
+    /// write(path)
+    /// Writes the stats to path as JSON
+    ///
+    pub fn write(&self, path: &str) {
+        if let Err(e) = fs::write(path, serde_json::to_string(self).unwrap()) {
+            error!("Failed to write stats \"{}\": {:?}", path, e);
+        }
+    }
+
+    ///
+    /// read(path)
+    /// Reads the stats a process wrote, None if there are none
+    ///
+    pub fn read(path: &str) -> Option<RunStats> {
+        let text = fs::read_to_string(path).ok()?;
+        serde_json::from_str(&text).ok()
+    }
+
+    ///
+    /// per_txn(count)
+    /// count divided by the number of transactions
+    ///
+    pub fn per_txn(&self, count: u64) -> f64 {
+        match self.transactions {
+            0 => 0.0,
+            n => count as f64 / n as f64,
+        }
+    }
+}
@@ -13,18 +13,61 @@ extern crate clap;
 use clap::{Arg, App};
 
 extern crate ctrlc;
+
+use std::time::Duration;
+
+use checker::CheckConfig;
+use coordinator::CoordinatorConfig;
+use crash::CrashPoint;
+use crash::CrashSpec;
+use message::CommitProtocol;
+use message::Presumption;
+use oplog::DurabilityPolicy;
+use oplog::LogConfig;
+use oplog::LogFormat;
+use participant::ParticipantConfig;
+use partition;
+use partition::Partition;
+use seed;
+use transport::Latency;
+use transport::LinkFaults;
+
 #[derive(Clone, Debug)]
 pub struct TPCOptions {
     pub send_success_probability: f64,        // Probability that a message send succeeds
     pub operation_success_probability: f64,   // Probability that an operation succeeds
+    pub write_probability: f64,               // Probability that an operation of a request writes
     pub num_clients: u32,                     // Number of concurrent clients issuing requests
     pub num_requests: u32,                    // Number of requests issued per client
     pub num_participants: u32,                // Number of participants in 2PC protocol (not including coordinator)
     pub verbosity: usize,                     // Integer verbosity level. experiment with 0 (default) to 5 (fire-hose of output)
-    pub mode: String,                         // One of "run", "client", "particpant", "check"
+    pub mode: String,                         // One of "run", "client", "particpant", "acceptor", "check"
     pub log_path: String,                     // Directory for client, participant, and coordinator logs
     pub ipc_path: String,                     // Path to IPC socket for setting up communication with the coordinator
     pub num: u32,                             // Participant / Client number for naming the log files
+    pub recover: bool,                        // Replay existing logs instead of starting fresh ones
+    pub epoch: u32,                           // Epoch prefixed to client txids so recovered runs do not reuse them
+    pub durability: DurabilityPolicy,         // How hard log appends work to reach stable storage
+    pub log_format: LogFormat,                // On-disk format of new logs
+    pub repair: bool,                         // Check mode: truncate torn log tails in place
+    pub segment_size: u64,                    // Rotate logs into segments of this many bytes, 0 for one file
+    pub truncate_logs: bool,                  // Delete log segments no checkpointed transaction needs
+    pub protocol: CommitProtocol,             // Atomic commit protocol: 2PC, 3PC or Paxos Commit
+    pub decision_timeout: Duration,           // How long a participant waits on the coordinator before asking its peers
+    pub crash: Option<CrashSpec>,             // Process to crash, at which point of which of its transactions
+    pub restart: bool,                        // Run mode: restart a participant that crashed at its crash point
+    pub restarted: bool,                      // Participant restarted by run mode: the coordinator is up
+    pub presumption: Presumption,             // 2PC variant: presumed nothing, abort or commit
+    pub ack_timeout: Duration,                // How long the coordinator waits for acks before re-sending
+    pub max_in_flight: usize,                 // Most transactions the coordinator runs at once
+    pub num_acceptors: u32,                   // Paxos Commit: number of acceptors, 2F+1 to survive F failures
+    pub fanout: u32,                          // Tree 2PC: children per coordinator and sub-coordinator, 0 for flat
+    pub latency: Latency,                     // Delay of messages between the coordinator and a participant
+    pub link_latencies: Vec<(String, Latency)>, // Delay on the links of single participants, overriding latency
+    pub reorder: usize,                       // How many later messages one between the coordinator and a participant can be overtaken by
+    pub duplicate: f64,                       // Probability that a message between the coordinator and a participant is delivered twice
+    pub partitions: Vec<Partition>,           // Links cut between groups of processes on a schedule
+    pub seed: u64,                            // Seed every random decision of the run is derived from
 }
 
 impl TPCOptions {
@@ -38,6 +81,7 @@ impl TPCOptions {
         // Default values for CLI options
         let default_send_success_probability = "1.0";
         let default_operation_success_probability = "1.0";
+        let default_write_probability = "1.0";
         let default_num_participants = "3";
         let default_num_clients = "3";
         let default_num_requests = "15";
@@ -46,6 +90,21 @@ impl TPCOptions {
         let default_log_path = "./logs/";
         let default_ipc_path = "none";
         let default_num = "0";
+        let default_epoch = "0";
+        let default_durability = "fsync";
+        let default_log_format = "json";
+        let default_segment_size = "0";
+        let default_protocol = "2pc";
+        let default_decision_timeout_ms = "3000";
+        let default_crash_coordinator = "0";
+        let default_presumption = "nothing";
+        let default_ack_timeout_ms = "1000";
+        let default_max_in_flight = "4";
+        let default_num_acceptors = "3";
+        let default_fanout = "0";
+        let default_delay = "none";
+        let default_reorder = "0";
+        let default_duplicate = "0.0";
 
         // Set-Up clap
         let matches = App::new("concurrency-2pc")
@@ -56,12 +115,17 @@ impl TPCOptions {
                     .short("S")
                     .required(false)
                     .takes_value(true)
-                    .help("Probability participants successfully send messages"))
+                    .help("Probability that a protocol message is sent; the transport drops (and records) the rest"))
             .arg(Arg::with_name("operation_success_probability")
                     .short("s")
                     .required(false)
                     .takes_value(true)
                     .help("Probability participants successfully execute requests"))
+            .arg(Arg::with_name("write_probability")
+                    .short("w")
+                    .required(false)
+                    .takes_value(true)
+                    .help("Probability that an operation of a request writes. A participant whose operations only read votes read-only"))
             .arg(Arg::with_name("num_clients")
                     .short("c")
                     .required(false)
@@ -91,7 +155,7 @@ impl TPCOptions {
                     .short("m")
                     .required(false)
                     .takes_value(true)
-                    .help("Mode: \"run\" starts 2PC, \"client\" starts a client process, \"participant\" starts a participant process, \"check\" checks logs produced by previous run"))
+                    .help("Mode: \"run\" starts 2PC, \"client\" starts a client process, \"participant\" starts a participant process, \"acceptor\" starts a Paxos Commit acceptor process, \"check\" checks logs produced by previous run"))
             .arg(Arg::with_name("ipc_path")
                     .long("ipc_path")
                     .required(false)
@@ -102,11 +166,147 @@ impl TPCOptions {
                     .required(false)
                     .takes_value(true)
                     .help("Participant / Client number for naming the log files. Ranges from 0 to num_clients - 1 or num_participants - 1"))
+            .arg(Arg::with_name("recover")
+                    .long("recover")
+                    .required(false)
+                    .takes_value(false)
+                    .help("Recover from the logs of a previous run instead of truncating them"))
+            .arg(Arg::with_name("epoch")
+                    .long("epoch")
+                    .required(false)
+                    .takes_value(true)
+                    .help("Epoch of this run, set by the coordinator for its clients when recovering"))
+            .arg(Arg::with_name("durability")
+                    .long("durability")
+                    .required(false)
+                    .takes_value(true)
+                    .help("Log durability: \"none\", \"flush\", \"fsync\" (default) or \"group\" commit"))
+            .arg(Arg::with_name("group_batch")
+                    .long("group_batch")
+                    .required(false)
+                    .takes_value(true)
+                    .validator(|b| match b.parse::<usize>() {
+                        Ok(n) if n >= 1 => Ok(()),
+                        _ => Err("must be at least 1 record".to_string()),
+                    })
+                    .help("Group commit: most records written with one fsync"))
+            .arg(Arg::with_name("group_delay_us")
+                    .long("group_delay_us")
+                    .required(false)
+                    .takes_value(true)
+                    .validator(|d| d.parse::<u64>().map(|_| ()).map_err(|e| e.to_string()))
+                    .help("Group commit: longest a record waits for others to join its fsync, in microseconds"))
+            .arg(Arg::with_name("log_format")
+                    .long("log_format")
+                    .required(false)
+                    .takes_value(true)
+                    .help("Format of new logs: \"json\" (default) or length-prefixed, CRC-checked \"binary\""))
+            .arg(Arg::with_name("repair")
+                    .long("repair")
+                    .required(false)
+                    .takes_value(false)
+                    .help("Check mode: truncate torn records off the end of the logs before checking"))
+            .arg(Arg::with_name("segment_size")
+                    .long("segment_size")
+                    .required(false)
+                    .takes_value(true)
+                    .help("Rotate logs into numbered segments of about this many bytes, checkpointing at each rotation. 0 (default) keeps one file"))
+            .arg(Arg::with_name("truncate_logs")
+                    .long("truncate_logs")
+                    .required(false)
+                    .takes_value(false)
+                    .help("Delete log segments that only hold resolved transactions after each checkpoint"))
+            .arg(Arg::with_name("protocol")
+                    .long("protocol")
+                    .required(false)
+                    .takes_value(true)
+                    .help("Commit protocol: \"2pc\" (default), \"3pc\" or \"paxos\" (Paxos Commit)"))
+            .arg(Arg::with_name("decision_timeout_ms")
+                    .long("decision_timeout_ms")
+                    .required(false)
+                    .takes_value(true)
+                    .help("How long a participant waits on the coordinator before asking its peers for the outcome"))
+            .arg(Arg::with_name("crash_coordinator")
+                    .long("crash_coordinator")
+                    .required(false)
+                    .takes_value(true)
+                    .help("Crash the coordinator in this transaction, after it sent phase 2 to one participant. 0 (default) never crashes. Short for --crash coordinator:during_broadcast:tx=N"))
+            .arg(Arg::with_name("crash")
+                    .long("crash")
+                    .required(false)
+                    .takes_value(true)
+                    .help("Crash a process at a point of one of its transactions, as \"PROCESS:POINT:tx=N\". The coordinator crashes \"before_decision_log\", \"after_decision_log\", \"during_broadcast\" or \"after_broadcast\"; a participant_N \"after_vote_log\", \"after_vote_send\" or \"after_decision_log\""))
+            .arg(Arg::with_name("restart")
+                    .long("restart")
+                    .required(false)
+                    .takes_value(false)
+                    .help("Run mode: restart a participant that crashed at its crash point, recovering from its log"))
+            .arg(Arg::with_name("restarted")
+                    .long("restarted")
+                    .required(false)
+                    .takes_value(false)
+                    .help("Set by run mode for a participant it restarted: the coordinator is up to ask about in-doubt transactions"))
+            .arg(Arg::with_name("presumption")
+                    .long("presumption")
+                    .required(false)
+                    .takes_value(true)
+                    .help("2PC variant: presumed \"nothing\" (default), presumed \"abort\" or presumed \"commit\""))
+            .arg(Arg::with_name("ack_timeout_ms")
+                    .long("ack_timeout_ms")
+                    .required(false)
+                    .takes_value(true)
+                    .help("How long the coordinator waits for acknowledgements before re-sending a decision"))
+            .arg(Arg::with_name("max_in_flight")
+                    .long("max_in_flight")
+                    .required(false)
+                    .takes_value(true)
+                    .help("Most transactions the coordinator has in phase 1 or PreCommit at once"))
+            .arg(Arg::with_name("num_acceptors")
+                    .long("num_acceptors")
+                    .required(false)
+                    .takes_value(true)
+                    .help("Paxos Commit: number of acceptors the votes are written to, 2F+1 to survive F failures"))
+            .arg(Arg::with_name("fanout")
+                    .long("fanout")
+                    .required(false)
+                    .takes_value(true)
+                    .help("Tree 2PC: the coordinator and every participant with children of its own (a sub-coordinator) have this many children. 0 (default) is flat 2PC"))
+            .arg(Arg::with_name("delay")
+                    .long("delay")
+                    .required(false)
+                    .takes_value(true)
+                    .help("Latency of the messages between the coordinator and the participants: \"none\" (default), \"fixed:MS\", \"uniform:MIN_MS:MAX_MS\" or \"exp:MEAN_MS\". A comma separated \"participant_N=LATENCY\" sets the links of one participant"))
+            .arg(Arg::with_name("reorder")
+                    .long("reorder")
+                    .required(false)
+                    .takes_value(true)
+                    .help("Reordering window: a message between the coordinator and a participant can be overtaken by this many later ones. 0 (default) keeps the order"))
+            .arg(Arg::with_name("duplicate")
+                    .long("duplicate")
+                    .required(false)
+                    .takes_value(true)
+                    .help("Probability that a message between the coordinator and a participant is delivered twice"))
+            .arg(Arg::with_name("partition")
+                    .long("partition")
+                    .required(false)
+                    .takes_value(true)
+                    .help("Comma separated partitions, each \"GROUP/GROUP@WINDOW\": every link between the two groups is cut during the window. A GROUP is a \"+\" separated list of \"coordinator\", \"participant_N\" and \"acceptor_N\"; a WINDOW is \"tx=FROM-THROUGH\", in transactions the coordinator proposed, or \"ms=FROM-UNTIL\", in milliseconds into the run. The end may be left out. E.g. \"participant_2/coordinator@tx=10-20\""))
+            .arg(Arg::with_name("partitions")
+                    .long("partitions")
+                    .required(false)
+                    .takes_value(true)
+                    .help("Scenario file with a partition (as for --partition) per line; lines starting with \"#\" are comments"))
+            .arg(Arg::with_name("seed")
+                    .long("seed")
+                    .required(false)
+                    .takes_value(true)
+                    .help("Seed for every random decision of the run (votes, requests, drops, delays); timing is not reproduced. Random if not given"))
             .get_matches();
 
         // Parse CLI options and take default values if none given
         let mode = matches.value_of("mode").unwrap_or(default_mode);
         let operation_success_probability = matches.value_of("operation_success_probability").unwrap_or(default_operation_success_probability).parse::<f64>().unwrap();
+        let write_probability = matches.value_of("write_probability").unwrap_or(default_write_probability).parse::<f64>().unwrap();
         let send_success_probability = matches.value_of("send_success_probability").unwrap_or(default_send_success_probability).parse::<f64>().unwrap();
         let num_clients = matches.value_of("num_clients").unwrap_or(default_num_clients).parse::<u32>().unwrap();
         let num_participants = matches.value_of("num_participants").unwrap_or(default_num_participants).parse::<u32>().unwrap();
@@ -115,9 +315,89 @@ impl TPCOptions {
         let log_path = matches.value_of("log_path").unwrap_or(default_log_path);
         let ipc_path = matches.value_of("ipc_path").unwrap_or(default_ipc_path);
         let num = matches.value_of("num").unwrap_or(default_num).parse::<u32>().unwrap();
+        let recover = matches.is_present("recover");
+        let repair = matches.is_present("repair");
+        let segment_size = matches.value_of("segment_size").unwrap_or(default_segment_size).parse::<u64>().unwrap();
+        let truncate_logs = matches.is_present("truncate_logs");
+        let epoch = matches.value_of("epoch").unwrap_or(default_epoch).parse::<u32>().unwrap();
+        let durability_name = matches.value_of("durability").unwrap_or(default_durability);
+        let durability = match DurabilityPolicy::from_name(durability_name) {
+            Some(DurabilityPolicy::GroupCommit { max_batch, max_delay }) => DurabilityPolicy::GroupCommit {
+                max_batch: matches.value_of("group_batch").map_or(max_batch, |b| b.parse::<usize>().unwrap()),
+                max_delay: matches.value_of("group_delay_us").map_or(max_delay, |d| Duration::from_micros(d.parse::<u64>().unwrap())),
+            },
+            Some(policy) => policy,
+            None => panic!("unknown durability policy \"{}\" requested!", durability_name),
+        };
+        let protocol_name = matches.value_of("protocol").unwrap_or(default_protocol);
+        let protocol = match CommitProtocol::from_name(protocol_name) {
+            Some(protocol) => protocol,
+            None => panic!("unknown commit protocol \"{}\" requested!", protocol_name),
+        };
+        let decision_timeout = Duration::from_millis(matches.value_of("decision_timeout_ms").unwrap_or(default_decision_timeout_ms).parse::<u64>().unwrap());
+        let crash_coordinator = matches.value_of("crash_coordinator").unwrap_or(default_crash_coordinator).parse::<u32>().unwrap();
+        let crash = match (matches.value_of("crash"), crash_coordinator) {
+            (Some(_), n) if n > 0 => panic!("--crash and --crash_coordinator do not go together!"),
+            (Some(spec), _) => match CrashSpec::from_name(spec) {
+                Some(crash) => Some(crash),
+                None => panic!("bad crash spec \"{}\" requested!", spec),
+            },
+            (None, 0) => None,
+            (None, n) => Some(CrashSpec { process: "coordinator".to_string(), point: CrashPoint::DuringBroadcast, tx: n }),
+        };
+        let restart = matches.is_present("restart");
+        let restarted = matches.is_present("restarted");
+        let presumption_name = matches.value_of("presumption").unwrap_or(default_presumption);
+        let presumption = match Presumption::from_name(presumption_name) {
+            Some(presumption) => presumption,
+            None => panic!("unknown presumption \"{}\" requested!", presumption_name),
+        };
+        if protocol != CommitProtocol::TwoPhase && presumption != Presumption::Nothing {
+            panic!("presumed {} is a 2PC variant, it does not go with {}!", presumption.name(), protocol.name());
+        }
+        let ack_timeout = Duration::from_millis(matches.value_of("ack_timeout_ms").unwrap_or(default_ack_timeout_ms).parse::<u64>().unwrap());
+        let max_in_flight = matches.value_of("max_in_flight").unwrap_or(default_max_in_flight).parse::<usize>().unwrap();
+        if max_in_flight == 0 {
+            panic!("max_in_flight has to be at least 1!");
+        }
+        let num_acceptors = matches.value_of("num_acceptors").unwrap_or(default_num_acceptors).parse::<u32>().unwrap();
+        if num_acceptors % 2 == 0 {
+            panic!("num_acceptors has to be odd (2F+1)!");
+        }
+        let fanout = matches.value_of("fanout").unwrap_or(default_fanout).parse::<u32>().unwrap();
+        if fanout > 0 && protocol != CommitProtocol::TwoPhase {
+            panic!("a tree of sub-coordinators (fanout {}) only goes with 2pc!", fanout);
+        }
+        if fanout > 0 && restart {
+            panic!("a restarted participant can not rejoin a tree of sub-coordinators!");
+        }
+        let delay = matches.value_of("delay").unwrap_or(default_delay);
+        let (latency, link_latencies) = match LinkFaults::parse_latency(delay) {
+            Some(latencies) => latencies,
+            None => panic!("bad delay \"{}\" requested!", delay),
+        };
+        let reorder = matches.value_of("reorder").unwrap_or(default_reorder).parse::<usize>().unwrap();
+        let duplicate = matches.value_of("duplicate").unwrap_or(default_duplicate).parse::<f64>().unwrap();
+        let mut partitions = match matches.value_of("partition").map(|spec| (spec, partition::parse_schedule(spec))) {
+            Some((_, Some(partitions))) => partitions,
+            Some((spec, None)) => panic!("bad partition \"{}\" requested!", spec),
+            None => Vec::new(),
+        };
+        if let Some(path) = matches.value_of("partitions") {
+            match partition::read_scenario(path) {
+                Some(scenario) => partitions.extend(scenario),
+                None => panic!("bad partition in scenario \"{}\"!", path),
+            }
+        }
+        let seed = matches.value_of("seed").map_or_else(seed::random_seed, |given| given.parse::<u64>().unwrap());
+        let log_format_name = matches.value_of("log_format").unwrap_or(default_log_format);
+        let log_format = match LogFormat::from_name(log_format_name) {
+            Some(format) => format,
+            None => panic!("unknown log format \"{}\" requested!", log_format_name),
+        };
 
         // IPC path is necessary for client / participant to communicate with the coordinator
-        match mode.as_ref() {
+        match mode {
             "run" => {},
             "client" => {
                 if ipc_path == default_ipc_path {
@@ -129,21 +409,168 @@ impl TPCOptions {
                     panic!("No ipc_path specified for participant mode");
                 }
             },
+            "acceptor" => {
+                if ipc_path == default_ipc_path {
+                    panic!("No ipc_path specified for acceptor mode");
+                }
+            },
             "check" => {},
             _ => panic!("unknown execution mode requested!"),
         }
 
         TPCOptions {
-            send_success_probability: send_success_probability,
-            operation_success_probability: operation_success_probability,
-            num_clients: num_clients,
-            num_participants: num_participants,
-            num_requests: num_requests,
-            verbosity: verbosity,
+            send_success_probability,
+            operation_success_probability,
+            write_probability,
+            num_clients,
+            num_participants,
+            num_requests,
+            verbosity,
             mode: mode.to_string(),
             log_path: log_path.to_string(),
             ipc_path: ipc_path.to_string(),
-            num: num,
+            num,
+            recover,
+            epoch,
+            durability,
+            log_format,
+            repair,
+            segment_size,
+            truncate_logs,
+            protocol,
+            decision_timeout,
+            crash,
+            restart,
+            restarted,
+            presumption,
+            ack_timeout,
+            max_in_flight,
+            num_acceptors,
+            fanout,
+            latency,
+            link_latencies,
+            reorder,
+            duplicate,
+            partitions,
+            seed,
+        }
+    }
+
+    ///
+    /// parent_of(num)
+    /// Tree 2PC: the participant that coordinates participant num, None if
+    /// that is the coordinator. The tree is filled level by level: the
+    /// coordinator has participants 0..fanout, participant i has the next
+    /// fanout after those of participant i-1.
+    ///
+    pub fn parent_of(&self, num: u32) -> Option<u32> {
+        if self.fanout == 0 || num < self.fanout {
+            None
+        } else {
+            Some(num / self.fanout - 1)
+        }
+    }
+
+    ///
+    /// children_of(parent)
+    /// Tree 2PC: the participants that parent coordinates, None standing for
+    /// the coordinator. In flat 2PC the coordinator has all of them.
+    ///
+    pub fn children_of(&self, parent: Option<u32>) -> Vec<u32> {
+        (0..self.num_participants).filter(|num| match self.fanout {
+            0 => parent.is_none(),
+            _ => self.parent_of(*num) == parent,
+        }).collect()
+    }
+
+    ///
+    /// log_config()
+    /// Settings for the logs this process writes
+    ///
+    pub fn log_config(&self) -> LogConfig {
+        LogConfig {
+            durability: self.durability,
+            format: self.log_format,
+            segment_size: self.segment_size,
+            truncate: self.truncate_logs,
+        }
+    }
+
+    ///
+    /// check_config()
+    /// What check mode needs to know of the run it checks
+    ///
+    pub fn check_config(&self) -> CheckConfig {
+        CheckConfig {
+            num_clients: self.num_clients,
+            num_requests: self.num_requests,
+            num_participants: self.num_participants,
+            num_acceptors: self.num_acceptors,
+            log_path: self.log_path.clone(),
+            repair: self.repair,
+            protocol: self.protocol,
+            presumption: self.presumption,
+        }
+    }
+
+    ///
+    /// coordinator_config()
+    /// How the coordinator of run mode runs
+    ///
+    pub fn coordinator_config(&self) -> CoordinatorConfig {
+        CoordinatorConfig {
+            log_config: self.log_config(),
+            protocol: self.protocol,
+            presumption: self.presumption,
+            ack_timeout: self.ack_timeout,
+            max_in_flight: self.max_in_flight,
+            crash: self.crash_of("coordinator"),
+        }
+    }
+
+    ///
+    /// participant_config()
+    /// How participant num of run mode runs
+    ///
+    pub fn participant_config(&self) -> ParticipantConfig {
+        let id_str = format!("participant_{}", self.num);
+        ParticipantConfig {
+            id_str: self.num.to_string(),
+            log_path: format!("{}//{}.log", self.log_path, id_str),
+            recover: self.recover,
+            log_config: self.log_config(),
+            protocol: self.protocol,
+            presumption: self.presumption,
+            decision_timeout: self.decision_timeout,
+            operation_success_prob: self.operation_success_probability,
+            seed: self.seed,
+            parent: self.parent_of(self.num).map(|num| num.to_string()),
+            children: self.children_of(Some(self.num)).iter().map(|num| num.to_string()).collect(),
+            crash: self.crash_of(&id_str),
+            restarted: self.restarted,
+        }
+    }
+
+    ///
+    /// crash_of(process)
+    /// The crash spec if it is process that crashes
+    ///
+    pub fn crash_of(&self, process: &str) -> Option<CrashSpec> {
+        self.crash.clone().filter(|crash| crash.process == process)
+    }
+
+    ///
+    /// link_faults()
+    /// Faults to inject on the links between the coordinator and the
+    /// participants, and the partitions to cut links with
+    ///
+    pub fn link_faults(&self) -> LinkFaults {
+        LinkFaults {
+            latency: self.latency,
+            links: self.link_latencies.clone(),
+            reorder: self.reorder,
+            duplicate: self.duplicate,
+            partitions: self.partitions.clone(),
         }
     }
 
@@ -153,9 +580,10 @@ impl TPCOptions {
     /// HINT: This can be useful for passing arguments to children processes
     ///       using the std::process::Command's args(..) function
     pub fn as_vec(&self) -> Vec<String> {
-        vec![
+        let mut args = vec![
             format!("-S{}", self.send_success_probability),
             format!("-s{}", self.operation_success_probability),
+            format!("-w{}", self.write_probability),
             format!("-c{}", self.num_clients),
             format!("-r{}", self.num_requests),
             format!("-p{}", self.num_participants),
@@ -164,6 +592,44 @@ impl TPCOptions {
             format!("-l{}", self.log_path),
             format!("--ipc_path={}", self.ipc_path),
             format!("--num={}", self.num),
-        ]
+            format!("--epoch={}", self.epoch),
+            format!("--durability={}", self.durability.name()),
+            format!("--log_format={}", self.log_format.name()),
+            format!("--segment_size={}", self.segment_size),
+            format!("--protocol={}", self.protocol.name()),
+            format!("--decision_timeout_ms={}", self.decision_timeout.as_millis()),
+            format!("--presumption={}", self.presumption.name()),
+            format!("--fanout={}", self.fanout),
+            format!("--delay={}", self.link_faults().latency_spec()),
+            format!("--reorder={}", self.reorder),
+            format!("--duplicate={}", self.duplicate),
+            format!("--seed={}", self.seed),
+        ];
+        if let DurabilityPolicy::GroupCommit { max_batch, max_delay } = self.durability {
+            args.push(format!("--group_batch={}", max_batch));
+            args.push(format!("--group_delay_us={}", max_delay.as_micros()));
+        }
+        if self.recover {
+            args.push("--recover".to_string());
+        }
+        if self.truncate_logs {
+            args.push("--truncate_logs".to_string());
+        }
+        if let Some(ref crash) = self.crash {
+            args.push(format!("--crash={}", crash.name()));
+        }
+        if self.restarted {
+            args.push("--restarted".to_string());
+        }
+        if !self.partitions.is_empty() {
+            args.push(format!("--partition={}", partition::schedule_spec(&self.partitions)));
+        }
+        args
+    }
+}
+
+impl Default for TPCOptions {
+    fn default() -> TPCOptions {
+        TPCOptions::new()
     }
 }
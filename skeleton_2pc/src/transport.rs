@@ -0,0 +1,795 @@
+//!
+//! transport.rs
+//! Fault-injecting transport. Every process wraps the IpcSenders it sends
+//! protocol messages on in a Sender that drops a message with a configurable
+//! probability. Drops are recorded next to the process's log, so check mode
+//! can tell the messages lost on purpose. On the links between the
+//! coordinator and the participants, messages can also be delayed, reordered
+//! and delivered twice. Links between processes on opposite sides of a
+//! scheduled partition lose every message while it is in place. Whether a
+//! message is dropped is drawn from the seed of the run for the link, the
+//! txid of the message and how many messages of that txid went over the
+//! link before it; the other faults of a link come from an RNG of its own. A process whose
+//! log commits in groups has its messages held back until the records it
+//! forced before sending them are durable.
+//!
+extern crate bincode;
+extern crate ipc_channel;
+extern crate rand;
+extern crate serde;
+extern crate serde_json;
+
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Write;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::mpsc;
+use std::sync::mpsc::RecvTimeoutError;
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
+use std::time::Instant;
+
+use transport::ipc_channel::ipc::IpcSender;
+use transport::rand::prelude::*;
+
+use message::MessageType;
+use message::ProtocolMessage;
+use oplog::Barrier;
+use partition::Partition;
+use partition::Schedule;
+use seed;
+
+/// How long a link has to be idle before the messages held back for
+/// reordering go out anyway
+const REORDER_HOLD: Duration = Duration::from_millis(10);
+
+///
+/// Latency
+/// Distribution of the delay of each message on a link
+///
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Latency {
+    None,
+    Fixed(Duration),
+    Uniform(Duration, Duration),    // between the two
+    Exponential(Duration),          // with this mean
+}
+
+impl Latency {
+
+    ///
+    /// from_name(name)
+    /// Parse "none", "fixed:MS", "uniform:MIN_MS:MAX_MS" or "exp:MEAN_MS"
+    ///
+    pub fn from_name(name: &str) -> Option<Latency> {
+        let parts: Vec<&str> = name.split(':').collect();
+        let mut ms = Vec::new();
+        for part in &parts[1..] {
+            match part.parse::<u64>() {
+                Ok(n) => ms.push(Duration::from_millis(n)),
+                Err(_) => return None,
+            }
+        }
+        match (parts[0], ms.len()) {
+            ("none", 0) => Some(Latency::None),
+            ("fixed", 1) => Some(Latency::Fixed(ms[0])),
+            ("uniform", 2) if ms[0] <= ms[1] => Some(Latency::Uniform(ms[0], ms[1])),
+            ("exp", 1) => Some(Latency::Exponential(ms[0])),
+            _ => None,
+        }
+    }
+
+    ///
+    /// name()
+    /// The inverse of from_name()
+    ///
+    pub fn name(&self) -> String {
+        match *self {
+            Latency::None => "none".to_string(),
+            Latency::Fixed(d) => format!("fixed:{}", d.as_millis()),
+            Latency::Uniform(lo, hi) => format!("uniform:{}:{}", lo.as_millis(), hi.as_millis()),
+            Latency::Exponential(mean) => format!("exp:{}", mean.as_millis()),
+        }
+    }
+
+    ///
+    /// sample(rng)
+    /// Delay of the next message
+    ///
+    fn sample(&self, rng: &mut StdRng) -> Duration {
+        let x: f64 = rng.gen();
+        match *self {
+            Latency::None => Duration::from_millis(0),
+            Latency::Fixed(d) => d,
+            Latency::Uniform(lo, hi) => lo + (hi - lo).mul_f64(x),
+            Latency::Exponential(mean) => mean.mul_f64(-(1.0 - x).ln()),
+        }
+    }
+}
+
+///
+/// LinkFaults
+/// Faults injected on the links between the coordinator and the
+/// participants, besides drops. The latency of a link can be set per
+/// participant; the others get the default. The partitions cut links
+/// between any processes.
+///
+#[derive(Clone, Debug)]
+pub struct LinkFaults {
+    pub latency: Latency,                   // default latency of a link
+    pub links: Vec<(String, Latency)>,      // latency of the link to a participant, e.g. "participant_2"
+    pub reorder: usize,                     // a message can be overtaken by this many later ones
+    pub duplicate: f64,                     // probability that a message is delivered twice
+    pub partitions: Vec<Partition>,         // links cut on a schedule
+}
+
+impl LinkFaults {
+
+    ///
+    /// parse_latency(spec)
+    /// Parse a comma separated list of latencies: a default one, and ones
+    /// for single links as "participant_N=LATENCY". Returns None if the
+    /// spec is bad.
+    ///
+    pub fn parse_latency(spec: &str) -> Option<(Latency, Vec<(String, Latency)>)> {
+        let mut latency = Latency::None;
+        let mut links = Vec::new();
+        for entry in spec.split(',') {
+            let mut parts = entry.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(name), None) => latency = Latency::from_name(name)?,
+                (Some(link), Some(name)) => links.push((link.to_string(), Latency::from_name(name)?)),
+                _ => return None,
+            }
+        }
+        Some((latency, links))
+    }
+
+    ///
+    /// latency_spec()
+    /// The inverse of parse_latency()
+    ///
+    pub fn latency_spec(&self) -> String {
+        let mut entries = vec![self.latency.name()];
+        entries.extend(self.links.iter().map(|(link, latency)| format!("{}={}", link, latency.name())));
+        entries.join(",")
+    }
+
+    ///
+    /// for_link(from, to)
+    /// The latency of the link between two processes, or None if it is not
+    /// a link between the coordinator and a participant, or it is faultless
+    ///
+    fn for_link(&self, from: &str, to: &str) -> Option<Latency> {
+        let participant = match (from, to) {
+            ("coordinator", p) | (p, "coordinator") if p.starts_with("participant_") => p,
+            _ => return None,
+        };
+        let latency = self.links.iter()
+            .find(|(link, _)| link == participant)
+            .map_or(self.latency, |(_, latency)| *latency);
+        if latency == Latency::None && self.reorder == 0 && self.duplicate == 0.0 {
+            return None;
+        }
+        Some(latency)
+    }
+}
+
+///
+/// DroppedMessage
+/// Record of one message the transport dropped on purpose
+///
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct DroppedMessage {
+    pub from: String,           // process that sent it, e.g. "coordinator"
+    pub to: String,             // process it was meant for, e.g. "participant_1"
+    pub mtype: MessageType,
+    pub txid: String,
+    #[serde(default)]
+    pub partitioned: bool,      // cut by a partition, not dropped at random
+}
+
+///
+/// Transport
+/// The fault settings of one process, shared by all of its Senders, its
+/// partition schedule, the seed of the run, the file its drops are
+/// recorded in, and the barrier of its log if that commits in groups
+///
+#[derive(Debug)]
+pub struct Transport {
+    name: String,
+    send_success_prob: f64,
+    faults: LinkFaults,
+    seed: u64,
+    schedule: Schedule,
+    drops: Mutex<File>,
+    barrier: Mutex<Option<Barrier>>,
+}
+
+impl Transport {
+
+    ///
+    /// new(name, log_path, recover, send_success_prob, faults, seed)
+    /// Transport of process `name` in the run seeded with seed. Its drops go
+    /// to name.drops in log_path, appended to those of the previous run if
+    /// recover is set.
+    ///
+    pub fn new(name: String, log_path: &str, recover: bool, send_success_prob: f64, faults: LinkFaults, seed: u64) -> Arc<Transport> {
+        let path = format!("{}//{}.drops", log_path, name);
+        let file = OpenOptions::new().create(true).write(true).append(recover).truncate(!recover).open(&path).unwrap();
+        let schedule = Schedule::new(&name, log_path, faults.partitions.clone());
+        Arc::new(Transport {
+            name,
+            send_success_prob,
+            faults,
+            seed,
+            schedule,
+            drops: Mutex::new(file),
+            barrier: Mutex::new(None),
+        })
+    }
+
+    ///
+    /// hold_for(barrier)
+    /// Hold every message of the process back until the records its log
+    /// forced before the message was sent are durable. The process has to
+    /// flush() its Senders before it waits for an answer.
+    ///
+    pub fn hold_for(&self, barrier: Option<Barrier>) {
+        *self.barrier.lock().unwrap() = barrier;
+    }
+
+    ///
+    /// sender(transport, tx, to)
+    /// Wrap the IpcSender to process `to` so its messages go through the
+    /// transport. A faulty link gets a thread that delivers its messages.
+    /// The delays, reordering and duplicates on the link come from the RNG
+    /// "NAME->TO/delivery".
+    ///
+    pub fn sender(transport: &Arc<Transport>, tx: IpcSender<ProtocolMessage>, to: String) -> Sender {
+        let link = match transport.faults.for_link(&transport.name, &to) {
+            Some(latency) => {
+                let (queue, rx) = mpsc::channel();
+                let reorder = transport.faults.reorder;
+                let duplicate = transport.faults.duplicate;
+                let rng = seed::rng(transport.seed, &format!("{}->{}/delivery", transport.name, to));
+                let delivery = thread::spawn(move || deliver(tx, rx, latency, reorder, duplicate, rng));
+                Link::Faulty { queue: Some(queue), delivery: Some(delivery) }
+            },
+            None => Link::Direct(tx),
+        };
+        Sender {
+            link,
+            to,
+            transport: Arc::clone(transport),
+            sent: Mutex::new(HashMap::new()),
+            held: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    ///
+    /// record(pm, to, partitioned)
+    /// Note that the message pm to `to` was dropped, or cut by a partition
+    ///
+    fn record(&self, pm: &ProtocolMessage, to: &str, partitioned: bool) {
+        let dropped = DroppedMessage {
+            from: self.name.clone(),
+            to: to.to_string(),
+            mtype: pm.mtype,
+            txid: pm.txid.clone(),
+            partitioned,
+        };
+        debug!("{}::Dropping {:?} for {} to {}{}", self.name, pm.mtype, pm.txid, to, if partitioned { " (partitioned)" } else { "" });
+        let mut file = self.drops.lock().unwrap();
+        writeln!(file, "{}", serde_json::to_string(&dropped).unwrap()).unwrap();
+    }
+}
+
+///
+/// droppable(pm)
+/// Control messages are never dropped: the exit of a run and the "done"
+/// that ends a client's requests or a recovery are not part of the
+/// protocols under test. Nor are they delayed, reordered or duplicated.
+///
+fn droppable(pm: &ProtocolMessage) -> bool {
+    pm.mtype != MessageType::CoordinatorExit && pm.txid != "done"
+}
+
+///
+/// Delivery
+/// State of the delivery thread of a faulty link: the messages held back
+/// for reordering, and the ones on their way, by when they are due
+///
+struct Delivery {
+    tx: IpcSender<ProtocolMessage>,
+    latency: Latency,
+    reorder: usize,
+    duplicate: f64,
+    rng: StdRng,
+    held: Vec<ProtocolMessage>,
+    due: BTreeMap<(Instant, u64), ProtocolMessage>,
+    seq: u64,
+}
+
+impl Delivery {
+
+    ///
+    /// schedule(pm, delay)
+    /// Put pm on its way, to be delivered after delay
+    ///
+    fn schedule(&mut self, pm: ProtocolMessage, delay: Duration) {
+        self.seq += 1;
+        self.due.insert((Instant::now() + delay, self.seq), pm);
+    }
+
+    ///
+    /// release(pm)
+    /// Put pm on its way with a delay drawn from the latency, and a copy of
+    /// it with a delay of its own if it is to be duplicated
+    ///
+    fn release(&mut self, pm: ProtocolMessage) {
+        let x: f64 = self.rng.gen();
+        if x < self.duplicate {
+            trace!("transport::Duplicating {:?} for {}", pm.mtype, pm.txid);
+            let delay = self.latency.sample(&mut self.rng);
+            self.schedule(pm.clone(), delay);
+        }
+        let delay = self.latency.sample(&mut self.rng);
+        self.schedule(pm, delay);
+    }
+
+    ///
+    /// hold(pm)
+    /// Hold pm back; once more than `reorder` are held, a random one of
+    /// them goes on its way
+    ///
+    fn hold(&mut self, pm: ProtocolMessage) {
+        self.held.push(pm);
+        if self.held.len() > self.reorder {
+            let i = self.rng.gen_range(0, self.held.len());
+            let pm = self.held.swap_remove(i);
+            self.release(pm);
+        }
+    }
+
+    ///
+    /// release_held()
+    /// Put every message held back on its way, in random order
+    ///
+    fn release_held(&mut self) {
+        let mut held: Vec<ProtocolMessage> = self.held.drain(..).collect();
+        held.shuffle(&mut self.rng);
+        for pm in held {
+            self.release(pm);
+        }
+    }
+
+    ///
+    /// send_due(all)
+    /// Deliver the messages that are due, or all of them right away.
+    /// Returns false once the other end is gone.
+    ///
+    fn send_due(&mut self, all: bool) -> bool {
+        let now = Instant::now();
+        while let Some(&key) = self.due.keys().next() {
+            if !all && key.0 > now {
+                break;
+            }
+            let pm = self.due.remove(&key).unwrap();
+            if self.tx.send(pm).is_err() {
+                return false;
+            }
+        }
+        true
+    }
+
+    ///
+    /// wait()
+    /// How long to wait for the next message to come in before something
+    /// has to go out
+    ///
+    fn wait(&self) -> Duration {
+        let wait = match self.due.keys().next() {
+            Some(&(at, _)) => at.saturating_duration_since(Instant::now()),
+            None => Duration::from_secs(3600),
+        };
+        if self.held.is_empty() { wait } else { wait.min(REORDER_HOLD) }
+    }
+}
+
+///
+/// deliver(tx, queue, latency, reorder, duplicate, rng)
+/// Delivery thread of a faulty link. Up to `reorder` messages are held
+/// back, and a random one of them goes on whenever another comes in (all
+/// of them once the link is idle). A message that goes on is delivered
+/// after a delay drawn from `latency`, so it can overtake others as well;
+/// with probability `duplicate` a second copy is delivered after a delay
+/// of its own. A control message is delivered right after everything
+/// queued before it. When the Sender is dropped, the thread ends once
+/// everything on the way is delivered. Every random choice comes from rng.
+///
+fn deliver(tx: IpcSender<ProtocolMessage>, queue: mpsc::Receiver<ProtocolMessage>, latency: Latency, reorder: usize, duplicate: f64, rng: StdRng) {
+    let mut delivery = Delivery {
+        tx,
+        latency,
+        reorder,
+        duplicate,
+        rng,
+        held: Vec::new(),
+        due: BTreeMap::new(),
+        seq: 0,
+    };
+    loop {
+        match queue.recv_timeout(delivery.wait()) {
+            Ok(pm) => {
+                if droppable(&pm) {
+                    delivery.hold(pm);
+                } else {
+                    let held: Vec<ProtocolMessage> = delivery.held.drain(..).collect();
+                    for pm in held {
+                        delivery.schedule(pm, Duration::from_millis(0));
+                    }
+                    if !delivery.send_due(true) || delivery.tx.send(pm).is_err() {
+                        break;
+                    }
+                }
+            },
+            // idle
+            Err(RecvTimeoutError::Timeout) => delivery.release_held(),
+            Err(RecvTimeoutError::Disconnected) => {
+                delivery.release_held();
+                while !delivery.due.is_empty() {
+                    thread::sleep(delivery.wait());
+                    if !delivery.send_due(false) {
+                        break;
+                    }
+                }
+                break;
+            },
+        }
+        if !delivery.send_due(false) {
+            // the other end is gone, and so is everything on the way
+            break;
+        }
+    }
+}
+
+///
+/// Link
+/// A link straight to the other end, or through a delivery thread
+///
+#[derive(Debug)]
+enum Link {
+    Direct(IpcSender<ProtocolMessage>),
+    Faulty {
+        queue: Option<mpsc::Sender<ProtocolMessage>>,
+        delivery: Option<JoinHandle<()>>,
+    },
+}
+
+///
+/// Sender
+/// An IpcSender of protocol messages that goes through the fault-injecting
+/// transport of its process, with the number of messages of each txid it
+/// sent so far, and the messages held back for the log, each with the
+/// ticket of the record it waits for
+///
+#[derive(Debug)]
+pub struct Sender {
+    link: Link,
+    to: String,
+    transport: Arc<Transport>,
+    sent: Mutex<HashMap<String, u32>>,
+    held: Mutex<VecDeque<(u64, ProtocolMessage)>>,
+}
+
+impl Sender {
+
+    ///
+    /// send(pm)
+    /// Send pm, or hold it back if a record forced before it is not durable
+    /// yet, or messages held before it still are: they go out in order
+    /// once it is, and pm looks sent to the caller
+    ///
+    pub fn send(&self, pm: ProtocolMessage) -> Result<(), bincode::Error> {
+        let pending = match *self.transport.barrier.lock().unwrap() {
+            Some(ref barrier) => barrier.pending(),
+            None => None,
+        };
+        {
+            let mut held = self.held.lock().unwrap();
+            if pending.is_none() && held.is_empty() {
+                drop(held);
+                return self.transmit(pm);
+            }
+            held.push_back((pending.unwrap_or(0), pm));
+        }
+        self.release();
+        Ok(())
+    }
+
+    ///
+    /// flush(wait)
+    /// Send the messages held back whose records are durable, after
+    /// waiting for all of them to be if wait is set. Fails, and sends
+    /// nothing more, if the log could not make them durable.
+    ///
+    pub fn flush(&self, wait: bool) -> io::Result<()> {
+        if wait {
+            let barrier = self.transport.barrier.lock().unwrap().clone();
+            let ticket = self.held.lock().unwrap().iter().map(|entry| entry.0).max();
+            if let (Some(barrier), Some(ticket)) = (barrier, ticket) {
+                barrier.wait(ticket)?;
+            }
+        }
+        self.release();
+        Ok(())
+    }
+
+    ///
+    /// release()
+    /// Send the messages held back whose records are durable
+    ///
+    fn release(&self) {
+        let barrier = match *self.transport.barrier.lock().unwrap() {
+            Some(ref barrier) => barrier.clone(),
+            None => return,
+        };
+        let mut held = self.held.lock().unwrap();
+        let durable = barrier.durable();
+        while held.front().is_some_and(|entry| entry.0 <= durable) {
+            let (_, pm) = held.pop_front().unwrap();
+            if self.transmit(pm).is_err() {
+                debug!("{}::Link to {} is gone", self.transport.name, self.to);
+            }
+        }
+    }
+
+    ///
+    /// transmit(pm)
+    /// Send pm, unless a partition cuts the link or the transport drops it:
+    /// a dropped message is recorded and looks sent to the caller. On a
+    /// faulty link the delivery thread sends it, and an error sending it is
+    /// not seen here. The coordinator's proposals advance the clock of the
+    /// partition schedule. Whether a message is dropped is drawn for the
+    /// link, its txid and its sequence number among the messages of the
+    /// txid on the link, so the other transactions do not change it.
+    ///
+    fn transmit(&self, pm: ProtocolMessage) -> Result<(), bincode::Error> {
+        if droppable(&pm) {
+            self.transport.schedule.observe(&pm);
+            if self.transport.schedule.cut(&self.transport.name, &self.to) {
+                self.transport.record(&pm, &self.to, true);
+                return Ok(());
+            }
+        }
+        if droppable(&pm) && self.draw(&pm) > self.transport.send_success_prob {
+            self.transport.record(&pm, &self.to, false);
+            return Ok(());
+        }
+        match self.link {
+            Link::Direct(ref tx) => tx.send(pm),
+            Link::Faulty { ref queue, .. } => {
+                if queue.as_ref().unwrap().send(pm).is_err() {
+                    debug!("{}::Link to {} is gone", self.transport.name, self.to);
+                }
+                Ok(())
+            },
+        }
+    }
+
+    ///
+    /// draw(pm)
+    /// The number pm is dropped by, if it is over the send success
+    /// probability. Drawn for "NAME->TO/TXID/SEQ", where SEQ numbers the
+    /// messages of the txid on the link from 1.
+    ///
+    fn draw(&self, pm: &ProtocolMessage) -> f64 {
+        let mut sent = self.sent.lock().unwrap();
+        let seq = sent.entry(pm.txid.clone()).or_insert(0);
+        *seq += 1;
+        seed::draw(self.transport.seed, &format!("{}->{}/{}/{}", self.transport.name, self.to, pm.txid, seq))
+    }
+}
+
+impl Drop for Sender {
+
+    ///
+    /// drop()
+    /// Lets the delivery thread of a faulty link deliver what is on the way.
+    /// Messages still held back are lost, as in a crash.
+    ///
+    fn drop(&mut self) {
+        let held = self.held.lock().unwrap().len();
+        if held > 0 {
+            debug!("{}::{} messages to {} never went out", self.transport.name, held, self.to);
+        }
+        if let Link::Faulty { ref mut queue, ref mut delivery } = self.link {
+            queue.take();
+            if let Some(delivery) = delivery.take() {
+                if delivery.join().is_err() {
+                    warn!("{}::Delivery to {} failed", self.transport.name, self.to);
+                }
+            }
+        }
+    }
+}
+
+///
+/// read_drops(log_path)
+/// The drops every process of the last run recorded in log_path
+///
+pub fn read_drops(log_path: &str) -> Vec<DroppedMessage> {
+    let mut drops = Vec::new();
+    let entries = match fs::read_dir(log_path) {
+        Ok(entries) => entries,
+        Err(_) => return drops,
+    };
+    for entry in entries {
+        let path = entry.unwrap().path();
+        if path.extension().is_none_or(|ext| ext != "drops") {
+            continue;
+        }
+        let file = File::open(&path).unwrap();
+        for line in BufReader::new(file).lines() {
+            match serde_json::from_str(&line.unwrap()) {
+                Ok(dropped) => drops.push(dropped),
+                Err(e) => warn!("bad drop record in {:?}: {}", path, e),
+            }
+        }
+    }
+    drops
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    use transport::ipc_channel::ipc;
+    use oplog::DurabilityPolicy;
+    use oplog::LogConfig;
+    use oplog::LogFormat;
+    use oplog::OpLog;
+
+    // A directory of its own for every test, tests run in parallel
+    fn temp_dir(name: &str) -> String {
+        let dir = env::temp_dir().join(format!("transport_test_{}_{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.to_string_lossy().into_owned()
+    }
+
+    fn faultless() -> LinkFaults {
+        LinkFaults {
+            latency: Latency::None,
+            links: Vec::new(),
+            reorder: 0,
+            duplicate: 0.0,
+            partitions: Vec::new(),
+        }
+    }
+
+    // Sends the messages of a run from the coordinator to participant_0,
+    // half of them dropped, and returns the drops it recorded, sorted
+    fn run(name: &str, seed: u64, messages: &[(MessageType, String)]) -> Vec<(String, MessageType)> {
+        let dir = temp_dir(name);
+        {
+            let transport = Transport::new("coordinator".to_string(), &dir, false, 0.5, faultless(), seed);
+            let (tx, _rx) = ipc::channel().unwrap();
+            let sender = Transport::sender(&transport, tx, "participant_0".to_string());
+            for (mtype, txid) in messages {
+                sender.send(ProtocolMessage::generate(*mtype, txid.clone(), "coordinator".to_string(), 0, 0)).unwrap();
+            }
+        }
+        let mut drops: Vec<(String, MessageType)> = read_drops(&dir).into_iter().map(|dropped| (dropped.txid, dropped.mtype)).collect();
+        drops.sort_by_key(|dropped| format!("{:?}", dropped));
+        let _ = fs::remove_dir_all(&dir);
+        drops
+    }
+
+    #[test]
+    fn drops_follow_the_seed() {
+        // every transaction is proposed, decided, and its decision re-sent
+        let txn = |tx: usize| vec![
+            (MessageType::CoordinatorPropose, format!("tx_{}", tx)),
+            (MessageType::CoordinatorCommit, format!("tx_{}", tx)),
+            (MessageType::CoordinatorCommit, format!("tx_{}", tx))];
+        let one_by_one: Vec<(MessageType, String)> = (0..8).flat_map(txn).collect();
+        let mut interleaved = Vec::new();
+        for step in 0..3 {
+            for tx in (0..8).rev() {
+                interleaved.push(txn(tx)[step].clone());
+            }
+        }
+
+        let first = run("first", 42, &one_by_one);
+        assert!(!first.is_empty() && first.len() < one_by_one.len());
+        assert_eq!(run("again", 42, &one_by_one), first);
+        // the other transactions on the link do not change what is dropped
+        assert_eq!(run("interleaved", 42, &interleaved), first);
+        assert!(run("other_seed", 43, &one_by_one) != first);
+    }
+
+    #[test]
+    fn messages_wait_for_their_records() {
+        let dir = temp_dir("barrier");
+        let durability = DurabilityPolicy::GroupCommit { max_batch: 64, max_delay: Duration::from_millis(200) };
+        let mut log = OpLog::new(format!("{}//participant_0.log", dir), LogConfig { durability, format: LogFormat::Json, segment_size: 0, truncate: false });
+        let transport = Transport::new("participant_0".to_string(), &dir, false, 1.0, faultless(), 0);
+        transport.hold_for(log.barrier());
+        let (tx, rx) = ipc::channel().unwrap();
+        let sender = Transport::sender(&transport, tx, "coordinator".to_string());
+        let vote = || ProtocolMessage::generate(MessageType::ParticipantVoteCommit, "tx_1".to_string(), "0".to_string(), 1, 0);
+
+        log.append_async(MessageType::ParticipantVoteCommit, "tx_1".to_string(), "0".to_string(), 1);
+        sender.send(vote()).unwrap();
+        assert!(rx.try_recv().is_err());
+        sender.flush(true).unwrap();
+        assert_eq!(rx.try_recv().unwrap().txid, "tx_1");
+        // nothing is pending any more, so the next message goes right out
+        sender.send(vote()).unwrap();
+        assert!(rx.try_recv().is_ok());
+        drop(log);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn drops_recorded_but_control_messages_get_through() {
+        let dir = temp_dir("control");
+        let (tx, rx) = ipc::channel().unwrap();
+        {
+            let transport = Transport::new("coordinator".to_string(), &dir, false, 0.0, faultless(), 0);
+            let sender = Transport::sender(&transport, tx, "participant_0".to_string());
+            for (mtype, txid) in &[(MessageType::CoordinatorPropose, "tx_1"), (MessageType::CoordinatorCommit, "tx_1"), (MessageType::CoordinatorExit, "done")] {
+                sender.send(ProtocolMessage::generate(*mtype, txid.to_string(), "coordinator".to_string(), 0, 0)).unwrap();
+            }
+        }
+        assert_eq!(rx.try_recv().unwrap().mtype, MessageType::CoordinatorExit);
+        assert!(rx.try_recv().is_err());
+        let drops: Vec<(String, String, MessageType, bool)> = read_drops(&dir).into_iter()
+            .map(|dropped| (dropped.from, dropped.to, dropped.mtype, dropped.partitioned))
+            .collect();
+        assert_eq!(drops, vec![
+            ("coordinator".to_string(), "participant_0".to_string(), MessageType::CoordinatorPropose, false),
+            ("coordinator".to_string(), "participant_0".to_string(), MessageType::CoordinatorCommit, false)]);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn faulty_link_delays_and_duplicates() {
+        let dir = temp_dir("delay");
+        let mut faults = faultless();
+        faults.latency = Latency::Fixed(Duration::from_millis(50));
+        faults.duplicate = 1.0;
+        let transport = Transport::new("coordinator".to_string(), &dir, false, 1.0, faults, 0);
+        let (tx, rx) = ipc::channel().unwrap();
+        let sender = Transport::sender(&transport, tx, "participant_0".to_string());
+        // only the links between the coordinator and participants are faulty
+        let (client_tx, client_rx) = ipc::channel().unwrap();
+        let client = Transport::sender(&transport, client_tx, "client_0".to_string());
+        let propose = || ProtocolMessage::generate(MessageType::CoordinatorPropose, "tx_1".to_string(), "coordinator".to_string(), 0, 0);
+
+        let sent = Instant::now();
+        sender.send(propose()).unwrap();
+        client.send(propose()).unwrap();
+        assert!(client_rx.try_recv().is_ok());
+        assert!(rx.try_recv().is_err());
+        assert_eq!(rx.recv().unwrap().txid, "tx_1");
+        assert_eq!(rx.recv().unwrap().txid, "tx_1");
+        assert!(sent.elapsed() >= Duration::from_millis(50));
+        drop(sender);
+        assert!(rx.try_recv().is_err());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}